@@ -0,0 +1,428 @@
+
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::Mul;
+
+use super::float;
+use super::language::*;
+
+/// A 3x3 homogeneous matrix acting on column vectors `(x, y, 1)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Matrix3(pub [[f64; 3]; 3]);
+
+impl Matrix3 {
+    pub fn identity() -> Matrix3 {
+        Matrix3([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    }
+
+    pub fn translation(u: f64, v: f64) -> Matrix3 {
+        Matrix3([[1.0, 0.0, u], [0.0, 1.0, v], [0.0, 0.0, 1.0]])
+    }
+
+    /// Rotation by `theta` radians around the center `(u, v)`.
+    pub fn rotation(u: f64, v: f64, theta: f64) -> Matrix3 {
+        let (sin, cos) = float::sin_cos(theta);
+        Matrix3([
+            [cos, -sin, u - u * cos + v * sin],
+            [sin, cos, v - u * sin - v * cos],
+            [0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// Scaling by `sx`, `sy` around the center `(u, v)`.
+    pub fn scale(u: f64, v: f64, sx: f64, sy: f64) -> Matrix3 {
+        Matrix3([[sx, 0.0, u - u * sx], [0.0, sy, v - v * sy], [0.0, 0.0, 1.0]])
+    }
+
+    /// Reflection across the line through `(u, v)` at angle `theta`.
+    pub fn reflection(u: f64, v: f64, theta: f64) -> Matrix3 {
+        let (sin, cos) = float::sin_cos(2.0 * theta);
+        Matrix3([
+            [cos, sin, u - u * cos - v * sin],
+            [sin, -cos, v - u * sin + v * cos],
+            [0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn apply(&self, p: Point) -> Point {
+        let m = &self.0;
+        Point {
+            x: m[0][0] * p.x + m[0][1] * p.y + m[0][2],
+            y: m[1][0] * p.x + m[1][1] * p.y + m[1][2],
+        }
+    }
+
+    /// The matrix applied `n` times, by repeated squaring.
+    pub fn pow(self, mut n: usize) -> Matrix3 {
+        let (mut result, mut square) = (Matrix3::identity(), self);
+        while n > 0 {
+            if n % 2 == 1 {
+                result = result * square;
+            }
+            square = square * square;
+            n /= 2;
+        }
+        result
+    }
+
+    /// The factor by which the map scales areas, negative if it also flips
+    /// the plane over; zero when it collapses the plane onto a line or a
+    /// point.
+    pub fn determinant(&self) -> f64 {
+        let [[a, b, c], [d, e, f], [g, h, i]] = self.0;
+        a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g)
+    }
+
+    /// The inverse of an affine matrix, one whose bottom row is `0 0 1`, or
+    /// `None` if it collapses the plane.
+    pub fn inverse(&self) -> Option<Matrix3> {
+        let [[a, b, c], [d, e, f], _] = self.0;
+        let determinant = self.determinant();
+        if determinant == 0.0 {
+            return None;
+        }
+        let (a2, b2, d2, e2) = (e / determinant, -b / determinant, -d / determinant, a / determinant);
+        Some(Matrix3([[a2, b2, -(a2 * c + b2 * f)], [d2, e2, -(d2 * c + e2 * f)], [0.0, 0.0, 1.0]]))
+    }
+
+    /// Whether every entry is within `epsilon` of the corresponding entry of `other`.
+    pub fn approx_eq(&self, other: &Matrix3, epsilon: f64) -> bool {
+        self.0.iter().flatten().zip(other.0.iter().flatten()).all(|(a, b)| (a - b).abs() <= epsilon)
+    }
+}
+
+impl Mul for Matrix3 {
+    type Output = Matrix3;
+
+    /// `a * b` is the map that applies `b` first, then `a`.
+    fn mul(self, other: Matrix3) -> Matrix3 {
+        let mut result = [[0.0; 3]; 3];
+        for (i, row) in result.iter_mut().enumerate() {
+            for (j, cell) in row.iter_mut().enumerate() {
+                *cell = (0..3).map(|k| self.0[i][k] * other.0[k][j]).sum();
+            }
+        }
+        Matrix3(result)
+    }
+}
+
+impl Expression {
+    /// The single affine map equivalent to this expression, folding chains by
+    /// matrix multiplication. Iterations, choices and spins have no such form.
+    pub fn to_affine(&self) -> Option<Matrix3> {
+        match self {
+            Expression::Translation { u, v } => Some(Matrix3::translation(*u, *v)),
+            Expression::Rotation { u, v, theta } => Some(Matrix3::rotation(*u, *v, *theta)),
+            Expression::Scale { u, v, sx, sy } => Some(Matrix3::scale(*u, *v, *sx, *sy)),
+            Expression::Reflection { u, v, theta } => Some(Matrix3::reflection(*u, *v, *theta)),
+            Expression::Chained(first, second) => Some(second.to_affine()? * first.to_affine()?),
+            Expression::Sequence(statements) => {
+                statements.iter().try_fold(Matrix3::identity(), |m, statement| Some(statement.to_affine()? * m))
+            }
+            Expression::Identity => Some(Matrix3::identity()),
+            Expression::Affine(m) => Some(*m),
+            Expression::Reverse(body) => body.chain_statements().into_iter().rev()
+                .try_fold(Matrix3::identity(), |m, statement| Some(statement.to_affine()? * m)),
+            // what a spin does depends on the frame
+            Expression::EitherOr { .. } | Expression::Iterate { .. } | Expression::Spin { .. } | Expression::Reference(_) => None,
+        }
+    }
+
+    /// An expression applying the affine map `m`, in the named
+    /// transformations where it is one: `Identity`, a translation, a
+    /// rotation or a scaling about a center, or a reflection, followed by a
+    /// translation where its center can't absorb the offset. Anything else,
+    /// such as a shear, is an `Affine` leaf. Entries within `1e-9` of what
+    /// a named form needs are taken as equal.
+    pub fn from_affine(m: Matrix3) -> Expression {
+        let [[a, b, c], [d, e, f], _] = m.0;
+        let near = |x: f64, y: f64| (x - y).abs() <= 1e-9;
+        let then_translate = |expr: Expression, u: f64, v: f64| match (near(u, 0.0), near(v, 0.0)) {
+            (true, true) => expr,
+            _ => Expression::Chained(Box::new(expr), Box::new(Expression::Translation { u, v })),
+        };
+        if near(a, 1.0) && near(b, 0.0) && near(d, 0.0) && near(e, 1.0) {
+            if near(c, 0.0) && near(f, 0.0) {
+                return Expression::Identity;
+            }
+            return Expression::Translation { u: c, v: f };
+        }
+        if near(a, e) && near(b, -d) && near(a * a + d * d, 1.0) {
+            // the center solves `(I - R) center = (c, f)`
+            let (sin, cos) = (d, a);
+            let det = 2.0 - 2.0 * cos;
+            let (u, v) = (((1.0 - cos) * c - sin * f) / det, (sin * c + (1.0 - cos) * f) / det);
+            return Expression::Rotation { u, v, theta: float::atan2(sin, cos) };
+        }
+        if near(b, 0.0) && near(d, 0.0) {
+            // an axis that isn't scaled can't absorb an offset along it
+            let center = |offset: f64, factor: f64| if near(factor, 1.0) { (0.0, offset) } else { (offset / (1.0 - factor), 0.0) };
+            let ((u, du), (v, dv)) = (center(c, a), center(f, e));
+            return then_translate(Expression::Scale { u, v, sx: a, sy: e }, du, dv);
+        }
+        if near(a, -e) && near(b, d) && near(a * a + b * b, 1.0) {
+            // the center takes the offset across the line, leaving a glide
+            // along it
+            let theta = float::atan2(b, a) / 2.0;
+            let (sin, cos) = float::sin_cos(theta);
+            let (nx, ny) = (-sin, cos);
+            let across = nx * c + ny * f;
+            let reflection = Expression::Reflection { u: nx * across / 2.0, v: ny * across / 2.0, theta };
+            return then_translate(reflection, c - nx * across, f - ny * across);
+        }
+        Expression::Affine(m)
+    }
+
+    /// The net effect of the expression as `evaluate` applies it: where it
+    /// takes the origin, and the angle in `(-π, π]` by which it turns the
+    /// x axis. Bounded iterations are folded in; `None` if the expression
+    /// has a choice, or a spin or reference, which have no single map.
+    pub fn net_transform(&self) -> Option<(Point, f64)> {
+        if self.contains_choice() {
+            return None;
+        }
+        let [[a, _, c], [d, _, f], _] = self.constant_matrix()?.0;
+        Some((Point { x: c, y: f }, float::atan2(d, a)))
+    }
+
+    /// Whether some leaf is an affine map collapsing the plane, with a
+    /// determinant within `1e-9` of zero, so that the expression can't be
+    /// undone. Leaves without an affine form never are.
+    pub fn is_degenerate(&self) -> bool {
+        self.leaves().any(|leaf| leaf.to_affine().is_some_and(|m| m.determinant().abs() <= 1e-9))
+    }
+
+    /// Whether applying this expression and then `other` ends up where
+    /// applying them the other way round does, with the two compositions'
+    /// matrices equal to within `1e-9`. Expressions without an affine form
+    /// are taken not to commute, since reordering them isn't known to be
+    /// safe.
+    pub fn commutes_with(&self, other: &Expression) -> bool {
+        match (self.to_affine(), other.to_affine()) {
+            (Some(a), Some(b)) => (a * b).approx_eq(&(b * a), 1e-9),
+            _ => false,
+        }
+    }
+
+    /// The composed matrices after each of `frames` steps, starting from
+    /// the identity: frame `k` is the step's matrix raised to the `k`th
+    /// power. A step is one run of the body of an iteration, whatever its
+    /// count, and one run of the whole expression otherwise. Empty when the
+    /// step has no single affine map, as with a choice or a spin; counted
+    /// iterations within the step are folded in.
+    pub fn keyframe_matrices(&self, frames: usize) -> Vec<Matrix3> {
+        let step = match self {
+            Expression::Iterate { body, .. } => body,
+            _ => self,
+        };
+        let Some(m) = step.constant_matrix() else { return Vec::new() };
+        let mut keyframes = Vec::with_capacity(frames);
+        let mut current = Matrix3::identity();
+        for _ in 0..frames {
+            keyframes.push(current);
+            current = m * current;
+        }
+        keyframes
+    }
+}
+
+impl Program {
+    /// The distinct maps the body can apply, one per combination of choices,
+    /// as used by an iterated function system. Maps equal to within `1e-9`
+    /// are listed once, in the order of `enumerate_branches`; branches
+    /// containing an iteration have no single map and are left out.
+    pub fn affine_maps(&self) -> Vec<Matrix3> {
+        let mut maps: Vec<Matrix3> = Vec::new();
+        for m in self.body.enumerate_branches().iter().filter_map(Expression::to_affine) {
+            if !maps.iter().any(|other| other.approx_eq(&m, 1e-9)) {
+                maps.push(m);
+            }
+        }
+        maps
+    }
+}
+
+#[test]
+fn test_rotation_matrix_matches_trig() {
+    let (u, v, theta) = (0.5, -1.5, 0.7);
+    let p = Point { x: 2.0, y: 3.0 };
+    let q = Matrix3::rotation(u, v, theta).apply(p);
+    let expected_x = u + (p.x - u) * theta.cos() - (p.y - v) * theta.sin();
+    let expected_y = v + (p.x - u) * theta.sin() + (p.y - v) * theta.cos();
+    assert!((q.x - expected_x).abs() < 1e-12);
+    assert!((q.y - expected_y).abs() < 1e-12);
+}
+
+#[test]
+fn test_chained_affine_matches_evaluate() {
+    let expr = Expression::Chained(
+        Box::new(Expression::Translation { u: 12.0, v: 0.4 }),
+        Box::new(Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 }),
+    );
+    let p = Point { x: -1.0, y: 4.0 };
+    let by_matrix = expr.to_affine().unwrap().apply(p);
+    let by_evaluation = expr.evaluate(p);
+    assert!((by_matrix.x - by_evaluation.x).abs() < 1e-12);
+    assert!((by_matrix.y - by_evaluation.y).abs() < 1e-12);
+
+    let choice = Expression::EitherOr {
+        left: Box::new(Expression::Translation { u: 1.0, v: 0.0 }),
+        right: Box::new(Expression::Translation { u: 0.0, v: 1.0 }),
+        weights: (0.5, 0.5),
+    };
+    assert_eq!(None, choice.to_affine());
+}
+
+#[test]
+fn test_reflection_is_involution() {
+    let reflection = Expression::Reflection { u: 1.5, v: -0.5, theta: 0.9 };
+    let m = reflection.to_affine().unwrap();
+    let twice = m * m;
+    let identity = Matrix3::identity();
+    for i in 0..3 {
+        for j in 0..3 {
+            assert!((twice.0[i][j] - identity.0[i][j]).abs() < 1e-12);
+        }
+    }
+
+    // points on the line are fixed
+    let on_line = Point { x: 1.5 + 0.9f64.cos(), y: -0.5 + 0.9f64.sin() };
+    let reflected = m.apply(on_line);
+    assert!((reflected.x - on_line.x).abs() < 1e-12);
+    assert!((reflected.y - on_line.y).abs() < 1e-12);
+
+    let p = Point { x: 3.0, y: 2.0 };
+    let by_matrix = m.apply(p);
+    let by_evaluation = reflection.evaluate(p);
+    assert!((by_matrix.x - by_evaluation.x).abs() < 1e-12);
+    assert!((by_matrix.y - by_evaluation.y).abs() < 1e-12);
+}
+
+#[test]
+fn test_affine_maps() {
+    use super::parser::program;
+
+    let (_, two_maps) = program("init(0, 0); { scale(0, 0, 0.5, 0.5) } or { scale(1, 0, 0.5, 0.5) }").unwrap();
+    assert_eq!(vec![Matrix3::scale(0.0, 0.0, 0.5, 0.5), Matrix3::scale(1.0, 0.0, 0.5, 0.5)], two_maps.affine_maps());
+
+    // a quarter turn twice is a half turn, up to rounding
+    let (_, duplicated) = program("init(0, 0); { rotation(0, 0, 180deg) } or { rotation(0, 0, 90deg); rotation(0, 0, 90deg) }").unwrap();
+    assert_eq!(1, duplicated.affine_maps().len());
+}
+
+#[test]
+fn test_pow_and_inverse() {
+    let m = Matrix3::rotation(1.0, 2.0, 0.3) * Matrix3::scale(0.0, 1.0, 2.0, 0.5) * Matrix3::translation(3.0, -1.0);
+    assert!(m.pow(3).approx_eq(&(m * m * m), 1e-12));
+    assert_eq!(Matrix3::identity(), m.pow(0));
+    assert!((m * m.inverse().unwrap()).approx_eq(&Matrix3::identity(), 1e-12));
+    assert!((m.inverse().unwrap() * m).approx_eq(&Matrix3::identity(), 1e-12));
+    assert_eq!(None, Matrix3::scale(0.0, 0.0, 0.0, 1.0).inverse());
+}
+
+#[test]
+fn test_from_affine() {
+    use super::parser::parse_expression;
+
+    let translation = parse_expression("translation(3, -1.5)").unwrap().0;
+    assert_eq!(translation, Expression::from_affine(translation.to_affine().unwrap()));
+    assert_eq!(Expression::Identity, Expression::from_affine(Matrix3::identity()));
+
+    for raw_expression in [
+        "rotation(1, 2, 0.7)",
+        "scale(1, -2, 0.5, 3)",
+        "reflection(1, 2, 0.3)",
+        "translation(1, 0); rotation(0, 0, 90deg)",
+        "scale(0, 0, 1, 2); translation(4, 1)",
+        "reflection(0, 1, 0.25); translation(2, 3)",
+    ] {
+        let m = parse_expression(raw_expression).unwrap().0.to_affine().unwrap();
+        let expr = Expression::from_affine(m);
+        assert!(!matches!(expr, Expression::Affine(_)), "{} gave {}", raw_expression, expr);
+        assert!(m.approx_eq(&expr.to_affine().unwrap(), 1e-9), "{} gave {}", raw_expression, expr);
+    }
+    let expr = Expression::from_affine(Matrix3::rotation(1.0, 2.0, 0.7));
+    assert!(expr.approx_eq(&Expression::Rotation { u: 1.0, v: 2.0, theta: 0.7 }, 1e-9), "{}", expr);
+
+    let shear = Matrix3([[1.0, 0.5, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+    assert_eq!(Expression::Affine(shear), Expression::from_affine(shear));
+}
+
+#[test]
+fn test_commutes_with() {
+    let translation = Expression::Translation { u: 1.0, v: -2.0 };
+    let rotation = Expression::Rotation { u: 0.5, v: 0.0, theta: 0.3 };
+    assert!(translation.commutes_with(&Expression::Translation { u: -4.0, v: 0.5 }));
+    assert!(!translation.commutes_with(&rotation));
+    assert!(!rotation.commutes_with(&translation));
+
+    // rotations about one center commute, about different centers they don't
+    assert!(rotation.commutes_with(&Expression::Rotation { u: 0.5, v: 0.0, theta: -1.2 }));
+    assert!(!rotation.commutes_with(&Expression::Rotation { u: 0.0, v: 0.0, theta: -1.2 }));
+    assert!(Expression::Identity.commutes_with(&rotation));
+    assert!(!Expression::Spin { theta: 0.3 }.commutes_with(&Expression::Identity));
+}
+
+#[test]
+fn test_determinant() {
+    use super::error::IssueKind;
+    use super::parser::program;
+
+    assert!((Matrix3::rotation(2.0, -1.0, 0.7).determinant() - 1.0).abs() < 1e-12);
+    assert!((Matrix3::reflection(0.0, 1.0, 0.3).determinant() + 1.0).abs() < 1e-12);
+    assert_eq!(-1.5, Matrix3::scale(1.0, 1.0, 0.5, -3.0).determinant());
+
+    assert!(!Expression::Rotation { u: 2.0, v: -1.0, theta: 0.7 }.is_degenerate());
+    let (_, collapsing) = program("init(0, 0); rotation(0, 0, 1); iter[2]({ id } or { scale(1, 1, 0, 2) })").unwrap();
+    assert!(collapsing.body.is_degenerate());
+    assert!(Expression::Scale { u: 0.0, v: 0.0, sx: 1e-10, sy: 1.0 }.is_degenerate());
+    assert!(!Expression::Spin { theta: 1.0 }.is_degenerate());
+
+    let (_, nearly) = program("init(0, 0); scale(0, 0, 1e-12, 1); affine(1, 2, 0, 0.5, 1, 3)").unwrap();
+    let messages: Vec<(IssueKind, String)> = nearly.validate().into_iter().map(|issue| (issue.kind, issue.message)).collect();
+    let expected_messages = vec![
+        (IssueKind::Degenerate, "`scale(0, 0, 0.000000000001, 1)` collapses the plane".to_string()),
+        (IssueKind::Degenerate, "`affine(1, 2, 0, 0.5, 1, 3)` collapses the plane".to_string()),
+    ];
+    assert_eq!(expected_messages, messages);
+}
+
+#[test]
+fn test_net_transform() {
+    use super::parser::parse_expression;
+
+    let (offset, angle) = parse_expression("translation(1,0); rotation(0,0,0.5)").unwrap().0.net_transform().unwrap();
+    assert!((offset.x - 0.5f64.cos()).abs() < 1e-12 && (offset.y - 0.5f64.sin()).abs() < 1e-12, "{:?}", offset);
+    assert!((angle - 0.5).abs() < 1e-12);
+
+    // around a square and back where it started, facing the same way
+    let (offset, angle) = parse_expression("iter[4](translation(1, 0); rotation(0, 0, 90deg))").unwrap().0.net_transform().unwrap();
+    assert!(offset.x.abs() < 1e-12 && offset.y.abs() < 1e-12 && angle.abs() < 1e-12, "{:?}", (offset, angle));
+
+    assert_eq!(None, parse_expression("translation(1, 0); { id } or { rotation(0, 0, 1) }").unwrap().0.net_transform());
+    assert_eq!(None, parse_expression("translation(1, 0); spin(1)").unwrap().0.net_transform());
+}
+
+#[test]
+fn test_keyframe_matrices() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter[5](translation(1, 0); rotation(0, 0, 0.3); iter[2](scale(1, 1, 1.1, 0.9)))").unwrap().0;
+    let Expression::Iterate { body, .. } = &expr else { panic!("expected an iteration") };
+    let m = body.constant_matrix().unwrap();
+    let keyframes = expr.keyframe_matrices(6);
+    assert_eq!(6, keyframes.len());
+    assert_eq!(Matrix3::identity(), keyframes[0]);
+    for (k, keyframe) in keyframes.iter().enumerate() {
+        assert!(keyframe.approx_eq(&m.pow(k), 1e-12), "frame {}", k);
+        let p = Point::new(0.5, -2.0);
+        let stepped = Expression::Iterate { count: Some(k), body: body.clone() }.evaluate(p);
+        let q = keyframe.apply(p);
+        assert!((q.x - stepped.x).abs() < 1e-9 && (q.y - stepped.y).abs() < 1e-9);
+    }
+
+    let rotation = parse_expression("rotation(0, 0, 90deg)").unwrap().0;
+    assert!(rotation.keyframe_matrices(5)[4].approx_eq(&Matrix3::identity(), 1e-12));
+    assert!(parse_expression("iter[3]({ id } or { translation(1, 0) })").unwrap().0.keyframe_matrices(4).is_empty());
+    assert!(rotation.keyframe_matrices(0).is_empty());
+}
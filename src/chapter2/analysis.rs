@@ -0,0 +1,543 @@
+
+use alloc::{boxed::Box, format, string::{String, ToString}, vec, vec::Vec};
+use core::fmt;
+use core::ops::Add;
+
+use super::affine::Matrix3;
+use super::error::{Issue, IssueKind};
+use super::language::*;
+
+/// Number of nodes of each kind in an expression tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpCounts {
+    pub translations: usize,
+    pub rotations: usize,
+    pub scales: usize,
+    pub reflections: usize,
+    pub either_ors: usize,
+    pub iterates: usize,
+    pub reverses: usize,
+    pub identities: usize,
+    pub spins: usize,
+    pub affines: usize,
+    pub references: usize,
+}
+
+/// A difference between two trees found by `Expression::diff`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffEntry {
+    /// The steps from the roots to the differing nodes, such as
+    /// `iter body > chain second`; empty when the roots differ.
+    pub path: String,
+    pub description: String,
+}
+
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "at root: {}", self.description)
+        } else {
+            write!(f, "at {}: {}", self.path, self.description)
+        }
+    }
+}
+
+impl Add for OpCounts {
+    type Output = OpCounts;
+
+    fn add(self, other: OpCounts) -> OpCounts {
+        OpCounts {
+            translations: self.translations + other.translations,
+            rotations: self.rotations + other.rotations,
+            scales: self.scales + other.scales,
+            reflections: self.reflections + other.reflections,
+            either_ors: self.either_ors + other.either_ors,
+            iterates: self.iterates + other.iterates,
+            reverses: self.reverses + other.reverses,
+            identities: self.identities + other.identities,
+            spins: self.spins + other.spins,
+            affines: self.affines + other.affines,
+            references: self.references + other.references,
+        }
+    }
+}
+
+/// Appends the differences between `a` and `b`, found at `path`, to
+/// `entries`.
+fn diff_into(a: &Expression, b: &Expression, path: &mut Vec<String>, entries: &mut Vec<DiffEntry>) {
+    let mut differ = |description: String| entries.push(DiffEntry { path: path.join(" > "), description });
+    let mut numbers = |names: &[&str], xs: &[f64], ys: &[f64]| {
+        for ((name, x), y) in names.iter().zip(xs).zip(ys) {
+            if x != y {
+                differ(format!("{} {} {:?} vs {:?}", variant_name(a), name, x, y));
+            }
+        }
+    };
+    let mut children: Vec<(String, &Expression, &Expression)> = Vec::new();
+    match (a, b) {
+        (Expression::Translation { u, v }, Expression::Translation { u: u2, v: v2 }) => numbers(&["u", "v"], &[*u, *v], &[*u2, *v2]),
+        (Expression::Rotation { u, v, theta }, Expression::Rotation { u: u2, v: v2, theta: theta2 })
+        | (Expression::Reflection { u, v, theta }, Expression::Reflection { u: u2, v: v2, theta: theta2 }) => {
+            numbers(&["u", "v", "theta"], &[*u, *v, *theta], &[*u2, *v2, *theta2])
+        }
+        (Expression::Scale { u, v, sx, sy }, Expression::Scale { u: u2, v: v2, sx: sx2, sy: sy2 }) => {
+            numbers(&["u", "v", "sx", "sy"], &[*u, *v, *sx, *sy], &[*u2, *v2, *sx2, *sy2])
+        }
+        (Expression::Spin { theta }, Expression::Spin { theta: theta2 }) => numbers(&["theta"], &[*theta], &[*theta2]),
+        (Expression::Affine(m), Expression::Affine(m2)) => {
+            let names: Vec<String> = (0..9).map(|i| format!("entry [{}][{}]", i / 3, i % 3)).collect();
+            let names: Vec<&str> = names.iter().map(String::as_str).collect();
+            numbers(&names, m.0.as_flattened(), m2.0.as_flattened());
+        }
+        (Expression::Chained(first, second), Expression::Chained(first2, second2)) => {
+            children.push(("chain first".to_string(), first, first2));
+            children.push(("chain second".to_string(), second, second2));
+        }
+        (Expression::Sequence(statements), Expression::Sequence(statements2)) => {
+            if statements.len() != statements2.len() {
+                differ(format!("Sequence length {} vs {}", statements.len(), statements2.len()));
+            }
+            for (i, (statement, statement2)) in statements.iter().zip(statements2).enumerate() {
+                children.push((format!("sequence [{}]", i), statement, statement2));
+            }
+        }
+        (Expression::EitherOr { left, right, weights }, Expression::EitherOr { left: left2, right: right2, weights: weights2 }) => {
+            numbers(&["left weight", "right weight"], &[weights.0, weights.1], &[weights2.0, weights2.1]);
+            children.push(("or left".to_string(), left, left2));
+            children.push(("or right".to_string(), right, right2));
+        }
+        (Expression::Iterate { count, body }, Expression::Iterate { count: count2, body: body2 }) => {
+            if count != count2 {
+                let shown = |count: &Option<usize>| count.map_or("unbounded".to_string(), |n| n.to_string());
+                differ(format!("Iterate count {} vs {}", shown(count), shown(count2)));
+            }
+            children.push(("iter body".to_string(), body, body2));
+        }
+        (Expression::Reverse(body), Expression::Reverse(body2)) => children.push(("rev body".to_string(), body, body2)),
+        (Expression::Identity, Expression::Identity) => {}
+        (Expression::Reference(name), Expression::Reference(name2)) => {
+            if name != name2 {
+                differ(format!("Reference name `{}` vs `{}`", name, name2));
+            }
+        }
+        _ => differ(format!("variant mismatch {} vs {}", variant_name(a), variant_name(b))),
+    }
+    for (step, child, child2) in children {
+        path.push(step);
+        diff_into(child, child2, path, entries);
+        path.pop();
+    }
+}
+
+fn variant_name(expr: &Expression) -> &'static str {
+    match expr {
+        Expression::Translation { .. } => "Translation",
+        Expression::Rotation { .. } => "Rotation",
+        Expression::Scale { .. } => "Scale",
+        Expression::Reflection { .. } => "Reflection",
+        Expression::Chained(..) => "Chained",
+        Expression::Sequence(_) => "Sequence",
+        Expression::EitherOr { .. } => "EitherOr",
+        Expression::Iterate { .. } => "Iterate",
+        Expression::Reverse(_) => "Reverse",
+        Expression::Identity => "Identity",
+        Expression::Spin { .. } => "Spin",
+        Expression::Affine(_) => "Affine",
+        Expression::Reference(_) => "Reference",
+    }
+}
+
+impl Program {
+    /// Every problem found in the body, in preorder: unbounded iterations
+    /// that make no progress (see `Expression::terminates`), scales and
+    /// affine maps that collapse the plane (see `Expression::is_degenerate`),
+    /// and references that were never resolved.
+    pub fn validate(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        self.body.visit(&mut |node| {
+            let (kind, message) = match node {
+                Expression::Iterate { count: None, body } if !body.makes_progress() => {
+                    (IssueKind::NoProgress, format!("`iter({})` never moves the point", body))
+                }
+                Expression::Scale { sx, sy, .. } if *sx == 0.0 || *sy == 0.0 => {
+                    (IssueKind::Degenerate, format!("`{}` scales by zero", node))
+                }
+                Expression::Scale { .. } | Expression::Affine(_) if node.is_degenerate() => {
+                    (IssueKind::Degenerate, format!("`{}` collapses the plane", node))
+                }
+                Expression::Reference(name) => {
+                    (IssueKind::UnresolvedReference { name: name.clone() }, format!("undefined name `{}`", name))
+                }
+                _ => return,
+            };
+            issues.push(Issue { kind, message, span: None });
+        });
+        issues
+    }
+}
+
+impl Expression {
+    /// Counts the nodes of the tree by kind. Iteration bodies and both sides
+    /// of a choice are counted once, regardless of how often they run.
+    pub fn count_operations(&self) -> OpCounts {
+        match self {
+            Expression::Translation { .. } => OpCounts { translations: 1, ..OpCounts::default() },
+            Expression::Rotation { .. } => OpCounts { rotations: 1, ..OpCounts::default() },
+            Expression::Scale { .. } => OpCounts { scales: 1, ..OpCounts::default() },
+            Expression::Reflection { .. } => OpCounts { reflections: 1, ..OpCounts::default() },
+            Expression::Chained(first, second) => first.count_operations() + second.count_operations(),
+            Expression::Sequence(statements) => statements.iter().map(Expression::count_operations).fold(OpCounts::default(), Add::add),
+            Expression::EitherOr { left, right, .. } => {
+                OpCounts { either_ors: 1, ..OpCounts::default() } + left.count_operations() + right.count_operations()
+            }
+            Expression::Iterate { body, .. } => OpCounts { iterates: 1, ..OpCounts::default() } + body.count_operations(),
+            Expression::Reverse(body) => OpCounts { reverses: 1, ..OpCounts::default() } + body.count_operations(),
+            Expression::Identity => OpCounts { identities: 1, ..OpCounts::default() },
+            Expression::Spin { .. } => OpCounts { spins: 1, ..OpCounts::default() },
+            Expression::Affine(_) => OpCounts { affines: 1, ..OpCounts::default() },
+            Expression::Reference(_) => OpCounts { references: 1, ..OpCounts::default() },
+        }
+    }
+
+    /// Where `a` and `b` differ, in preorder: each number, count, weight or
+    /// name that isn't equal, and each pair of nodes of different variants,
+    /// below which nothing more is compared. Sequences are compared as far
+    /// as the shorter one goes. Empty when the trees are equal.
+    pub fn diff(a: &Expression, b: &Expression) -> Vec<DiffEntry> {
+        let mut entries = Vec::new();
+        diff_into(a, b, &mut Vec::new(), &mut entries);
+        entries
+    }
+
+    /// How many subtrees of the tree, itself included, equal `needle`
+    /// exactly.
+    pub fn occurrences(&self, needle: &Expression) -> usize {
+        let mut count = 0;
+        self.visit(&mut |node| {
+            if node == needle {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// The centers of the rotations, as visited by `leaves`, each listed
+    /// once: a center within `1e-9` of one already listed on both axes is
+    /// left out.
+    pub fn rotation_centers(&self) -> Vec<Point> {
+        let mut centers: Vec<Point> = Vec::new();
+        for leaf in self.leaves() {
+            if let Expression::Rotation { u, v, .. } = leaf {
+                if !centers.iter().any(|center| (center.x - u).abs() <= 1e-9 && (center.y - v).abs() <= 1e-9) {
+                    centers.push(Point::new(*u, *v));
+                }
+            }
+        }
+        centers
+    }
+
+    /// Whether there is an `EitherOr` anywhere in the tree. References are
+    /// not looked through.
+    pub fn contains_choice(&self) -> bool {
+        match self {
+            Expression::EitherOr { .. } => true,
+            Expression::Chained(first, second) => first.contains_choice() || second.contains_choice(),
+            Expression::Sequence(statements) => statements.iter().any(Expression::contains_choice),
+            Expression::Iterate { body, .. } | Expression::Reverse(body) => body.contains_choice(),
+            _ => false,
+        }
+    }
+
+    /// Whether the expression always applies the same transformations, with
+    /// no choices to make.
+    pub fn is_linear(&self) -> bool {
+        !self.contains_choice()
+    }
+
+    /// Whether repeating every unbounded `iter(...)` until it stops moving
+    /// the point could end: each such body must make progress. `evaluate`
+    /// runs an unbounded iteration once; this is for callers that run them
+    /// to a fixed point. A body reducing to a single affine map makes
+    /// progress unless that map is the identity; any other body only if it
+    /// contains a translation or rotation that is not zero.
+    pub fn terminates(&self) -> bool {
+        match self {
+            Expression::Chained(first, second) | Expression::EitherOr { left: first, right: second, .. } => {
+                first.terminates() && second.terminates()
+            }
+            Expression::Sequence(statements) => statements.iter().all(Expression::terminates),
+            Expression::Iterate { count: None, body } => body.makes_progress() && body.terminates(),
+            Expression::Iterate { count: Some(_), body } | Expression::Reverse(body) => body.terminates(),
+            _ => true,
+        }
+    }
+
+    fn makes_progress(&self) -> bool {
+        if let Some(m) = self.to_affine() {
+            return !m.approx_eq(&Matrix3::identity(), 1e-12);
+        }
+        self.leaves().any(|leaf| match leaf {
+            Expression::Translation { u, v } => *u != 0.0 || *v != 0.0,
+            Expression::Rotation { theta, .. } => *theta != 0.0,
+            _ => false,
+        })
+    }
+
+    /// Maximum nesting of chains, choices and iterations; a leaf has depth 1.
+    pub fn depth(&self) -> usize {
+        match self {
+            Expression::Chained(first, second) => 1 + first.depth().max(second.depth()),
+            Expression::Sequence(statements) => 1 + statements.iter().map(Expression::depth).max().unwrap_or(0),
+            Expression::EitherOr { left, right, .. } => 1 + left.depth().max(right.depth()),
+            Expression::Iterate { body, .. } | Expression::Reverse(body) => 1 + body.depth(),
+            _ => 1,
+        }
+    }
+
+    /// Expands every choice into its alternatives, yielding one choice-free
+    /// expression per combination, leftmost alternatives first. A choice inside
+    /// an iteration body is resolved the same way on every iteration.
+    pub fn enumerate_branches(&self) -> Vec<Expression> {
+        match self {
+            Expression::Translation { .. }
+            | Expression::Rotation { .. }
+            | Expression::Scale { .. }
+            | Expression::Reflection { .. }
+            | Expression::Identity
+            | Expression::Spin { .. }
+            | Expression::Affine(_)
+            | Expression::Reference(_) => vec![self.clone()],
+            Expression::Chained(first, second) => {
+                let second_branches = second.enumerate_branches();
+                first.enumerate_branches().into_iter()
+                    .flat_map(|first| second_branches.iter().map(move |second| {
+                        Expression::Chained(Box::new(first.clone()), Box::new(second.clone()))
+                    }))
+                    .collect()
+            }
+            Expression::Sequence(statements) => statements.iter().fold(vec![vec![]], |prefixes: Vec<Vec<Expression>>, statement| {
+                let branches = statement.enumerate_branches();
+                prefixes.iter()
+                    .flat_map(|prefix| branches.iter().map(move |branch| {
+                        let mut prefix = prefix.clone();
+                        prefix.push(branch.clone());
+                        prefix
+                    }))
+                    .collect()
+            }).into_iter().map(Expression::Sequence).collect(),
+            Expression::EitherOr { left, right, .. } => {
+                let mut branches = left.enumerate_branches();
+                branches.extend(right.enumerate_branches());
+                branches
+            }
+            Expression::Iterate { count, body } => body.enumerate_branches().into_iter()
+                .map(|body| Expression::Iterate { count: *count, body: Box::new(body) })
+                .collect(),
+            Expression::Reverse(body) => body.enumerate_branches().into_iter()
+                .map(|body| Expression::Reverse(Box::new(body)))
+                .collect(),
+        }
+    }
+
+    /// Whether the trees have the same shape, counts and names, with every
+    /// coordinate, angle and factor within `epsilon` of its counterpart.
+    pub fn approx_eq(&self, other: &Expression, epsilon: f64) -> bool {
+        let close = |a: &[f64], b: &[f64]| a.iter().zip(b).all(|(a, b)| (a - b).abs() <= epsilon);
+        match (self, other) {
+            (Expression::Translation { u, v }, Expression::Translation { u: u2, v: v2 }) => close(&[*u, *v], &[*u2, *v2]),
+            (Expression::Rotation { u, v, theta }, Expression::Rotation { u: u2, v: v2, theta: theta2 })
+            | (Expression::Reflection { u, v, theta }, Expression::Reflection { u: u2, v: v2, theta: theta2 }) => {
+                close(&[*u, *v, *theta], &[*u2, *v2, *theta2])
+            }
+            (Expression::Scale { u, v, sx, sy }, Expression::Scale { u: u2, v: v2, sx: sx2, sy: sy2 }) => {
+                close(&[*u, *v, *sx, *sy], &[*u2, *v2, *sx2, *sy2])
+            }
+            (Expression::Chained(first, second), Expression::Chained(first2, second2)) => {
+                first.approx_eq(first2, epsilon) && second.approx_eq(second2, epsilon)
+            }
+            (Expression::EitherOr { left, right, weights }, Expression::EitherOr { left: left2, right: right2, weights: weights2 }) => {
+                close(&[weights.0, weights.1], &[weights2.0, weights2.1]) && left.approx_eq(left2, epsilon) && right.approx_eq(right2, epsilon)
+            }
+            (Expression::Sequence(statements), Expression::Sequence(statements2)) => {
+                statements.len() == statements2.len() && statements.iter().zip(statements2).all(|(a, b)| a.approx_eq(b, epsilon))
+            }
+            (Expression::Iterate { count, body }, Expression::Iterate { count: count2, body: body2 }) => {
+                count == count2 && body.approx_eq(body2, epsilon)
+            }
+            (Expression::Reverse(body), Expression::Reverse(body2)) => body.approx_eq(body2, epsilon),
+            (Expression::Identity, Expression::Identity) => true,
+            (Expression::Spin { theta }, Expression::Spin { theta: theta2 }) => close(&[*theta], &[*theta2]),
+            (Expression::Affine(m), Expression::Affine(m2)) => m.approx_eq(m2, epsilon),
+            (Expression::Reference(name), Expression::Reference(name2)) => name == name2,
+            _ => false,
+        }
+    }
+}
+
+#[test]
+fn test_count_operations() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    let expected_counts = OpCounts { translations: 2, rotations: 1, iterates: 1, ..OpCounts::default() };
+    assert_eq!(expected_counts, expr.count_operations());
+
+    let expr = parse_expression("{ iter[3](scale(0, 0, 2, 2)) } or { { reflection(0, 0, 1) } or { translation(1, 0) } }").unwrap().0;
+    let expected_counts = OpCounts { translations: 1, scales: 1, reflections: 1, either_ors: 2, iterates: 1, ..OpCounts::default() };
+    assert_eq!(expected_counts, expr.count_operations());
+}
+
+#[test]
+fn test_enumerate_branches() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("{ translation(1,0) } or { rotation(0,0,1) }").unwrap().0;
+    let expected_branches = vec![
+        Expression::Translation { u: 1.0, v: 0.0 },
+        Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 },
+    ];
+    assert_eq!(expected_branches, expr.enumerate_branches());
+
+    let expr = parse_expression("iter[2]({ translation(1,0) } or { { rotation(0,0,1) } or { scale(0,0,2,2) } }); { translation(0,1) } or { translation(0,2) }").unwrap().0;
+    let branches = expr.enumerate_branches();
+    let expected_branches: Vec<Expression> = [
+        "iter[2](translation(1,0)); translation(0,1)",
+        "iter[2](translation(1,0)); translation(0,2)",
+        "iter[2](rotation(0,0,1)); translation(0,1)",
+        "iter[2](rotation(0,0,1)); translation(0,2)",
+        "iter[2](scale(0,0,2,2)); translation(0,1)",
+        "iter[2](scale(0,0,2,2)); translation(0,2)",
+    ].iter().map(|text| parse_expression(text).unwrap().0).collect();
+    assert_eq!(expected_branches, branches);
+    assert!(branches.iter().all(|branch| branch.count_operations().either_ors == 0));
+}
+
+#[test]
+fn test_depth() {
+    use super::parser::parse_expression;
+
+    assert_eq!(1, parse_expression("translation(1,2)").unwrap().0.depth());
+    assert_eq!(3, parse_expression("iter(iter(translation(1,2)))").unwrap().0.depth());
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    assert_eq!(4, expr.depth());
+    assert_eq!(3, parse_expression("{ translation(1,0) } or { rotation(0,0,1); scale(0,0,1,1) }").unwrap().0.depth());
+}
+
+#[test]
+fn test_approx_eq() {
+    use super::parser::parse_expression;
+
+    let rotation = Expression::Rotation { u: 0.0, v: 1.0, theta: 0.5 };
+    let nudged = Expression::Rotation { u: 0.0, v: 1.0, theta: 0.5 + 1e-12 };
+    assert!(rotation.approx_eq(&nudged, 1e-9));
+    assert!(!rotation.approx_eq(&nudged, 1e-15));
+
+    let expr = parse_expression("iter[2]({ rotation(0, 1, 0.5) } or { translation(1, 0) })").unwrap().0;
+    let nudged_expr = Expression::Iterate {
+        count: Some(2),
+        body: Box::new(Expression::EitherOr { left: Box::new(nudged), right: Box::new(Expression::Translation { u: 1.0, v: 0.0 }), weights: (0.5, 0.5) }),
+    };
+    assert!(expr.approx_eq(&nudged_expr, 1e-9));
+    assert!(!expr.approx_eq(&parse_expression("iter[3]({ rotation(0, 1, 0.5) } or { translation(1, 0) })").unwrap().0, 1e-9));
+    assert!(!rotation.approx_eq(&Expression::Reflection { u: 0.0, v: 1.0, theta: 0.5 }, 1e-9));
+}
+
+#[test]
+fn test_contains_choice() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    assert!(expr.is_linear());
+    assert!(!expr.contains_choice());
+
+    let expr = parse_expression("translation(1, 0); iter[2]({ rotation(0, 0, 1) } or { scale(0, 0, 2, 2) })").unwrap().0;
+    assert!(!expr.is_linear());
+    assert!(expr.contains_choice());
+}
+
+#[test]
+fn test_terminates() {
+    use super::parser::parse_expression;
+
+    assert!(!parse_expression("iter(translation(0,0))").unwrap().0.terminates());
+    assert!(parse_expression("iter(translation(1,0))").unwrap().0.terminates());
+    assert!(!parse_expression("translation(1, 0); iter(id)").unwrap().0.terminates());
+    assert!(!parse_expression("iter(translation(1, 0); translation(-1, 0))").unwrap().0.terminates());
+    assert!(!parse_expression("iter[3](iter(rotation(0, 0, 0)))").unwrap().0.terminates());
+    assert!(parse_expression("iter({ translation(1, 0) } or { rotation(0, 0, 1) }); iter[2](id)").unwrap().0.terminates());
+}
+
+#[test]
+fn test_validate() {
+    use super::parser::program;
+
+    let (_, valid) = program("init(0, 0); iter(translation(1, 0)); iter[3](scale(0, 0, 2, 2))").unwrap();
+    assert_eq!(Vec::<Issue>::new(), valid.validate());
+
+    let (_, mut invalid) = program("init(0, 0); iter(translation(0, 0)); { scale(0, 0, 0, 2) } or { affine(1, 2, 0, 2, 4, 0) }; iter(rotation(1, 1, 0); id)").unwrap();
+    invalid.body = Expression::Chained(Box::new(invalid.body), Box::new(Expression::Reference("later".to_string())));
+    let issues = invalid.validate();
+    let kinds: Vec<IssueKind> = issues.iter().map(|issue| issue.kind.clone()).collect();
+    let expected_kinds = vec![
+        IssueKind::NoProgress,
+        IssueKind::Degenerate,
+        IssueKind::Degenerate,
+        IssueKind::NoProgress,
+        IssueKind::UnresolvedReference { name: "later".to_string() },
+    ];
+    assert_eq!(expected_kinds, kinds);
+    assert_eq!("`iter(translation(0, 0))` never moves the point", issues[0].message);
+    assert_eq!("`scale(0, 0, 0, 2)` scales by zero", issues[1].message);
+    assert_eq!("undefined name `later`", issues[4].message);
+    // a `Program` has no positions to point the issues at
+    assert!(issues.iter().all(|issue| issue.span.is_none()));
+}
+
+#[test]
+fn test_occurrences() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("rotation(0, 0, 1); iter[2]({ translation(1, 0) } or { rotation(0, 0, 1); translation(1, 0) })").unwrap().0;
+    assert_eq!(2, expr.occurrences(&Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 }));
+    assert_eq!(2, expr.occurrences(&Expression::Translation { u: 1.0, v: 0.0 }));
+    assert_eq!(0, expr.occurrences(&Expression::Rotation { u: 0.0, v: 0.0, theta: 2.0 }));
+    assert_eq!(1, expr.occurrences(&parse_expression("rotation(0, 0, 1); translation(1, 0)").unwrap().0));
+    assert_eq!(1, expr.occurrences(&expr));
+
+    let nested = parse_expression("iter[2](iter[2](id))").unwrap().0;
+    assert_eq!(1, nested.occurrences(&parse_expression("iter[2](id)").unwrap().0));
+    let repeated = parse_expression("iter[2](iter[2](id)); translation(1, 0); iter[2](iter[2](id))").unwrap().0;
+    assert_eq!(2, repeated.occurrences(&nested));
+}
+
+#[test]
+fn test_diff() {
+    use super::parser::parse_expression;
+
+    let a = parse_expression("iter[2](translation(1, 0)); { rotation(0, 0, 1) } or { id }").unwrap().0;
+    assert!(Expression::diff(&a, &a).is_empty());
+
+    let b = parse_expression("iter[2](translation(2, 0)); { translation(0, 0) } or { id }").unwrap().0;
+    let entries = Expression::diff(&a, &b);
+    let rendered: Vec<String> = entries.iter().map(DiffEntry::to_string).collect();
+    let expected = vec![
+        "at chain first > iter body: Translation u 1.0 vs 2.0".to_string(),
+        "at chain second > or left: variant mismatch Rotation vs Translation".to_string(),
+    ];
+    assert_eq!(expected, rendered);
+    assert_eq!("chain first > iter body", entries[0].path);
+
+    let c = parse_expression("iter(translation(1, 0)); { rotation(0, 0, 1) }:0.25 or { id }").unwrap().0;
+    let descriptions: Vec<String> = Expression::diff(&a, &c).into_iter().map(|entry| entry.description).collect();
+    assert_eq!(vec!["Iterate count 2 vs unbounded", "EitherOr left weight 0.5 vs 0.25"], descriptions);
+    assert_eq!("at root: variant mismatch Chained vs Identity", Expression::diff(&a, &Expression::Identity)[0].to_string());
+}
+
+#[test]
+fn test_rotation_centers() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("rotation(1, 2, 0.5); iter[3](translation(1, 0); rotation(-1, 0, 1)); { rotation(1, 2.0000000001, 2) } or { scale(5, 5, 2, 2) }").unwrap().0;
+    assert_eq!(vec![Point::new(1.0, 2.0), Point::new(-1.0, 0.0)], expr.rotation_centers());
+    assert!(parse_expression("translation(1, 0); reflection(2, 2, 1)").unwrap().0.rotation_centers().is_empty());
+}
@@ -0,0 +1,254 @@
+
+use alloc::{boxed::Box, string::ToString, vec, vec::Vec};
+
+use super::affine::Matrix3;
+use super::error::DecodeError;
+use super::language::*;
+
+/// First byte of every encoding, telling it apart from other data.
+const MAGIC: u8 = 0xA7;
+/// Second byte of every encoding, raised when the layout changes.
+const VERSION: u8 = 1;
+/// Deepest nesting `from_bytes` decodes, as `ParserConfig::max_depth` does
+/// for text, so that corrupt input can't overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+// one byte per variant, in declaration order
+const TRANSLATION: u8 = 0;
+const ROTATION: u8 = 1;
+const SCALE: u8 = 2;
+const REFLECTION: u8 = 3;
+const CHAINED: u8 = 4;
+const SEQUENCE: u8 = 5;
+const EITHER_OR: u8 = 6;
+const ITERATE: u8 = 7;
+const REVERSE: u8 = 8;
+const IDENTITY: u8 = 9;
+const SPIN: u8 = 10;
+const AFFINE: u8 = 11;
+const REFERENCE: u8 = 12;
+
+impl Expression {
+    /// A compact binary encoding of the expression: the magic and version
+    /// bytes, then the tree in preorder. Each node is its variant's tag byte
+    /// followed by its numbers as little-endian `f64`s, then its children.
+    /// Counts and lengths are little-endian `u64`s; an iteration count is
+    /// preceded by a byte that is 0 when there is no count.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![MAGIC, VERSION];
+        encode(self, &mut bytes);
+        bytes
+    }
+
+    /// Reads an expression written by `to_bytes`, which must make up all of
+    /// `bytes`. Expressions nested deeper than 256 levels fail with
+    /// `DecodeError::TooDeep`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Expression, DecodeError> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.byte()? != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        match reader.byte()? {
+            VERSION => {}
+            version => return Err(DecodeError::UnsupportedVersion { version }),
+        }
+        let expr = reader.expression(0)?;
+        if reader.offset < bytes.len() {
+            return Err(DecodeError::TrailingBytes { offset: reader.offset });
+        }
+        Ok(expr)
+    }
+}
+
+fn encode(expr: &Expression, bytes: &mut Vec<u8>) {
+    let numbers = |tag: u8, numbers: &[f64], bytes: &mut Vec<u8>| {
+        bytes.push(tag);
+        for number in numbers {
+            bytes.extend(number.to_le_bytes());
+        }
+    };
+    match expr {
+        Expression::Translation { u, v } => numbers(TRANSLATION, &[*u, *v], bytes),
+        Expression::Rotation { u, v, theta } => numbers(ROTATION, &[*u, *v, *theta], bytes),
+        Expression::Scale { u, v, sx, sy } => numbers(SCALE, &[*u, *v, *sx, *sy], bytes),
+        Expression::Reflection { u, v, theta } => numbers(REFLECTION, &[*u, *v, *theta], bytes),
+        Expression::Chained(first, second) => {
+            bytes.push(CHAINED);
+            encode(first, bytes);
+            encode(second, bytes);
+        }
+        Expression::Sequence(statements) => {
+            bytes.push(SEQUENCE);
+            bytes.extend((statements.len() as u64).to_le_bytes());
+            for statement in statements {
+                encode(statement, bytes);
+            }
+        }
+        Expression::EitherOr { left, right, weights } => {
+            numbers(EITHER_OR, &[weights.0, weights.1], bytes);
+            encode(left, bytes);
+            encode(right, bytes);
+        }
+        Expression::Iterate { count, body } => {
+            bytes.push(ITERATE);
+            match count {
+                Some(count) => {
+                    bytes.push(1);
+                    bytes.extend((*count as u64).to_le_bytes());
+                }
+                None => bytes.push(0),
+            }
+            encode(body, bytes);
+        }
+        Expression::Reverse(body) => {
+            bytes.push(REVERSE);
+            encode(body, bytes);
+        }
+        Expression::Identity => bytes.push(IDENTITY),
+        Expression::Spin { theta } => numbers(SPIN, &[*theta], bytes),
+        Expression::Affine(Matrix3(rows)) => numbers(AFFINE, rows.as_flattened(), bytes),
+        Expression::Reference(name) => {
+            bytes.push(REFERENCE);
+            bytes.extend((name.len() as u64).to_le_bytes());
+            bytes.extend(name.as_bytes());
+        }
+    }
+}
+
+/// The bytes being decoded and how far they have been read.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], DecodeError> {
+        let taken = self.bytes.get(self.offset..).and_then(|rest| rest.get(..n)).ok_or(DecodeError::Truncated)?;
+        self.offset += n;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn number(&mut self) -> Result<f64, DecodeError> {
+        let bytes = self.take(8)?;
+        Ok(f64::from_le_bytes(bytes.try_into().expect("took 8 bytes")))
+    }
+
+    fn count(&mut self) -> Result<usize, DecodeError> {
+        let offset = self.offset;
+        let bytes = self.take(8)?;
+        usize::try_from(u64::from_le_bytes(bytes.try_into().expect("took 8 bytes"))).map_err(|_| DecodeError::CountOverflow { offset })
+    }
+
+    /// A child of a node at `depth`.
+    fn boxed(&mut self, depth: usize) -> Result<Box<Expression>, DecodeError> {
+        self.expression(depth + 1).map(Box::new)
+    }
+
+    fn expression(&mut self, depth: usize) -> Result<Expression, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep { depth: MAX_DEPTH });
+        }
+        let offset = self.offset;
+        let expr = match self.byte()? {
+            TRANSLATION => Expression::Translation { u: self.number()?, v: self.number()? },
+            ROTATION => Expression::Rotation { u: self.number()?, v: self.number()?, theta: self.number()? },
+            SCALE => Expression::Scale { u: self.number()?, v: self.number()?, sx: self.number()?, sy: self.number()? },
+            REFLECTION => Expression::Reflection { u: self.number()?, v: self.number()?, theta: self.number()? },
+            CHAINED => Expression::Chained(self.boxed(depth)?, self.boxed(depth)?),
+            SEQUENCE => {
+                let length = self.count()?;
+                // every statement takes at least a byte, so a corrupt length
+                // can't make this allocate more than the input holds
+                let mut statements = Vec::with_capacity(length.min(self.bytes.len() - self.offset));
+                for _ in 0..length {
+                    statements.push(self.expression(depth + 1)?);
+                }
+                Expression::Sequence(statements)
+            }
+            EITHER_OR => {
+                let weights = (self.number()?, self.number()?);
+                Expression::EitherOr { left: self.boxed(depth)?, right: self.boxed(depth)?, weights }
+            }
+            ITERATE => {
+                let count = if self.byte()? == 0 { None } else { Some(self.count()?) };
+                Expression::Iterate { count, body: self.boxed(depth)? }
+            }
+            REVERSE => Expression::Reverse(self.boxed(depth)?),
+            IDENTITY => Expression::Identity,
+            SPIN => Expression::Spin { theta: self.number()? },
+            AFFINE => {
+                let mut rows = [[0.0; 3]; 3];
+                for entry in rows.as_flattened_mut() {
+                    *entry = self.number()?;
+                }
+                Expression::Affine(Matrix3(rows))
+            }
+            REFERENCE => {
+                let length = self.count()?;
+                let start = self.offset;
+                let name = core::str::from_utf8(self.take(length)?).map_err(|_| DecodeError::InvalidUtf8 { offset: start })?;
+                Expression::Reference(name.to_string())
+            }
+            tag => return Err(DecodeError::UnknownTag { tag, offset }),
+        };
+        Ok(expr)
+    }
+}
+
+#[test]
+fn test_binary_round_trip() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    let bytes = expr.to_bytes();
+    assert_eq!([MAGIC, VERSION, CHAINED, ITERATE, 0, CHAINED, TRANSLATION], bytes[..7]);
+    assert_eq!(Ok(expr), Expression::from_bytes(&bytes));
+
+    let expr = parse_expression("iter[3]({ scale(0, 0, -0.5, 2) }:0.25 or { reflection(1, 1, 0.5); spin(1) }); rev(id; affine(1, 2, 3, 4, 5, 6))").unwrap().0;
+    let expr = Expression::Chained(Box::new(expr.flatten_chains()), Box::new(Expression::Reference("größe".to_string())));
+    assert_eq!(Ok(expr.clone()), Expression::from_bytes(&expr.to_bytes()));
+}
+
+#[test]
+fn test_binary_errors() {
+    use super::parser::parse_expression;
+
+    let bytes = parse_expression("iter[2](translation(1, 2); rotation(0, 0, 1)); { id } or { spin(2) }").unwrap().0.to_bytes();
+    for length in 0..bytes.len() {
+        assert_eq!(Err(DecodeError::Truncated), Expression::from_bytes(&bytes[..length]), "{} bytes", length);
+    }
+    let mut extended = bytes.clone();
+    extended.push(IDENTITY);
+    assert_eq!(Err(DecodeError::TrailingBytes { offset: bytes.len() }), Expression::from_bytes(&extended));
+
+    assert_eq!(Err(DecodeError::BadMagic), Expression::from_bytes(&[b'{', VERSION, IDENTITY]));
+    assert_eq!(Err(DecodeError::UnsupportedVersion { version: 2 }), Expression::from_bytes(&[MAGIC, 2, IDENTITY]));
+    assert_eq!(Err(DecodeError::UnknownTag { tag: 200, offset: 3 }), Expression::from_bytes(&[MAGIC, VERSION, REVERSE, 200]));
+    let mut huge = vec![MAGIC, VERSION, SEQUENCE];
+    huge.extend(u64::MAX.to_le_bytes());
+    assert!(Expression::from_bytes(&huge).is_err());
+}
+
+#[test]
+fn test_binary_depth_limit() {
+    let nested = |tag: u8, depth: usize| {
+        let mut bytes = vec![MAGIC, VERSION];
+        for _ in 0..depth {
+            bytes.push(tag);
+            if tag == CHAINED {
+                bytes.push(IDENTITY);
+            }
+        }
+        bytes.push(IDENTITY);
+        bytes
+    };
+    for tag in [REVERSE, CHAINED] {
+        assert_eq!(Err(DecodeError::TooDeep { depth: MAX_DEPTH }), Expression::from_bytes(&nested(tag, 2_000_000)), "tag {}", tag);
+        assert_eq!(Err(DecodeError::TooDeep { depth: MAX_DEPTH }), Expression::from_bytes(&nested(tag, MAX_DEPTH + 1)), "tag {}", tag);
+        assert!(Expression::from_bytes(&nested(tag, MAX_DEPTH)).is_ok(), "tag {}", tag);
+    }
+}
@@ -0,0 +1,197 @@
+
+use alloc::{boxed::Box, vec::Vec};
+
+use super::affine::Matrix3;
+use super::language::*;
+use super::rewrite::chain;
+
+/// Fluent construction of expressions without spelling out the boxes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Builder {
+    expr: Expression,
+}
+
+impl Builder {
+    pub fn translation(u: f64, v: f64) -> Builder {
+        Builder { expr: Expression::Translation { u, v } }
+    }
+
+    pub fn rotation(u: f64, v: f64, theta: f64) -> Builder {
+        Builder { expr: Expression::Rotation { u, v, theta } }
+    }
+
+    pub fn scale(u: f64, v: f64, sx: f64, sy: f64) -> Builder {
+        Builder { expr: Expression::Scale { u, v, sx, sy } }
+    }
+
+    pub fn reflection(u: f64, v: f64, theta: f64) -> Builder {
+        Builder { expr: Expression::Reflection { u, v, theta } }
+    }
+
+    /// Chains `next` after everything built so far.
+    pub fn then(self, next: Builder) -> Builder {
+        Builder { expr: Expression::Chained(Box::new(self.expr), Box::new(next.expr)) }
+    }
+
+    /// Repeats everything built so far `count` times.
+    pub fn iterate(self, count: usize) -> Builder {
+        Builder { expr: Expression::Iterate { count: Some(count), body: Box::new(self.expr) } }
+    }
+
+    pub fn build(self) -> Expression {
+        self.expr
+    }
+}
+
+/// An even choice between `left` and `right`.
+pub fn either(left: Builder, right: Builder) -> Builder {
+    weighted(left, 0.5, right, 0.5)
+}
+
+/// A choice taking `left` and `right` with probabilities proportional to
+/// `left_weight` and `right_weight`.
+pub fn weighted(left: Builder, left_weight: f64, right: Builder, right_weight: f64) -> Builder {
+    let weights = (left_weight, right_weight);
+    Builder { expr: Expression::EitherOr { left: Box::new(left.expr), right: Box::new(right.expr), weights } }
+}
+
+impl Expression {
+    /// The translation taking `from` to `to`.
+    pub fn translation_between(from: Point, to: Point) -> Expression {
+        let offset = to - from;
+        Expression::Translation { u: offset.x, v: offset.y }
+    }
+
+    /// Rotation by `theta` radians around `center`.
+    pub fn rotation_about(center: Point, theta: f64) -> Expression {
+        Expression::Rotation { u: center.x, v: center.y, theta }
+    }
+}
+
+impl Program {
+    /// Runs `other`'s body after this one, from wherever this body leaves
+    /// the point; `other.init` is ignored.
+    pub fn then(self, other: Program) -> Program {
+        Program { init: self.init, body: Expression::Chained(Box::new(self.body), Box::new(other.body)) }
+    }
+
+    /// Like `then`, but first translates from where this body ends, with
+    /// choices taking their left branch, to `other.init`.
+    pub fn then_from(self, other: Program) -> Program {
+        let body = Expression::Chained(Box::new(Expression::translation_between(self.evaluate(), other.init)), Box::new(other.body));
+        self.then(Program { init: other.init, body })
+    }
+
+    /// The program drawing a copy of one run of the body in each cell of a
+    /// grid `rows` high and `cols` wide, row by row. The copy in row `i` and
+    /// column `j` is this one moved by `(j * spacing.0, i * spacing.1)`, the
+    /// centers of its rotations, scalings and reflections included. Between
+    /// copies, a translation takes the point from where the last copy ends,
+    /// with choices taking their left branch, to where the next one starts.
+    /// An empty grid draws nothing beyond `init`.
+    pub fn tile(&self, rows: usize, cols: usize, spacing: (f64, f64)) -> Program {
+        let mut statements = Vec::new();
+        let mut at = self.init;
+        for i in 0..rows {
+            for j in 0..cols {
+                let offset = Point::new(j as f64 * spacing.0, i as f64 * spacing.1);
+                let start = self.init + offset;
+                if start != at {
+                    statements.push(Expression::translation_between(at, start));
+                }
+                let copy = moved(&self.body, offset);
+                at = copy.evaluate(start);
+                statements.push(copy);
+            }
+        }
+        Program { init: self.init, body: chain(statements).unwrap_or(Expression::Identity) }
+    }
+}
+
+/// `expr` acting on points moved by `offset` as it does on the originals,
+/// so that its trajectories move with them.
+fn moved(expr: &Expression, offset: Point) -> Expression {
+    expr.map_leaves(&|leaf| match leaf {
+        Expression::Rotation { u, v, theta } => Expression::Rotation { u: u + offset.x, v: v + offset.y, theta: *theta },
+        Expression::Scale { u, v, sx, sy } => Expression::Scale { u: u + offset.x, v: v + offset.y, sx: *sx, sy: *sy },
+        Expression::Reflection { u, v, theta } => Expression::Reflection { u: u + offset.x, v: v + offset.y, theta: *theta },
+        Expression::Affine(m) => {
+            Expression::Affine(Matrix3::translation(offset.x, offset.y) * *m * Matrix3::translation(-offset.x, -offset.y))
+        }
+        leaf => leaf.clone(),
+    })
+}
+
+#[test]
+fn test_builder_matches_parser() {
+    use super::parser::parse_expression;
+
+    let built = Builder::translation(12.0, 0.4)
+        .then(Builder::rotation(0.2, 0.3, 0.5))
+        .iterate(5)
+        .then(Builder::translation(8.0, 15.0))
+        .build();
+    let parsed = parse_expression("iter[5](translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation(8, 15)").unwrap().0;
+    assert_eq!(parsed, built);
+
+    let built = either(Builder::scale(0.0, 0.0, 0.5, 0.5), Builder::reflection(1.0, 1.0, 0.25).iterate(2)).build();
+    let parsed = parse_expression("{ scale(0, 0, 0.5, 0.5) } or { iter[2](reflection(1, 1, 0.25)) }").unwrap().0;
+    assert_eq!(parsed, built);
+}
+
+#[test]
+fn test_program_composition() {
+    use super::parser::program;
+
+    let (_, first) = program("init(1, 1); translation(2, 0)").unwrap();
+    let (_, second) = program("init(10, 10); rotation(0, 0, 90deg)").unwrap();
+    let (_, expected_program) = program("init(1, 1); translation(2, 0); rotation(0, 0, 90deg)").unwrap();
+    let composed = first.then(second);
+    assert_eq!(expected_program, composed);
+
+    let (_, first) = program("init(1, 1); translation(2, 0)").unwrap();
+    let (_, second) = program("init(10, 10); rotation(0, 0, 90deg)").unwrap();
+    let (_, expected_program) = program("init(1, 1); translation(2, 0); translation(7, 9); rotation(0, 0, 90deg)").unwrap();
+    let composed = first.then_from(second);
+    assert_eq!(expected_program, composed);
+    let end = composed.evaluate();
+    assert!((end.x + 10.0).abs() < 1e-12 && (end.y - 10.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_geometric_constructors() {
+    let (from, to) = (Point::new(1.5, -2.0), Point::new(-3.0, 4.25));
+    let translation = Expression::translation_between(from, to);
+    assert_eq!(Expression::Translation { u: -4.5, v: 6.25 }, translation);
+    assert_eq!(to, translation.evaluate(from));
+
+    let rotation = Expression::rotation_about(Point::new(1.0, 1.0), std::f64::consts::PI);
+    assert_eq!(Expression::Rotation { u: 1.0, v: 1.0, theta: std::f64::consts::PI }, rotation);
+    let p = rotation.evaluate(Point::new(2.0, 1.0));
+    assert!(p.x().abs() < 1e-12 && (p.y() - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_tile() {
+    use super::parser::program;
+
+    // a unit square walked counterclockwise around the origin
+    let (_, square) = program("init(0, 0); iter[4](translation(1, 0); rotation(0, 0, 90deg))").unwrap();
+    let tiled = square.tile(2, 2, (3.0, 2.0));
+    let points = tiled.trajectory(1).unwrap();
+    let copy = square.trajectory(1).unwrap();
+    for offset in [Point::new(0.0, 0.0), Point::new(3.0, 0.0), Point::new(0.0, 2.0), Point::new(3.0, 2.0)] {
+        for p in &copy {
+            let q = *p + offset;
+            assert!(points.iter().any(|r| (r.x - q.x).abs() < 1e-9 && (r.y - q.y).abs() < 1e-9), "{:?} is missing", q);
+        }
+    }
+
+    let ((min, max), (tiled_min, tiled_max)) = (square.bounding_box(1).unwrap(), tiled.bounding_box(1).unwrap());
+    assert!((min.x + 1.0).abs() < 1e-9 && min.y.abs() < 1e-9 && max.x.abs() < 1e-9 && (max.y - 1.0).abs() < 1e-9);
+    assert!((tiled_min.x - min.x).abs() < 1e-9 && (tiled_min.y - min.y).abs() < 1e-9);
+    assert!((tiled_max.x - max.x - 3.0).abs() < 1e-9 && (tiled_max.y - max.y - 2.0).abs() < 1e-9, "{:?}", tiled_max);
+
+    assert_eq!(square, square.tile(1, 1, (3.0, 2.0)));
+    assert_eq!(Expression::Identity, square.tile(0, 4, (1.0, 1.0)).body);
+}
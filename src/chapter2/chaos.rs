@@ -0,0 +1,240 @@
+
+use alloc::{collections::BTreeSet, vec::Vec};
+
+use super::float;
+use super::language::*;
+use super::metrics::bounds;
+
+/// Chaos game steps left out of `box_counting_dimension` while the point
+/// settles onto the attractor.
+const DIMENSION_WARMUP: usize = 100;
+
+/// The pseudo-random generator driving the chaos game. Each yields the
+/// same sequence for a seed regardless of platform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RngKind {
+    /// xorshift64*, seeded through splitmix64; the default.
+    #[default]
+    Xorshift,
+    /// The 64-bit linear congruential generator of Knuth's MMIX, seeded
+    /// with the seed itself and yielding its state after each step.
+    Lcg,
+}
+
+pub(crate) struct Rng {
+    state: u64,
+    kind: RngKind,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng::with_kind(seed, RngKind::default())
+    }
+
+    pub(crate) fn with_kind(seed: u64, kind: RngKind) -> Rng {
+        let state = match kind {
+            RngKind::Xorshift => {
+                // splitmix64 scrambles the seed and keeps the state away from zero
+                let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                (z ^ (z >> 31)) | 1
+            }
+            RngKind::Lcg => seed,
+        };
+        Rng { state, kind }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        match self.kind {
+            RngKind::Xorshift => {
+                self.state ^= self.state >> 12;
+                self.state ^= self.state << 25;
+                self.state ^= self.state >> 27;
+                self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+            }
+            RngKind::Lcg => {
+                self.state = self.state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+                self.state
+            }
+        }
+    }
+
+    /// Uniform in `[0, 1)`, from the top 53 bits, which are the better ones
+    /// of an LCG.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Program {
+    /// Runs the body `steps` times from `init`, resolving every choice at
+    /// random according to its weights, and returns the point reached after
+    /// each step. The numbers come from the generator `rng` seeded with
+    /// `seed`, so the same seed and kind always produce the same points;
+    /// `RngKind::default()` is what `attractor_bounds` and SVG rendering
+    /// use.
+    pub fn chaos_game(&self, steps: usize, seed: u64, rng: RngKind) -> Vec<Point> {
+        let mut rng = Rng::with_kind(seed, rng);
+        let mut p = self.init;
+        (0..steps)
+            .map(|_| {
+                p = self.body.evaluate_choosing(p, &mut |left| rng.next_f64() < left);
+                p
+            })
+            .collect()
+    }
+
+    /// The minimum and maximum corners of the points the chaos game visits
+    /// in `samples` steps after `warmup` steps that are not tracked, giving
+    /// the attractor's extent once the game has settled onto it. Both
+    /// corners are the point after the warmup when there are no samples.
+    /// The same seed always gives the same bounds.
+    pub fn attractor_bounds(&self, warmup: usize, samples: usize, seed: u64) -> (Point, Point) {
+        let mut rng = Rng::new(seed);
+        let mut step = |p: Point| self.body.evaluate_choosing(p, &mut |left| rng.next_f64() < left);
+        let start = (0..warmup).fold(self.init, |p, _| step(p));
+        let mut p = start;
+        let points: Vec<Point> = (0..samples)
+            .map(|_| {
+                p = step(p);
+                p
+            })
+            .collect();
+        bounds(&points).unwrap_or((start, start))
+    }
+
+    /// An estimate of the fractal dimension of the attractor, from `samples`
+    /// chaos game steps seeded with `seed` taken after a short warmup. The
+    /// square around the points is cut into grids of 2, 4, 8, ... boxes a
+    /// side, for as long as there are at least four samples per box of the
+    /// grid, and the slope of the number of boxes holding points against
+    /// the grid size, both logarithmic, is the dimension. It is only an
+    /// estimate: it grows closer with more samples, and drifts low when the
+    /// game visits parts of the attractor rarely. A single point has
+    /// dimension 0.
+    pub fn box_counting_dimension(&self, samples: usize, seed: u64) -> f64 {
+        let points = self.chaos_game(DIMENSION_WARMUP.saturating_add(samples), seed, RngKind::default());
+        let points = &points[DIMENSION_WARMUP..];
+        let Some((min, max)) = bounds(points) else { return 0.0 };
+        let side = (max.x - min.x).max(max.y - min.y);
+        if side == 0.0 {
+            return 0.0;
+        }
+        // counted in u64, where the boxes of the finest grid fit even when
+        // `usize` is 32 bits
+        let mut levels = 2;
+        while levels < 16 && 4 * (1u64 << (2 * (levels + 1))) <= samples as u64 {
+            levels += 1;
+        }
+        let fit: Vec<(f64, f64)> = (1..=levels)
+            .map(|level| {
+                let per_side = (1u64 << level) as f64;
+                let index = |offset: f64| float::floor(offset / side * per_side).min(per_side - 1.0) as u64;
+                let boxes: BTreeSet<_> = points.iter().map(|p| (index(p.x - min.x), index(p.y - min.y))).collect();
+                (float::ln(per_side), float::ln(boxes.len() as f64))
+            })
+            .collect();
+        // least squares slope
+        let n = fit.len() as f64;
+        let (mean_x, mean_y) = (fit.iter().map(|f| f.0).sum::<f64>() / n, fit.iter().map(|f| f.1).sum::<f64>() / n);
+        let covariance: f64 = fit.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+        let variance: f64 = fit.iter().map(|(x, _)| (x - mean_x) * (x - mean_x)).sum();
+        covariance / variance
+    }
+}
+
+#[test]
+fn test_chaos_game_is_reproducible() {
+    use super::parser::program;
+
+    let (_, sierpinski) = program(
+        "init(0, 0); { scale(0, 0, 0.5, 0.5) } or { { scale(1, 0, 0.5, 0.5) } or { scale(0.5, 1, 0.5, 0.5) } }",
+    ).unwrap();
+    let first_run = sierpinski.chaos_game(500, 42, RngKind::Xorshift);
+    let second_run = sierpinski.chaos_game(500, 42, RngKind::Xorshift);
+    assert_eq!(500, first_run.len());
+    assert_eq!(first_run, second_run);
+    assert_ne!(first_run, sierpinski.chaos_game(500, 43, RngKind::Xorshift));
+
+    // both branches get picked
+    assert!(first_run.iter().any(|p| p.x() > 0.5));
+    assert!(first_run.iter().any(|p| p.y() > 0.5));
+    assert!(first_run.iter().all(|p| (0.0..=1.0).contains(&p.x()) && (0.0..=1.0).contains(&p.y())));
+}
+
+#[test]
+fn test_attractor_bounds() {
+    use super::metrics::bounds;
+    use super::parser::program;
+
+    let (_, sierpinski) = program(
+        "init(5, -3); { scale(0, 0, 0.5, 0.5) } or { { scale(1, 0, 0.5, 0.5) } or { scale(0.5, 1, 0.5, 0.5) } }",
+    ).unwrap();
+    let (min, max) = sierpinski.attractor_bounds(100, 2000, 11);
+    assert_eq!((min, max), sierpinski.attractor_bounds(100, 2000, 11));
+    assert_eq!(bounds(&sierpinski.chaos_game(2100, 11, RngKind::default())[100..]), Some((min, max)));
+    assert!(min.x() >= 0.0 && min.y() >= 0.0 && max.x() <= 1.0 && max.y() <= 1.0, "{:?}", (min, max));
+    assert!(min.x() < 0.05 && min.y() < 0.05 && max.x() > 0.95 && max.y() > 0.95, "{:?}", (min, max));
+
+    let after_warmup = sierpinski.chaos_game(10, 11, RngKind::default())[9];
+    assert_eq!((after_warmup, after_warmup), sierpinski.attractor_bounds(10, 0, 11));
+}
+
+#[test]
+fn test_chaos_game_follows_weights() {
+    use super::parser::program;
+
+    // every step lands on 1 or -1 depending on the branch taken
+    let (_, weighted) = program("init(0, 0); scale(0, 0, 0, 0); { translation(1, 0) }:0.7 or { translation(-1, 0) }:0.3").unwrap();
+    let steps = 20_000;
+    let lefts = weighted.chaos_game(steps, 3, RngKind::default()).iter().filter(|p| p.x() > 0.0).count();
+    let share = lefts as f64 / steps as f64;
+    assert!((share - 0.7).abs() < 0.02, "{}", share);
+
+    let (_, never) = program("init(0, 0); scale(0, 0, 0, 0); { translation(1, 0) }:0 or { translation(-1, 0) }:1").unwrap();
+    assert!(never.chaos_game(1000, 3, RngKind::Lcg).iter().all(|p| p.x() < 0.0));
+}
+
+#[test]
+fn test_rng_kinds() {
+    use super::parser::program;
+
+    let (_, sierpinski) = program(
+        "init(0, 0); { scale(0, 0, 0.5, 0.5) } or { { scale(1, 0, 0.5, 0.5) } or { scale(0.5, 1, 0.5, 0.5) } }",
+    ).unwrap();
+    for kind in [RngKind::Xorshift, RngKind::Lcg] {
+        let run = sierpinski.chaos_game(300, 5, kind);
+        assert_eq!(run, sierpinski.chaos_game(300, 5, kind), "{:?}", kind);
+        assert_ne!(run, sierpinski.chaos_game(300, 6, kind), "{:?}", kind);
+    }
+    assert_ne!(sierpinski.chaos_game(300, 5, RngKind::Xorshift), sierpinski.chaos_game(300, 5, RngKind::Lcg));
+    assert_eq!(RngKind::Xorshift, RngKind::default());
+
+    // the LCG starts from the seed itself
+    let mut rng = Rng::with_kind(1, RngKind::Lcg);
+    assert_eq!(6_364_136_223_846_793_005u64.wrapping_add(1_442_695_040_888_963_407), rng.next_u64());
+}
+
+#[test]
+fn test_box_counting_dimension() {
+    use super::parser::program;
+
+    let (_, sierpinski) = program(
+        "init(0, 0); { scale(0, 0, 0.5, 0.5) }:1 or { { scale(1, 0, 0.5, 0.5) } or { scale(0.5, 1, 0.5, 0.5) } }:2",
+    ).unwrap();
+    let dimension = sierpinski.box_counting_dimension(100_000, 7);
+    assert!((dimension - 3f64.log2()).abs() < 0.08, "{}", dimension);
+    assert_eq!(dimension, sierpinski.box_counting_dimension(100_000, 7));
+
+    // the four quarters of the square fill it
+    let (_, square) = program(
+        "init(0, 0); { { scale(0, 0, 0.5, 0.5) } or { scale(1, 0, 0.5, 0.5) } } or { { scale(0, 1, 0.5, 0.5) } or { scale(1, 1, 0.5, 0.5) } }",
+    ).unwrap();
+    let dimension = square.box_counting_dimension(100_000, 7);
+    assert!((dimension - 2.0).abs() < 0.08, "{}", dimension);
+
+    let (_, fixed) = program("init(3, 4); scale(1, 1, 0.5, 0.5)").unwrap();
+    assert!(fixed.box_counting_dimension(1000, 7).abs() < 0.01);
+    assert_eq!(0.0, fixed.box_counting_dimension(0, 7));
+}
@@ -0,0 +1,109 @@
+
+use alloc::{boxed::Box, collections::{BTreeMap, BTreeSet}, string::String};
+
+use super::error::ResolveError;
+use super::language::*;
+
+/// Named expressions available to `Reference`s, as introduced by
+/// `let name = ...` statements.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Definitions {
+    expressions: BTreeMap<String, Expression>,
+}
+
+impl Definitions {
+    pub fn new() -> Definitions {
+        Definitions::default()
+    }
+
+    /// Binds `name` to `expr`, replacing any earlier definition.
+    pub fn define(&mut self, name: impl Into<String>, expr: Expression) {
+        self.expressions.insert(name.into(), expr);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Expression> {
+        self.expressions.get(name)
+    }
+}
+
+impl Expression {
+    /// The expression with every `Reference` replaced by its definition.
+    /// Definitions are resolved in turn, so they may refer to one another,
+    /// but not to themselves through any chain of references.
+    pub fn resolve(&self, definitions: &Definitions) -> Result<Expression, ResolveError> {
+        self.resolve_visiting(definitions, &mut BTreeSet::new())
+    }
+
+    /// `resolve` with `visiting` holding the names whose definitions are
+    /// being inlined further up, any of which showing up again is a cycle.
+    fn resolve_visiting(&self, definitions: &Definitions, visiting: &mut BTreeSet<String>) -> Result<Expression, ResolveError> {
+        let resolved = match self {
+            Expression::Reference(name) => match definitions.get(name) {
+                Some(_) if visiting.contains(name) => return Err(ResolveError::Cyclic { name: name.clone() }),
+                Some(expr) => {
+                    visiting.insert(name.clone());
+                    let resolved = expr.resolve_visiting(definitions, visiting)?;
+                    visiting.remove(name);
+                    resolved
+                }
+                None => return Err(ResolveError::Undefined { name: name.clone() }),
+            },
+            Expression::Chained(first, second) => {
+                Expression::Chained(Box::new(first.resolve_visiting(definitions, visiting)?), Box::new(second.resolve_visiting(definitions, visiting)?))
+            }
+            Expression::Sequence(statements) => {
+                Expression::Sequence(statements.iter().map(|statement| statement.resolve_visiting(definitions, visiting)).collect::<Result<_, _>>()?)
+            }
+            Expression::EitherOr { left, right, weights } => Expression::EitherOr {
+                left: Box::new(left.resolve_visiting(definitions, visiting)?),
+                right: Box::new(right.resolve_visiting(definitions, visiting)?),
+                weights: *weights,
+            },
+            Expression::Iterate { count, body } => Expression::Iterate { count: *count, body: Box::new(body.resolve_visiting(definitions, visiting)?) },
+            Expression::Reverse(body) => Expression::Reverse(Box::new(body.resolve_visiting(definitions, visiting)?)),
+            _ => self.clone(),
+        };
+        Ok(resolved)
+    }
+}
+
+#[test]
+fn test_resolve() {
+    let rotation = Expression::Rotation { u: 0.0, v: 0.0, theta: 0.1 };
+    let mut definitions = Definitions::new();
+    definitions.define("a", rotation.clone());
+    definitions.define("b", Expression::Iterate { count: Some(2), body: Box::new(Expression::Reference("a".to_string())) });
+
+    let expr = Expression::Chained(Box::new(Expression::Reference("b".to_string())), Box::new(Expression::Reference("a".to_string())));
+    let expected_expression = Expression::Chained(
+        Box::new(Expression::Iterate { count: Some(2), body: Box::new(rotation.clone()) }),
+        Box::new(rotation),
+    );
+    assert_eq!(Ok(expected_expression), expr.resolve(&definitions));
+
+    let expr = Expression::EitherOr { left: Box::new(Expression::Reference("a".to_string())), right: Box::new(Expression::Reference("c".to_string())), weights: (0.5, 0.5) };
+    assert_eq!(Err(ResolveError::Undefined { name: "c".to_string() }), expr.resolve(&definitions));
+}
+
+#[test]
+fn test_resolve_cycles() {
+    let reference = |name: &str| Expression::Reference(name.to_string());
+    let mut definitions = Definitions::new();
+    definitions.define("a", Expression::Iterate { count: None, body: Box::new(reference("a")) });
+    assert_eq!(Err(ResolveError::Cyclic { name: "a".to_string() }), reference("a").resolve(&definitions));
+
+    definitions.define("a", Expression::Chained(Box::new(Expression::Identity), Box::new(reference("b"))));
+    definitions.define("b", Expression::Reverse(Box::new(reference("a"))));
+    assert_eq!(Err(ResolveError::Cyclic { name: "b".to_string() }), reference("b").resolve(&definitions));
+    let expr = Expression::EitherOr { left: Box::new(Expression::Identity), right: Box::new(reference("a")), weights: (0.5, 0.5) };
+    assert_eq!(Err(ResolveError::Cyclic { name: "a".to_string() }), expr.resolve(&definitions));
+
+    // using a name twice is not a cycle
+    definitions.define("b", Expression::Identity);
+    let twice = Expression::Chained(Box::new(reference("b")), Box::new(reference("a")));
+    let expected_expression = Expression::Chained(
+        Box::new(Expression::Identity),
+        Box::new(Expression::Chained(Box::new(Expression::Identity), Box::new(Expression::Identity))),
+    );
+    assert_eq!(Ok(expected_expression), twice.resolve(&definitions));
+}
@@ -0,0 +1,58 @@
+
+use alloc::{format, string::{String, ToString}, vec, vec::Vec};
+use core::fmt::Write;
+
+use super::language::*;
+
+impl Expression {
+    /// Renders the tree as a Graphviz DOT digraph with one node per
+    /// expression. Leaves are labeled with their syntax, and the edges to
+    /// the sides of a choice are labeled `left` and `right`.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph expression {\n");
+        self.write_dot(&mut dot, &mut 0);
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Writes this subtree with its root numbered `*next`, advancing `next`
+    /// past every node written, and returns the root's number.
+    fn write_dot(&self, dot: &mut String, next: &mut usize) -> usize {
+        let id = *next;
+        *next += 1;
+        let (label, children): (String, Vec<(&Expression, Option<&str>)>) = match self {
+            Expression::Chained(first, second) => ("chain".to_string(), vec![(first, None), (second, None)]),
+            Expression::Sequence(statements) => ("sequence".to_string(), statements.iter().map(|statement| (statement, None)).collect()),
+            Expression::EitherOr { left, right, .. } => ("or".to_string(), vec![(left, Some("left")), (right, Some("right"))]),
+            Expression::Iterate { count: None, body } => ("iter".to_string(), vec![(body, None)]),
+            Expression::Iterate { count: Some(n), body } => (format!("iter[{}]", n), vec![(body, None)]),
+            Expression::Reverse(body) => ("rev".to_string(), vec![(body, None)]),
+            leaf => (leaf.to_string(), vec![]),
+        };
+        writeln!(dot, "  n{} [label=\"{}\"];", id, label).unwrap();
+        for (child, edge_label) in children {
+            let child_id = child.write_dot(dot, next);
+            match edge_label {
+                Some(edge_label) => writeln!(dot, "  n{} -> n{} [label=\"{}\"];", id, child_id, edge_label).unwrap(),
+                None => writeln!(dot, "  n{} -> n{};", id, child_id).unwrap(),
+            }
+        }
+        id
+    }
+}
+
+#[test]
+fn test_to_dot() {
+    use super::parser::parse_expression;
+
+    let dot = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0.to_dot();
+    assert!(dot.starts_with("digraph expression {\n") && dot.ends_with("}\n"));
+    assert_eq!(6, dot.matches("[label=").count());
+    assert_eq!(5, dot.matches(" -> ").count());
+    assert!(dot.contains("  n0 [label=\"chain\"];\n  n1 [label=\"iter\"];\n  n2 [label=\"chain\"];\n  n3 [label=\"translation(12, 0.4)\"];\n"));
+    assert!(dot.contains("  n0 -> n5;\n"));
+
+    let dot = parse_expression("{ translation(1, 0) } or { iter[2](id) }").unwrap().0.to_dot();
+    assert!(dot.contains("  n0 -> n1 [label=\"left\"];\n"));
+    assert!(dot.contains("  n2 [label=\"iter[2]\"];\n  n3 [label=\"id\"];\n  n2 -> n3;\n  n0 -> n2 [label=\"right\"];\n"));
+}
@@ -0,0 +1,190 @@
+
+use alloc::{format, string::{String, ToString}};
+use core::fmt;
+use core::ops::Range;
+
+/// A parse failure located in the original input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// Byte offset of the failure into the original input.
+    pub offset: usize,
+    /// 1-based line of the failure.
+    pub line: usize,
+    /// 1-based column of the failure, counted in characters.
+    pub column: usize,
+    /// The token found at the failure point, or `end of input`.
+    pub unexpected: String,
+    pub message: String,
+    pub kind: ParseErrorKind,
+    /// For `parse_many`, the index of the program that failed among the
+    /// programs in the input.
+    pub chunk: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// The input does not follow the grammar.
+    Syntax,
+    /// Groups are nested deeper than the limit of `depth` levels.
+    TooDeep { depth: usize },
+    /// A number is infinite or not a number.
+    NonFinite,
+    /// An iteration count does not fit in a `usize`.
+    CountOverflow,
+    /// A rational literal has a zero denominator.
+    DivisionByZero,
+    /// A choice weight is negative, or both weights of a choice are zero.
+    InvalidWeight,
+    /// The input read is not UTF-8.
+    InvalidUtf8,
+    /// Reading the input failed.
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
+    /// A reference to a name with no definition in scope.
+    UndefinedName { name: String },
+    /// A definition that refers to itself, directly or through other
+    /// definitions.
+    CyclicReference { name: String },
+}
+
+/// Something questionable in input that still parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    /// Byte offset of the questionable input.
+    pub offset: usize,
+    pub message: String,
+}
+
+/// A semantic problem found by `Program::validate`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub message: String,
+    /// Byte range of the offending input. `None` when the program has no
+    /// positions, as a `Program` doesn't keep them; the message then names
+    /// the offending node instead.
+    pub span: Option<Range<usize>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum IssueKind {
+    /// An unbounded iteration whose body never moves the point.
+    NoProgress,
+    /// A scale by zero or an affine map collapsing the plane, which can't be
+    /// undone.
+    Degenerate,
+    /// A reference with no definition.
+    UnresolvedReference { name: String },
+}
+
+/// A failure to evaluate an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    /// The result would hold more than `limit` points.
+    TooManyPoints { limit: usize },
+    /// Iteration `iteration` moved the point at least as far as the one
+    /// before it, so the expression isn't contracting towards a fixed point.
+    NotContracting { iteration: usize },
+}
+
+/// A reference that could not be inlined by `Expression::resolve`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveError {
+    /// No definition of `name` was given.
+    Undefined { name: String },
+    /// The definition of `name` refers back to `name`, directly or through
+    /// other definitions.
+    Cyclic { name: String },
+}
+
+/// A failure to read an expression from `Expression::to_bytes` output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The bytes don't start with the format's magic byte.
+    BadMagic,
+    /// The bytes are in a later version of the format than is understood.
+    UnsupportedVersion { version: u8 },
+    /// The bytes end partway through an expression.
+    Truncated,
+    /// The byte at `offset` doesn't name a variant.
+    UnknownTag { tag: u8, offset: usize },
+    /// An iteration count or length at `offset` doesn't fit in a `usize`.
+    CountOverflow { offset: usize },
+    /// Expressions are nested deeper than the limit of `depth` levels.
+    TooDeep { depth: usize },
+    /// A reference name at `offset` is not UTF-8.
+    InvalidUtf8 { offset: usize },
+    /// Bytes are left over at `offset` after the expression.
+    TrailingBytes { offset: usize },
+}
+
+impl ParseError {
+    /// Builds an error for a failure at `tail`, which must be a suffix of `original`.
+    pub(crate) fn at(original: &str, tail: &str, message: String) -> ParseError {
+        let offset = if tail.is_empty() {
+            original.len()
+        } else {
+            tail.as_ptr() as usize - original.as_ptr() as usize
+        };
+        let before = &original[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        ParseError {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            unexpected: unexpected_token(tail),
+            message,
+            kind: ParseErrorKind::Syntax,
+            chunk: None,
+        }
+    }
+}
+
+/// The message and where it applies, as `line:column`.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.message, self.line, self.column)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+/// Formats `err`, a failure to parse `input`, as a compiler-style
+/// diagnostic: the message, the position, and the source line with a caret
+/// under the failing column.
+pub fn render_error(input: &str, err: &ParseError) -> String {
+    let source_line = input.lines().nth(err.line - 1).unwrap_or("");
+    // tabs are kept so that the caret lines up however they are displayed
+    let padding: String = source_line.chars().take(err.column - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let number = err.line.to_string();
+    let gutter = " ".repeat(number.len());
+    format!(
+        "error: {}\n{}--> {}:{}\n{} |\n{} | {}\n{} | {}^\n",
+        err.message, gutter, err.line, err.column, gutter, number, source_line, gutter, padding,
+    )
+}
+
+fn unexpected_token(tail: &str) -> String {
+    let word: String = tail.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+    match tail.chars().next() {
+        None => "end of input".to_string(),
+        Some(c) if word.is_empty() => c.to_string(),
+        Some(_) => word,
+    }
+}
+
+#[test]
+fn test_render_error() {
+    use super::parser::parse_expression;
+
+    let input = "iter(\n    translation(12.0, x);\n    rotation(0.2, 0.3, 0.5)\n)";
+    let err = parse_expression(input).unwrap_err();
+    let expected = "error: expected a number\n --> 2:23\n  |\n2 |     translation(12.0, x);\n  |                       ^\n";
+    assert_eq!(expected, render_error(input, &err));
+
+    let input = "translation(1, 2";
+    let rendered = render_error(input, &parse_expression(input).unwrap_err());
+    assert!(rendered.contains("1 | translation(1, 2\n"));
+    assert!(rendered.ends_with(&format!("  | {}^\n", " ".repeat(input.len()))), "{}", rendered);
+}
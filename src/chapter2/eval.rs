@@ -0,0 +1,543 @@
+
+use alloc::vec::Vec;
+
+use super::error::EvalError;
+use super::float;
+use super::language::*;
+
+/// Limits on what evaluation may produce.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalConfig {
+    /// Most points a trajectory may contain, start included, so that huge
+    /// iteration counts fail up front instead of exhausting memory.
+    pub max_points: usize,
+}
+
+impl Default for EvalConfig {
+    fn default() -> EvalConfig {
+        EvalConfig { max_points: 1_000_000 }
+    }
+}
+
+impl Expression {
+    /// Applies the transformation to `p`. Choices always take the left branch
+    /// and an iteration without a count applies its body once.
+    ///
+    /// Panics on a `Reference`; see `Expression::resolve`.
+    pub fn evaluate(&self, p: Point) -> Point {
+        self.evaluate_choosing(p, &mut |_| true)
+    }
+
+    /// Like `evaluate`, but every choice takes its left branch exactly when
+    /// `choose_left` returns true. It is passed the probability the choice's
+    /// weights give the left branch.
+    pub(crate) fn evaluate_choosing(&self, p: Point, choose_left: &mut dyn FnMut(f64) -> bool) -> Point {
+        match self {
+            Expression::Translation { u, v } => p + Point::new(*u, *v),
+            Expression::Rotation { u, v, theta } => {
+                let Point { x: dx, y: dy } = p - Point::new(*u, *v);
+                let (sin, cos) = float::sin_cos(*theta);
+                Point { x: u + dx * cos - dy * sin, y: v + dx * sin + dy * cos }
+            }
+            Expression::Scale { u, v, sx, sy } => Point { x: u + (p.x - u) * sx, y: v + (p.y - v) * sy },
+            Expression::Reflection { u, v, theta } => {
+                let Point { x: dx, y: dy } = p - Point::new(*u, *v);
+                let (sin, cos) = float::sin_cos(2.0 * theta);
+                Point { x: u + dx * cos + dy * sin, y: v + dx * sin - dy * cos }
+            }
+            Expression::Chained(first, second) => {
+                let p = first.evaluate_choosing(p, choose_left);
+                second.evaluate_choosing(p, choose_left)
+            }
+            Expression::Sequence(statements) => statements.iter().fold(p, |p, statement| statement.evaluate_choosing(p, choose_left)),
+            Expression::Reverse(body) => {
+                body.chain_statements().into_iter().rev().fold(p, |p, statement| statement.evaluate_choosing(p, choose_left))
+            }
+            Expression::EitherOr { left, right, weights: (w1, w2) } => {
+                if choose_left(w1 / (w1 + w2)) {
+                    left.evaluate_choosing(p, choose_left)
+                } else {
+                    right.evaluate_choosing(p, choose_left)
+                }
+            }
+            Expression::Iterate { count, body } => {
+                (0..count.unwrap_or(1)).fold(p, |p, _| body.evaluate_choosing(p, choose_left))
+            }
+            Expression::Identity | Expression::Spin { .. } => p,
+            Expression::Affine(m) => m.apply(p),
+            Expression::Reference(name) => panic!("unresolved reference `{}`", name),
+        }
+    }
+
+    /// Applies the transformation to each of `points`, as `evaluate` does.
+    /// An expression without choices or iterations is reduced to a single
+    /// matrix first, and with the `rayon` feature the points are split across
+    /// threads.
+    pub fn evaluate_batch(&self, points: &[Point]) -> Vec<Point> {
+        let matrix = self.to_affine();
+        let apply = |p: &Point| match &matrix {
+            Some(m) => m.apply(*p),
+            None => self.evaluate(*p),
+        };
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            points.par_iter().map(apply).collect()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            points.iter().map(apply).collect()
+        }
+    }
+
+    /// The starting point followed by the point reached after every step.
+    /// Each iteration of an `Iterate` is a step, with nested iterations
+    /// contributing their own steps in evaluation order; an expression
+    /// without iterations is a single step. Fails, before evaluating anything,
+    /// if there would be more points than `EvalConfig::max_points` allows.
+    pub fn trajectory(&self, start: Point) -> Result<Vec<Point>, EvalError> {
+        self.trajectory_with(start, &EvalConfig::default())
+    }
+
+    pub fn trajectory_with(&self, start: Point, config: &EvalConfig) -> Result<Vec<Point>, EvalError> {
+        let mut points = allocate_points(self.step_count(), config)?;
+        points.push(start);
+        self.walk_steps(start, &mut |p| points.push(p));
+        Ok(points)
+    }
+
+    /// Like `evaluate`, but calls `on_step` with `0` and `start`, then with
+    /// the point reached after every statement of a chain and every
+    /// iteration, so a chain without iterations still reports each of its
+    /// statements. Along with the point comes the index of the iteration of
+    /// the innermost `Iterate` it is reached in, `0` outside of any.
+    pub fn evaluate_with<F: FnMut(usize, Point)>(&self, start: Point, on_step: &mut F) -> Point {
+        on_step(0, start);
+        self.walk_statements(start, 0, on_step)
+    }
+
+    /// Applies the transformation over and over from `start` until a step
+    /// moves the point less than `epsilon`, as a contraction does near its
+    /// fixed point, and returns the point reached with the number of
+    /// applications made; after `max_iters` applications it stops wherever
+    /// the point is. A step at least as long as the one before means the
+    /// map isn't contracting and fails with `EvalError::NotContracting`.
+    /// That check is conservative: a contraction that shears can lengthen
+    /// some steps on its way in.
+    pub fn evaluate_to_fixpoint(&self, start: Point, epsilon: f64, max_iters: usize) -> Result<(Point, usize), EvalError> {
+        let (mut p, mut previous_step) = (start, f64::INFINITY);
+        for iteration in 1..=max_iters {
+            let next = self.evaluate(p);
+            let step = float::hypot((next - p).x, (next - p).y);
+            p = next;
+            if step < epsilon {
+                return Ok((p, iteration));
+            }
+            if step >= previous_step {
+                return Err(EvalError::NotContracting { iteration });
+            }
+            previous_step = step;
+        }
+        Ok((p, max_iters))
+    }
+
+    /// Number of points `walk_steps` reports, or `None` if it overflows.
+    fn step_count(&self) -> Option<usize> {
+        match self {
+            Expression::Iterate { count, body } => body.step_count()?.checked_mul(count.unwrap_or(1)),
+            Expression::Chained(first, second) if self.contains_iterate() => first.step_count()?.checked_add(second.step_count()?),
+            Expression::Sequence(statements) => chain_step_count(&statements.iter().collect::<Vec<_>>()),
+            Expression::Reverse(body) => chain_step_count(&body.chain_statements().into_iter().rev().collect::<Vec<_>>()),
+            Expression::EitherOr { left, .. } if self.contains_iterate() => left.step_count(),
+            _ => Some(1),
+        }
+    }
+
+    /// Evaluates from `p` in iteration `index`, passing the end point of
+    /// every statement to `on_step` for `evaluate_with`, and returns the
+    /// final point.
+    fn walk_statements(&self, p: Point, index: usize, on_step: &mut dyn FnMut(usize, Point)) -> Point {
+        match self {
+            Expression::Iterate { count, body } => {
+                (0..count.unwrap_or(1)).fold(p, |p, index| body.walk_statements(p, index, on_step))
+            }
+            Expression::Chained(..) | Expression::Sequence(_) => {
+                self.chain_statements().into_iter().fold(p, |p, statement| statement.walk_statements(p, index, on_step))
+            }
+            Expression::Reverse(body) => {
+                body.chain_statements().into_iter().rev().fold(p, |p, statement| statement.walk_statements(p, index, on_step))
+            }
+            Expression::EitherOr { left, .. } => left.walk_statements(p, index, on_step),
+            _ => {
+                let p = self.evaluate(p);
+                on_step(index, p);
+                p
+            }
+        }
+    }
+
+    /// Evaluates from `p`, passing the end point of every step to `on_step`,
+    /// and returns the final point.
+    pub(crate) fn walk_steps(&self, p: Point, on_step: &mut dyn FnMut(Point)) -> Point {
+        match self {
+            Expression::Iterate { count, body } => (0..count.unwrap_or(1)).fold(p, |p, _| body.walk_steps(p, on_step)),
+            Expression::Chained(first, second) if self.contains_iterate() => {
+                let p = first.walk_steps(p, on_step);
+                second.walk_steps(p, on_step)
+            }
+            Expression::Sequence(statements) => walk_chain_steps(&statements.iter().collect::<Vec<_>>(), p, on_step),
+            Expression::Reverse(body) => walk_chain_steps(&body.chain_statements().into_iter().rev().collect::<Vec<_>>(), p, on_step),
+            Expression::EitherOr { left, .. } if self.contains_iterate() => left.walk_steps(p, on_step),
+            _ => {
+                let p = self.evaluate(p);
+                on_step(p);
+                p
+            }
+        }
+    }
+
+    fn contains_iterate(&self) -> bool {
+        match self {
+            Expression::Iterate { .. } => true,
+            Expression::Chained(first, second) => first.contains_iterate() || second.contains_iterate(),
+            Expression::Sequence(statements) => statements.iter().any(Expression::contains_iterate),
+            Expression::Reverse(body) => body.contains_iterate(),
+            Expression::EitherOr { left, right, .. } => left.contains_iterate() || right.contains_iterate(),
+            _ => false,
+        }
+    }
+}
+
+/// The steps of `statements` run in order, counted as for the right-nested
+/// chain of them.
+fn chain_step_count(statements: &[&Expression]) -> Option<usize> {
+    match statements {
+        [statement] => statement.step_count(),
+        [first, rest @ ..] if statements.iter().any(|statement| statement.contains_iterate()) => {
+            first.step_count()?.checked_add(chain_step_count(rest)?)
+        }
+        _ => Some(1),
+    }
+}
+
+fn walk_chain_steps(statements: &[&Expression], p: Point, on_step: &mut dyn FnMut(Point)) -> Point {
+    match statements {
+        [statement] => statement.walk_steps(p, on_step),
+        [first, rest @ ..] if statements.iter().any(|statement| statement.contains_iterate()) => {
+            let p = first.walk_steps(p, on_step);
+            walk_chain_steps(rest, p, on_step)
+        }
+        _ => {
+            let p = statements.iter().fold(p, |p, statement| statement.evaluate(p));
+            on_step(p);
+            p
+        }
+    }
+}
+
+impl Program {
+    /// The points visited by running the body `steps` times from `init`:
+    /// `init` itself followed by the trajectory of every run, with choices
+    /// taking their left branch.
+    pub fn trajectory(&self, steps: usize) -> Result<Vec<Point>, EvalError> {
+        self.trajectory_with(steps, &EvalConfig::default())
+    }
+
+    pub fn trajectory_with(&self, steps: usize, config: &EvalConfig) -> Result<Vec<Point>, EvalError> {
+        let mut points = allocate_points(self.body.step_count().and_then(|count| count.checked_mul(steps)), config)?;
+        points.push(self.init);
+        (0..steps).fold(self.init, |start, _| self.body.walk_steps(start, &mut |p| points.push(p)));
+        Ok(points)
+    }
+
+    /// The number of steps in one run of the body, as `trajectory` counts
+    /// them, without running it: the points it adds after `init`. `None` if
+    /// the body has an iteration without a count anywhere, even in a branch
+    /// a run wouldn't take, or the count overflows.
+    pub fn frame_count(&self) -> Option<usize> {
+        let mut bounded = true;
+        self.body.visit(&mut |node| bounded &= !matches!(node, Expression::Iterate { count: None, .. }));
+        if bounded { self.body.step_count() } else { None }
+    }
+
+    /// `m` points spaced evenly by distance along one run of the body's
+    /// trajectory, taken as straight segments between consecutive points,
+    /// from `init` to where the run ends. Choices take their left branch.
+    /// When the path has no length every point is `init`. The trajectory is
+    /// walked rather than collected, so it isn't limited by `EvalConfig`.
+    pub fn sample_uniform(&self, m: usize) -> Vec<Point> {
+        let walk = |on_segment: &mut dyn FnMut(Point, Point)| {
+            let mut previous = self.init;
+            self.body.walk_steps(self.init, &mut |p| {
+                on_segment(previous, p);
+                previous = p;
+            })
+        };
+        let mut length = 0.0;
+        let end = walk(&mut |from, to| length += float::hypot((to - from).x, (to - from).y));
+        let spacing = if m > 1 { length / (m - 1) as f64 } else { 0.0 };
+
+        let mut samples = Vec::with_capacity(m);
+        let mut walked = 0.0;
+        walk(&mut |from, to| {
+            let segment = float::hypot((to - from).x, (to - from).y);
+            while samples.len() < m && samples.len() as f64 * spacing <= walked + segment {
+                let t = if segment > 0.0 { (samples.len() as f64 * spacing - walked) / segment } else { 0.0 };
+                samples.push(Point { x: from.x + (to.x - from.x) * t, y: from.y + (to.y - from.y) * t });
+            }
+            walked += segment;
+        });
+        // rounding can leave the last targets just past the end
+        samples.resize(m, end);
+        samples
+    }
+}
+
+/// Room for a starting point and `steps` more, unless that exceeds the limit.
+fn allocate_points(steps: Option<usize>, config: &EvalConfig) -> Result<Vec<Point>, EvalError> {
+    match steps.and_then(|steps| steps.checked_add(1)) {
+        Some(count) if count <= config.max_points => Ok(Vec::with_capacity(count)),
+        _ => Err(EvalError::TooManyPoints { limit: config.max_points }),
+    }
+}
+
+#[test]
+fn test_translation_then_rotation() {
+    let expr = Expression::Chained(
+        Box::new(Expression::Translation { u: 1.0, v: 0.0 }),
+        Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: std::f64::consts::FRAC_PI_2 }),
+    );
+    let p = expr.evaluate(Point { x: 1.0, y: 0.0 });
+    assert!(p.x().abs() < 1e-12);
+    assert!((p.y() - 2.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_rotation_about_center() {
+    let expr = Expression::Rotation { u: 1.0, v: 1.0, theta: std::f64::consts::PI };
+    let p = expr.evaluate(Point { x: 2.0, y: 1.0 });
+    assert!(p.x().abs() < 1e-12);
+    assert!((p.y() - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn test_iterate_count() {
+    let expr = Expression::Iterate {
+        count: Some(5),
+        body: Box::new(Expression::Translation { u: 1.0, v: 0.5 }),
+    };
+    let p = expr.evaluate(Point { x: 0.0, y: 0.0 });
+    assert_eq!((5.0, 2.5), (p.x(), p.y()));
+
+    let expr = Expression::Iterate {
+        count: None,
+        body: Box::new(Expression::Translation { u: 1.0, v: 0.5 }),
+    };
+    let p = expr.evaluate(Point { x: 0.0, y: 0.0 });
+    assert_eq!((1.0, 0.5), (p.x(), p.y()));
+}
+
+#[test]
+fn test_scale_about_origin() {
+    let expr = Expression::Scale { u: 0.0, v: 0.0, sx: 2.0, sy: 2.0 };
+    let p = expr.evaluate(Point { x: 1.5, y: -3.0 });
+    assert_eq!((3.0, -6.0), (p.x(), p.y()));
+
+    let expr = Expression::Scale { u: 1.0, v: 1.0, sx: 3.0, sy: 0.5 };
+    let p = expr.evaluate(Point { x: 2.0, y: 3.0 });
+    assert_eq!((4.0, 2.0), (p.x(), p.y()));
+}
+
+#[test]
+fn test_trajectory() {
+    use super::parser::parse_expression;
+
+    let origin = Point { x: 0.0, y: 0.0 };
+    let points: Vec<(f64, f64)> = parse_expression("iter[3](translation(1,0))").unwrap().0
+        .trajectory(origin).unwrap().iter().map(|p| (p.x(), p.y())).collect();
+    assert_eq!(vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (3.0, 0.0)], points);
+
+    let points: Vec<(f64, f64)> = parse_expression("translation(1,0); translation(0,2)").unwrap().0
+        .trajectory(origin).unwrap().iter().map(|p| (p.x(), p.y())).collect();
+    assert_eq!(vec![(0.0, 0.0), (1.0, 2.0)], points);
+
+    let points: Vec<(f64, f64)> = parse_expression("iter[2](iter[2](translation(1,0)); translation(0,1)); translation(5,5)").unwrap().0
+        .trajectory(origin).unwrap().iter().map(|p| (p.x(), p.y())).collect();
+    let expected_points = vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (2.0, 1.0), (3.0, 1.0), (4.0, 1.0), (4.0, 2.0), (9.0, 7.0)];
+    assert_eq!(expected_points, points);
+}
+
+#[test]
+fn test_program_trajectory() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(1, 1); translation(1, 0); translation(0, 1)").unwrap();
+    let points: Vec<(f64, f64)> = walk.trajectory(3).unwrap().iter().map(|p| (p.x(), p.y())).collect();
+    assert_eq!(vec![(1.0, 1.0), (2.0, 2.0), (3.0, 3.0), (4.0, 4.0)], points);
+    assert_eq!(vec![walk.init], walk.trajectory(0).unwrap());
+
+    let (_, walk) = program("init(0, 0); iter[2](translation(1, 0)); translation(0, 1)").unwrap();
+    let points: Vec<(f64, f64)> = walk.trajectory(2).unwrap().iter().map(|p| (p.x(), p.y())).collect();
+    assert_eq!(vec![(0.0, 0.0), (1.0, 0.0), (2.0, 0.0), (2.0, 1.0), (3.0, 1.0), (4.0, 1.0), (4.0, 2.0)], points);
+}
+
+#[test]
+fn test_trajectory_limit() {
+    use super::parser::{parse_expression, program};
+
+    let origin = Point { x: 0.0, y: 0.0 };
+    let expr = parse_expression("iter[1000](translation(1, 0))").unwrap().0;
+    let config = EvalConfig { max_points: 1001 };
+    assert_eq!(1001, expr.trajectory_with(origin, &config).unwrap().len());
+    let config = EvalConfig { max_points: 1000 };
+    assert_eq!(Err(EvalError::TooManyPoints { limit: 1000 }), expr.trajectory_with(origin, &config));
+
+    // the size is checked before anything runs, even when it overflows
+    let expr = parse_expression("iter[18446744073709551615](iter[18446744073709551615](translation(1, 0)))").unwrap().0;
+    assert_eq!(Err(EvalError::TooManyPoints { limit: 1_000_000 }), expr.trajectory(origin));
+    let (_, walk) = program("init(0, 0); iter[1000](translation(1, 0))").unwrap();
+    assert_eq!(Err(EvalError::TooManyPoints { limit: 1_000_000 }), walk.trajectory(1000));
+    assert_eq!(999_001, walk.trajectory(999).unwrap().len());
+}
+
+#[test]
+fn test_evaluate_batch() {
+    use super::parser::parse_expression;
+
+    let points: Vec<Point> = (0..1000).map(|i| Point { x: i as f64 * 0.1, y: (i % 7) as f64 - 3.0 }).collect();
+    for raw_expression in ["translation(1, 2); rotation(0.5, -1, 0.3); scale(1, 1, 2, 0.5); reflection(0, 0, 1)", "iter[3](translation(1, 0); rotation(0, 0, 0.1))"] {
+        let expr = parse_expression(raw_expression).unwrap().0;
+        let batch = expr.evaluate_batch(&points);
+        assert_eq!(points.len(), batch.len());
+        for (p, q) in points.iter().zip(&batch) {
+            let expected = expr.evaluate(*p);
+            assert!((expected.x - q.x).abs() < 1e-9 && (expected.y - q.y).abs() < 1e-9);
+        }
+    }
+    assert!(Expression::Identity.evaluate_batch(&[]).is_empty());
+}
+
+#[test]
+fn test_reverse() {
+    use super::parser::parse_expression;
+
+    let reversed = parse_expression("rev(translation(1, 0); rotation(0, 0, 90deg); iter[2](scale(1, 1, 2, 0.5)))").unwrap().0;
+    let expected = parse_expression("iter[2](scale(1, 1, 2, 0.5)); rotation(0, 0, 90deg); translation(1, 0)").unwrap().0;
+    let p = Point { x: 0.5, y: -2.0 };
+    assert_eq!(expected.evaluate(p), reversed.evaluate(p));
+    assert_eq!(expected.trajectory(p), reversed.trajectory(p));
+    let reversed = parse_expression("rev(translation(1, 0); rotation(0, 0, 90deg))").unwrap().0.to_affine().unwrap();
+    let expected = parse_expression("rotation(0, 0, 90deg); translation(1, 0)").unwrap().0.to_affine().unwrap();
+    assert!(expected.approx_eq(&reversed, 1e-12));
+
+    // only the top-level order is reversed
+    let reversed = parse_expression("rev(iter[2](translation(1, 0); rotation(0, 0, 90deg)))").unwrap().0;
+    let expected = parse_expression("iter[2](translation(1, 0); rotation(0, 0, 90deg))").unwrap().0;
+    assert_eq!(expected.evaluate(p), reversed.evaluate(p));
+}
+
+#[test]
+fn test_evaluate_with() {
+    use super::parser::parse_expression;
+
+    let start = Point { x: 1.0, y: -1.0 };
+    let cases = [
+        ("translation(1, 2)", vec![0, 0]),
+        ("iter[3](translation(1, 0); iter[2](rotation(0, 0, 0.5))); scale(0, 0, 2, 2); iter[2](id)", vec![0, 0, 0, 1, 1, 0, 1, 2, 0, 1, 0, 0, 1]),
+        ("rev(iter[2](translation(0, 1)); rotation(1, 1, 1))", vec![0, 0, 0, 1]),
+    ];
+    for (raw_expression, indices) in cases {
+        let expr = parse_expression(raw_expression).unwrap().0;
+        let mut steps = Vec::new();
+        let end = expr.evaluate_with(start, &mut |index, p| steps.push((index, p)));
+        let trajectory = expr.trajectory(start).unwrap();
+        assert_eq!(trajectory, steps.iter().map(|(_, p)| *p).collect::<Vec<_>>(), "{}", raw_expression);
+        assert_eq!(indices, steps.iter().map(|(index, _)| *index).collect::<Vec<_>>(), "{}", raw_expression);
+        assert_eq!(expr.evaluate(start), end);
+    }
+
+    // every statement of a flat chain is reported, where `trajectory` has
+    // the chain as a single step
+    let expr = parse_expression("iter[2](translation(1, 0); rotation(0, 0, 90deg))").unwrap().0;
+    let mut steps = Vec::new();
+    let end = expr.evaluate_with(Point::new(0.0, 0.0), &mut |index, p| steps.push((index, p)));
+    let expected_steps = [(0, Point::new(0.0, 0.0)), (0, Point::new(1.0, 0.0)), (0, Point::new(0.0, 1.0)), (1, Point::new(1.0, 1.0)), (1, Point::new(-1.0, 1.0))];
+    assert_eq!(expected_steps.len(), steps.len());
+    for ((index, p), (expected_index, expected)) in steps.iter().zip(expected_steps) {
+        assert_eq!(expected_index, *index);
+        assert!(float::hypot((*p - expected).x, (*p - expected).y) < 1e-12, "{:?}", steps);
+    }
+    assert_eq!(3, expr.trajectory(Point::new(0.0, 0.0)).unwrap().len());
+    assert_eq!(expr.evaluate(Point::new(0.0, 0.0)), end);
+}
+
+#[test]
+fn test_evaluate_to_fixpoint() {
+    use super::parser::parse_expression;
+
+    let halve = parse_expression("scale(0, 0, 0.5, 0.5)").unwrap().0;
+    let (p, iterations) = halve.evaluate_to_fixpoint(Point::new(8.0, -6.0), 1e-9, 100).unwrap();
+    assert!(float::hypot(p.x, p.y) < 1e-9, "{:?}", p);
+    // the step after n halvings is 10 / 2^n
+    assert_eq!(34, iterations);
+    assert_eq!(Ok((Point::new(1.0, -0.75), 3)), halve.evaluate_to_fixpoint(Point::new(8.0, -6.0), 1e-9, 3));
+
+    let spiral = parse_expression("rotation(1, 1, 1); scale(1, 1, 0.9, 0.9)").unwrap().0;
+    let (p, _) = spiral.evaluate_to_fixpoint(Point::new(0.0, 0.0), 1e-12, 1000).unwrap();
+    assert!(float::hypot(p.x - 1.0, p.y - 1.0) < 1e-10, "{:?}", p);
+
+    let shift = parse_expression("translation(1, 0)").unwrap().0;
+    assert_eq!(Err(EvalError::NotContracting { iteration: 2 }), shift.evaluate_to_fixpoint(Point::new(0.0, 0.0), 1e-9, 100));
+    let turn = parse_expression("rotation(0, 0, 0.5)").unwrap().0;
+    assert_eq!(Ok((Point::new(0.0, 0.0), 1)), turn.evaluate_to_fixpoint(Point::new(0.0, 0.0), 1e-9, 100));
+}
+
+#[test]
+fn test_sample_uniform() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(1, 1); iter[3](translation(2, 0)); translation(0, 3)").unwrap();
+    let samples = walk.sample_uniform(7);
+    let expected = [(1.0, 1.0), (2.5, 1.0), (4.0, 1.0), (5.5, 1.0), (7.0, 1.0), (7.0, 2.5), (7.0, 4.0)];
+    assert_eq!(7, samples.len());
+    for (p, (x, y)) in samples.iter().zip(expected) {
+        assert!((p.x() - x).abs() < 1e-12 && (p.y() - y).abs() < 1e-12, "{:?}", samples);
+    }
+    for pair in samples.windows(2) {
+        let step = pair[1] - pair[0];
+        assert!((step.x().hypot(step.y()) - 1.5).abs() < 1e-12);
+    }
+
+    assert_eq!(vec![Point::new(1.0, 1.0)], walk.sample_uniform(1));
+    assert!(walk.sample_uniform(0).is_empty());
+    let (_, still) = program("init(2, -1); iter[4](rotation(2, -1, 1))").unwrap();
+    assert_eq!(vec![Point::new(2.0, -1.0); 3], still.sample_uniform(3));
+}
+
+#[test]
+fn test_frame_count() {
+    use super::parser::program;
+
+    let (_, nested) = program("init(0, 0); iter[3](translation(1, 0); iter[4](rotation(0, 0, 1))); iter[2](id); scale(0, 0, 2, 2)").unwrap();
+    assert_eq!(Some(3 * (1 + 4) + 2 + 1), nested.frame_count());
+    assert_eq!(nested.trajectory(1).unwrap().len() - 1, nested.frame_count().unwrap());
+
+    let (_, plain) = program("init(0, 0); translation(1, 0); rotation(0, 0, 1)").unwrap();
+    assert_eq!(Some(1), plain.frame_count());
+    let (_, unbounded) = program("init(0, 0); iter[3](translation(1, 0)); { id } or { iter(rotation(0, 0, 1)) }").unwrap();
+    assert_eq!(None, unbounded.frame_count());
+}
+
+#[cfg(not(feature = "std"))]
+#[test]
+fn test_without_std() {
+    use super::parser::parse_program;
+
+    // the float functions come from `libm` here
+    let parsed_program = parse_program("init(1, 0); iter[4](rotation(0, 0, 90deg); translation(0.5, 0)); scale(0, 0, 2, 2)").unwrap();
+    let points = parsed_program.trajectory(1).unwrap();
+    assert_eq!(6, points.len());
+    let end = parsed_program.evaluate();
+    assert!((end.x - 2.0).abs() < 1e-12 && end.y.abs() < 1e-12, "{:?}", end);
+    assert_eq!("expected a number at 1:6", parse_program("init(x, 0); id").unwrap_err().to_string());
+}
@@ -0,0 +1,95 @@
+
+//! The float functions `core` lacks: those of `std` when it is available,
+//! and otherwise `libm`'s.
+
+#[cfg(feature = "std")]
+mod imp {
+    pub(crate) fn sin_cos(x: f64) -> (f64, f64) {
+        x.sin_cos()
+    }
+
+    pub(crate) fn atan2(y: f64, x: f64) -> f64 {
+        y.atan2(x)
+    }
+
+    pub(crate) fn hypot(x: f64, y: f64) -> f64 {
+        x.hypot(y)
+    }
+
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        x.sqrt()
+    }
+
+    pub(crate) fn floor(x: f64) -> f64 {
+        x.floor()
+    }
+
+    pub(crate) fn round(x: f64) -> f64 {
+        x.round()
+    }
+
+    pub(crate) fn powi(x: f64, n: i32) -> f64 {
+        x.powi(n)
+    }
+
+    pub(crate) fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+
+    pub(crate) fn rem_euclid(x: f64, modulus: f64) -> f64 {
+        x.rem_euclid(modulus)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    pub(crate) fn sin_cos(x: f64) -> (f64, f64) {
+        libm::sincos(x)
+    }
+
+    pub(crate) fn atan2(y: f64, x: f64) -> f64 {
+        libm::atan2(y, x)
+    }
+
+    pub(crate) fn hypot(x: f64, y: f64) -> f64 {
+        libm::hypot(x, y)
+    }
+
+    pub(crate) fn sqrt(x: f64) -> f64 {
+        libm::sqrt(x)
+    }
+
+    pub(crate) fn floor(x: f64) -> f64 {
+        libm::floor(x)
+    }
+
+    pub(crate) fn round(x: f64) -> f64 {
+        libm::round(x)
+    }
+
+    pub(crate) fn powi(x: f64, n: i32) -> f64 {
+        libm::pow(x, f64::from(n))
+    }
+
+    pub(crate) fn ln(x: f64) -> f64 {
+        libm::log(x)
+    }
+
+    /// As `f64::rem_euclid` does it.
+    pub(crate) fn rem_euclid(x: f64, modulus: f64) -> f64 {
+        let r = x % modulus;
+        if r < 0.0 { r + modulus.abs() } else { r }
+    }
+}
+
+pub(crate) use imp::*;
+
+#[test]
+fn test_float_functions() {
+    let (sin, cos) = sin_cos(core::f64::consts::FRAC_PI_6);
+    assert!((sin - 0.5).abs() < 1e-15 && (cos - 0.75f64.sqrt()).abs() < 1e-15);
+    assert!((atan2(1.0, -1.0) - 3.0 * core::f64::consts::FRAC_PI_4).abs() < 1e-15);
+    assert_eq!((5.0, 3.0, -2.0, -3.0, 1000.0), (hypot(3.0, 4.0), sqrt(9.0), floor(-1.5), round(-2.5), powi(10.0, 3)));
+    assert_eq!((1.5, 0.0), (rem_euclid(-0.5, 2.0), rem_euclid(4.0, 2.0)));
+    assert!((ln(core::f64::consts::E) - 1.0).abs() < 1e-15 && ln(1.0) == 0.0);
+}
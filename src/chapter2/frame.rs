@@ -0,0 +1,139 @@
+
+use alloc::{vec, vec::Vec};
+
+use super::float;
+use super::language::*;
+
+/// A point with a heading, the state `evaluate_frame` and `run_turtle`
+/// carry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub point: Point,
+    /// Radians counterclockwise from the x axis.
+    pub angle: f64,
+}
+
+impl Frame {
+    pub fn new(point: Point, angle: f64) -> Frame {
+        Frame { point, angle }
+    }
+}
+
+impl Expression {
+    /// Applies the transformation to a frame, like a turtle: `spin` turns
+    /// the heading, and `translation(u, v)` moves `u` along the heading and
+    /// `v` to its left. The other transformations act on the point as in
+    /// `evaluate` and turn the heading with it. Choices take the left branch
+    /// and an iteration without a count applies its body once.
+    ///
+    /// Rewrites such as `fold_constants` preserve `evaluate`, not this.
+    /// Panics on a `Reference`; see `Expression::resolve`.
+    pub fn evaluate_frame(&self, frame: Frame) -> Frame {
+        self.walk_frame(frame, false, &mut |_| {})
+    }
+
+    /// Evaluates like `evaluate_frame`, calling `on_leaf` with the frame
+    /// after every leaf. In `turtle` mode rotations only turn the heading,
+    /// their center ignored, as spins do.
+    fn walk_frame(&self, frame: Frame, turtle: bool, on_leaf: &mut dyn FnMut(Frame)) -> Frame {
+        let Frame { point, angle } = frame;
+        let frame = match self {
+            Expression::Chained(first, second) => {
+                let frame = first.walk_frame(frame, turtle, on_leaf);
+                return second.walk_frame(frame, turtle, on_leaf);
+            }
+            Expression::Sequence(statements) => {
+                return statements.iter().fold(frame, |frame, statement| statement.walk_frame(frame, turtle, on_leaf));
+            }
+            Expression::Reverse(body) => {
+                return body.chain_statements().into_iter().rev().fold(frame, |frame, statement| statement.walk_frame(frame, turtle, on_leaf));
+            }
+            Expression::EitherOr { left, .. } => return left.walk_frame(frame, turtle, on_leaf),
+            Expression::Iterate { count, body } => {
+                return (0..count.unwrap_or(1)).fold(frame, |frame, _| body.walk_frame(frame, turtle, on_leaf));
+            }
+            Expression::Reference(name) => panic!("unresolved reference `{}`", name),
+            Expression::Translation { u, v } => {
+                let (sin, cos) = float::sin_cos(angle);
+                Frame { point: point + Point::new(u * cos - v * sin, u * sin + v * cos), angle }
+            }
+            Expression::Spin { theta } => Frame { point, angle: angle + theta },
+            Expression::Rotation { theta, .. } if turtle => Frame { point, angle: angle + theta },
+            Expression::Rotation { theta, .. } => Frame { point: self.evaluate(point), angle: angle + theta },
+            leaf => {
+                // turn the heading by the linear part of the map, keeping it
+                // if the map collapses it
+                let m = leaf.to_affine().expect("leaves other than spins are affine").0;
+                let (sin, cos) = float::sin_cos(angle);
+                let (dx, dy) = (m[0][0] * cos + m[0][1] * sin, m[1][0] * cos + m[1][1] * sin);
+                let angle = if dx == 0.0 && dy == 0.0 { angle } else { float::atan2(dy, dx) };
+                Frame { point: self.evaluate(point), angle }
+            }
+        };
+        on_leaf(frame);
+        frame
+    }
+}
+
+impl Program {
+    /// Runs the body `steps` times as a turtle starting at `init`, heading
+    /// along the x axis: rotations and spins turn it, and `translation(d, 0)`
+    /// moves it `d` forward, with the other transformations applied as in
+    /// `evaluate_frame`. Returns `init` followed by the position after every
+    /// transformation that moved it, the corners of the path drawn.
+    ///
+    /// Panics on a `Reference`; see `Expression::resolve`.
+    pub fn run_turtle(&self, steps: usize) -> Vec<Point> {
+        let mut points = vec![self.init];
+        let mut on_leaf = |frame: Frame| {
+            if points.last() != Some(&frame.point) {
+                points.push(frame.point);
+            }
+        };
+        (0..steps).fold(Frame::new(self.init, 0.0), |frame, _| self.body.walk_frame(frame, true, &mut on_leaf));
+        points
+    }
+}
+
+#[test]
+fn test_spin_like_a_turtle() {
+    use std::f64::consts::FRAC_PI_2;
+
+    use super::parser::parse_expression;
+
+    let close = |a: Frame, b: Frame| (a.point.x - b.point.x).abs() < 1e-12 && (a.point.y - b.point.y).abs() < 1e-12 && (a.angle - b.angle).abs() < 1e-12;
+    let start = Frame::new(Point::new(0.0, 0.0), 0.0);
+
+    // a square walked counterclockwise comes back where it started
+    let square = parse_expression("iter[4](translation(1, 0); spin(90deg))").unwrap().0;
+    let end = square.evaluate_frame(start);
+    assert!(close(Frame::new(start.point, 4.0 * FRAC_PI_2), end), "{:?}", end);
+
+    let turn = parse_expression("translation(1, 0); spin(90deg); translation(2, 0.5)").unwrap().0;
+    assert!(close(Frame::new(Point::new(0.5, 2.0), FRAC_PI_2), turn.evaluate_frame(start)));
+    // without a frame the spin does nothing
+    assert_eq!(Point::new(3.0, 0.5), turn.evaluate(start.point));
+
+    let mirrored = parse_expression("spin(30deg); reflection(0, 0, 0); rotation(1, 0, 90deg)").unwrap().0;
+    let end = mirrored.evaluate_frame(Frame::new(Point::new(2.0, 0.0), 0.0));
+    assert!(close(Frame::new(Point::new(1.0, 1.0), (60.0f64).to_radians()), end), "{:?}", end);
+}
+
+#[test]
+fn test_run_turtle() {
+    use super::parser::program;
+
+    let close = |a: &[Point], b: &[Point]| a.len() == b.len() && a.iter().zip(b).all(|(p, q)| (p.x - q.x).abs() < 1e-12 && (p.y - q.y).abs() < 1e-12);
+
+    let (_, up) = program("init(0, 0); rotation(0,0,90deg); translation(1,0)").unwrap();
+    let points = up.run_turtle(1);
+    assert!(close(&[Point::new(0.0, 0.0), Point::new(0.0, 1.0)], &points), "{:?}", points);
+
+    // the center of a rotation doesn't matter to the turtle
+    let (_, square) = program("init(5, 5); translation(2, 0); rotation(100, -3, 90deg)").unwrap();
+    let points = square.run_turtle(4);
+    let expected_points = [Point::new(5.0, 5.0), Point::new(7.0, 5.0), Point::new(7.0, 7.0), Point::new(5.0, 7.0), Point::new(5.0, 5.0)];
+    assert!(close(&expected_points, &points), "{:?}", points);
+
+    assert_eq!(vec![square.init], square.run_turtle(0));
+}
@@ -1,19 +1,360 @@
 
-pub(crate) struct Point {
-    x: f64,
-    y: f64,
+use alloc::{boxed::Box, format, string::{String, ToString}, vec::Vec};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::{Add, Sub};
+
+use super::affine::Matrix3;
+use super::float;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Point {
+    pub(crate) x: f64,
+    pub(crate) y: f64,
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum Expression {
+impl Point {
+    pub fn new(x: f64, y: f64) -> Point {
+        Point { x, y }
+    }
+
+    pub fn x(&self) -> f64 {
+        self.x
+    }
+
+    pub fn y(&self) -> f64 {
+        self.y
+    }
+
+    /// The point with both coordinates rounded to `decimals` places.
+    pub fn round_to(&self, decimals: u32) -> Point {
+        Point { x: round_to(self.x, decimals), y: round_to(self.y, decimals) }
+    }
+}
+
+/// `n` rounded to `decimals` places, half away from zero, with `-0.0` made
+/// `0.0` so that rounded output is stable.
+pub(crate) fn round_to(n: f64, decimals: u32) -> f64 {
+    let scale = float::powi(10.0, decimals as i32);
+    float::round(n * scale) / scale + 0.0
+}
+
+/// Componentwise sum, for offsetting a point by a vector.
+impl Add for Point {
+    type Output = Point;
+
+    fn add(self, other: Point) -> Point {
+        Point { x: self.x + other.x, y: self.y + other.y }
+    }
+}
+
+/// Componentwise difference: the vector from `other` to `self`.
+impl Sub for Point {
+    type Output = Point;
+
+    fn sub(self, other: Point) -> Point {
+        Point { x: self.x - other.x, y: self.y - other.y }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
     Translation{u: f64, v: f64},
     Rotation{u: f64, v: f64, theta: f64},
+    Scale{u: f64, v: f64, sx: f64, sy: f64},
+    Reflection{u: f64, v: f64, theta: f64},
     Chained(Box<Expression>, Box<Expression>),
-    EitherOr{left: Box<Expression>, right: Box<Expression>},
-    Iterate(Box<Expression>),
+    /// The statements applied in order, a flat alternative to nested
+    /// `Chained`s produced by `flatten_chains`. An empty sequence leaves the
+    /// point where it is.
+    Sequence(Vec<Expression>),
+    /// A choice between `left` and `right`. The chaos game takes each
+    /// branch with probability proportional to its weight, 0.5 for both
+    /// unless written as `{ ... }:0.7 or { ... }:0.3`.
+    EitherOr{left: Box<Expression>, right: Box<Expression>, weights: (f64, f64)},
+    Iterate{count: Option<usize>, body: Box<Expression>},
+    /// The statements of the body's chain applied last to first; the
+    /// statements themselves are not reversed.
+    Reverse(Box<Expression>),
+    /// Leaves the point where it is.
+    Identity,
+    /// Turns the heading of a `Frame` by `theta` radians; see
+    /// `Expression::evaluate_frame`. The point stays where it is.
+    Spin{theta: f64},
+    /// An arbitrary affine map, as produced by `fold_constants`.
+    Affine(Matrix3),
+    /// A use of a named definition, which must be resolved before the
+    /// expression is evaluated.
+    Reference(String),
+}
+
+/// Prints the expression in the syntax accepted by the parser. Chains are
+/// printed flat, so left-nested chains read back right-nested.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Translation { u, v } => write!(f, "translation({}, {})", u, v),
+            Expression::Rotation { u, v, theta } => write!(f, "rotation({}, {}, {})", u, v, theta),
+            Expression::Scale { u, v, sx, sy } => write!(f, "scale({}, {}, {}, {})", u, v, sx, sy),
+            Expression::Reflection { u, v, theta } => write!(f, "reflection({}, {}, {})", u, v, theta),
+            Expression::Chained(first, second) => write!(f, "{}; {}", first, second),
+            Expression::Sequence(statements) if statements.is_empty() => write!(f, "id"),
+            Expression::Sequence(statements) => {
+                let statements: Vec<String> = statements.iter().map(Expression::to_string).collect();
+                write!(f, "{}", statements.join("; "))
+            }
+            Expression::EitherOr { left, right, weights: (0.5, 0.5) } => write!(f, "{{ {} }} or {{ {} }}", left, right),
+            Expression::EitherOr { left, right, weights: (w1, w2) } => write!(f, "{{ {} }}:{} or {{ {} }}:{}", left, w1, right, w2),
+            Expression::Iterate { count: None, body } => write!(f, "iter({})", body),
+            Expression::Iterate { count: Some(n), body } => write!(f, "iter[{}]({})", n, body),
+            Expression::Reverse(body) => write!(f, "rev({})", body),
+            Expression::Identity => write!(f, "id"),
+            Expression::Spin { theta } => write!(f, "spin({})", theta),
+            Expression::Affine(Matrix3([[a, b, c], [d, e, g], _])) => write!(f, "affine({}, {}, {}, {}, {}, {})", a, b, c, d, e, g),
+            Expression::Reference(name) => write!(f, "{}", name),
+        }
+    }
 }
 
-pub(crate) struct Program {
-    init: Point,
-    body: Expression,
+impl Expression {
+    /// Prints the expression like `Display`, but with each statement on its
+    /// own line and the bodies of `iter(...)`, `rev(...)` and `{ ... }`
+    /// indented by `indent` spaces per level.
+    pub fn pretty(&self, indent: usize) -> String {
+        let mut out = String::new();
+        self.write_pretty(&mut out, indent, 0);
+        out
+    }
+
+    fn write_pretty(&self, out: &mut String, indent: usize, level: usize) {
+        let newline = |out: &mut String, level: usize| {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent * level));
+        };
+        match self {
+            Expression::Chained(first, second) => {
+                first.write_pretty(out, indent, level);
+                out.push(';');
+                newline(out, level);
+                second.write_pretty(out, indent, level);
+            }
+            Expression::Sequence(statements) if !statements.is_empty() => {
+                for (i, statement) in statements.iter().enumerate() {
+                    if i > 0 {
+                        out.push(';');
+                        newline(out, level);
+                    }
+                    statement.write_pretty(out, indent, level);
+                }
+            }
+            Expression::EitherOr { left, right, weights } => {
+                let weight = |w: f64| if *weights == (0.5, 0.5) { String::new() } else { format!(":{}", w) };
+                out.push('{');
+                newline(out, level + 1);
+                left.write_pretty(out, indent, level + 1);
+                newline(out, level);
+                out.push_str(&format!("}}{} or {{", weight(weights.0)));
+                newline(out, level + 1);
+                right.write_pretty(out, indent, level + 1);
+                newline(out, level);
+                out.push('}');
+                out.push_str(&weight(weights.1));
+            }
+            Expression::Iterate { body, .. } | Expression::Reverse(body) => {
+                match self {
+                    Expression::Iterate { count: Some(n), .. } => out.push_str(&format!("iter[{}](", n)),
+                    Expression::Iterate { count: None, .. } => out.push_str("iter("),
+                    _ => out.push_str("rev("),
+                }
+                newline(out, level + 1);
+                body.write_pretty(out, indent, level + 1);
+                newline(out, level);
+                out.push(')');
+            }
+            leaf => out.push_str(&leaf.to_string()),
+        }
+    }
+}
+
+/// Hashes numbers by their bits, with `-0.0` hashing as `0.0` so that equal
+/// expressions hash equally.
+impl Hash for Expression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let hash_numbers = |numbers: &[f64], state: &mut H| {
+            for n in numbers {
+                (n + 0.0).to_bits().hash(state);
+            }
+        };
+        core::mem::discriminant(self).hash(state);
+        match self {
+            Expression::Translation { u, v } => hash_numbers(&[*u, *v], state),
+            Expression::Rotation { u, v, theta } | Expression::Reflection { u, v, theta } => hash_numbers(&[*u, *v, *theta], state),
+            Expression::Scale { u, v, sx, sy } => hash_numbers(&[*u, *v, *sx, *sy], state),
+            Expression::Chained(first, second) => {
+                first.hash(state);
+                second.hash(state);
+            }
+            Expression::EitherOr { left, right, weights } => {
+                left.hash(state);
+                right.hash(state);
+                hash_numbers(&[weights.0, weights.1], state);
+            }
+            Expression::Sequence(statements) => statements.hash(state),
+            Expression::Reverse(body) => body.hash(state),
+            Expression::Iterate { count, body } => {
+                count.hash(state);
+                body.hash(state);
+            }
+            Expression::Identity => {}
+            Expression::Spin { theta } => hash_numbers(&[*theta], state),
+            Expression::Affine(m) => hash_numbers(m.0.as_flattened(), state),
+            Expression::Reference(name) => name.hash(state),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub(crate) init: Point,
+    pub(crate) body: Expression,
+}
+
+impl Program {
+    pub fn evaluate(&self) -> Point {
+        self.body.evaluate(self.init)
+    }
+
+    /// The point the body starts from.
+    pub fn init(&self) -> Point {
+        self.init
+    }
+
+    /// The program running the same body from `p` instead.
+    pub fn with_init(self, p: Point) -> Program {
+        Program { init: p, ..self }
+    }
+}
+
+#[test]
+fn test_point_arithmetic() {
+    let p = Point::new(1.5, -2.0);
+    assert_eq!((1.5, -2.0), (p.x(), p.y()));
+    assert_eq!(Point::new(2.0, 1.0), p + Point::new(0.5, 3.0));
+    assert_eq!(Point::new(1.0, -5.0), p - Point::new(0.5, 3.0));
+    assert_eq!(p, p + Point::new(0.5, 3.0) - Point::new(0.5, 3.0));
+}
+
+#[test]
+fn test_round_to() {
+    assert_eq!(Point::new(0.123, -2.0), Point::new(0.123456, -1.9996).round_to(3));
+    let rounded = Point::new(-0.0001, 2.5).round_to(0);
+    assert_eq!(Point::new(0.0, 3.0), rounded);
+    assert!(rounded.x.is_sign_positive());
+}
+
+#[test]
+fn test_display_round_trip() {
+    use super::parser::expression;
+
+    let translation = || Box::new(Expression::Translation { u: 12.0, v: -0.4 });
+    let rotation = || Box::new(Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 });
+    let expressions = vec![
+        *translation(),
+        *rotation(),
+        Expression::Scale { u: 1.0, v: -2.0, sx: 0.5, sy: 3.0 },
+        Expression::Reflection { u: 0.0, v: 1.0, theta: 0.25 },
+        Expression::Chained(translation(), rotation()),
+        Expression::Iterate { count: None, body: Box::new(Expression::Chained(translation(), rotation())) },
+        Expression::Chained(
+            Box::new(Expression::Iterate { count: Some(7), body: rotation() }),
+            Box::new(Expression::Chained(translation(), translation())),
+        ),
+        Expression::EitherOr { left: translation(), right: rotation(), weights: (0.5, 0.5) },
+        Expression::EitherOr { left: translation(), right: rotation(), weights: (0.7, 0.3) },
+        Expression::Chained(Box::new(Expression::Identity), translation()),
+        Expression::Chained(Box::new(Expression::Spin { theta: -0.75 }), translation()),
+        Expression::Affine(Matrix3([[0.5, -1.0, 2.0], [0.25, 4.0, -3.5], [0.0, 0.0, 1.0]])),
+        Expression::Chained(
+            Box::new(Expression::EitherOr {
+                left: Box::new(Expression::Iterate { count: None, body: translation() }),
+                right: Box::new(Expression::Chained(rotation(), translation())),
+                weights: (0.5, 0.5),
+            }),
+            Box::new(Expression::Iterate {
+                count: Some(2),
+                body: Box::new(Expression::EitherOr { left: rotation(), right: translation(), weights: (2.0, 1.0) }),
+            }),
+        ),
+    ];
+    for expr in expressions {
+        let text = expr.to_string();
+        let (remaining_text, parsed_expression) = expression(&text).unwrap();
+        assert!(remaining_text.is_empty(), "trailing input after {}", text);
+        assert_eq!(expr, parsed_expression);
+    }
+}
+
+#[test]
+fn test_pretty() {
+    use super::parser::{expression, parse_expression};
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    let expected = r"iter(
+  translation(12, 0.4);
+  rotation(0.2, 0.3, 0.5)
+);
+translation(8, 15)";
+    assert_eq!(expected, expr.pretty(2));
+
+    let expr = parse_expression("{ iter[3](translation(1, 0)) } or { id }; scale(0, 0, 2, 2)").unwrap().0;
+    let expected = r"{
+    iter[3](
+        translation(1, 0)
+    )
+} or {
+    id
+};
+scale(0, 0, 2, 2)";
+    let pretty = expr.pretty(4);
+    assert_eq!(expected, pretty);
+    assert_eq!(expr, expression(&pretty).unwrap().1);
+}
+
+#[test]
+fn test_structural_hash() {
+    use std::collections::hash_map::DefaultHasher;
+
+    use super::parser::parse_expression;
+
+    let hash = |expr: &Expression| {
+        let mut hasher = DefaultHasher::new();
+        expr.hash(&mut hasher);
+        hasher.finish()
+    };
+    let raw_expression = "iter[3]({ translation(1, -0.5) } or { rotation(0, 0, 1); scale(0, 0, 2, 2) }); id";
+    let expr = parse_expression(raw_expression).unwrap().0;
+    let copy = expr.clone();
+    assert_eq!(hash(&parse_expression(raw_expression).unwrap().0), hash(&expr));
+    assert_eq!(hash(&copy), hash(&expr));
+    assert_eq!(hash(&Expression::Translation { u: 0.0, v: 1.0 }), hash(&Expression::Translation { u: -0.0, v: 1.0 }));
+
+    assert_ne!(hash(&Expression::Translation { u: 1.0, v: 0.0 }), hash(&Expression::Translation { u: 0.0, v: 1.0 }));
+    let rotation = Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 };
+    assert_ne!(hash(&rotation), hash(&Expression::Reflection { u: 0.0, v: 0.0, theta: 1.0 }));
+}
+
+#[test]
+fn test_with_init() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(1, 1); iter[2](translation(1, 0); rotation(0, 0, 90deg))").unwrap();
+    let (_, expected_walk) = program("init(1, 1); iter[2](translation(1, 0); rotation(0, 0, 90deg))").unwrap();
+    assert_eq!(Point::new(1.0, 1.0), walk.init());
+    let moved = walk.with_init(Point::new(-2.0, 0.5));
+    assert_eq!(Point::new(-2.0, 0.5), moved.init());
+    assert_eq!(expected_walk.body, moved.body);
+    assert_eq!(moved.body.evaluate(Point::new(-2.0, 0.5)), moved.evaluate());
+    assert_eq!(Point::new(-2.0, 0.5), moved.trajectory(1).unwrap()[0]);
 }
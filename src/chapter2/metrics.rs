@@ -0,0 +1,310 @@
+
+use alloc::{collections::BTreeSet, vec, vec::Vec};
+use core::f64::consts::TAU;
+
+use super::chaos::Rng;
+use super::error::EvalError;
+use super::float;
+use super::language::*;
+
+impl Program {
+    /// The minimum and maximum corners of the points visited by `steps` runs
+    /// of the body; both are `init` when no steps are taken.
+    pub fn bounding_box(&self, steps: usize) -> Result<(Point, Point), EvalError> {
+        Ok(bounds(&self.trajectory(steps)?).unwrap_or((self.init, self.init)))
+    }
+
+    /// The mean of the points visited by `steps` runs of the body, `init`
+    /// included as in `trajectory`; just `init` when no steps are taken. The
+    /// points are summed as they are visited rather than collected.
+    pub fn centroid(&self, steps: usize) -> Point {
+        let (mut sum, mut count) = (self.init, 1.0);
+        (0..steps).fold(self.init, |start, _| {
+            self.body.walk_steps(start, &mut |p| {
+                sum = sum + p;
+                count += 1.0;
+            })
+        });
+        Point { x: sum.x / count, y: sum.y / count }
+    }
+
+    /// The number of turns the heading of `steps` runs of the body makes:
+    /// the signed angles between consecutive segments of the trajectory,
+    /// counterclockwise positive, summed and divided by 2π. When the
+    /// trajectory ends back at `init` the turn from its last segment into
+    /// its first is counted too, so a closed loop gives a whole number.
+    /// Points repeating the one before add no segment, and a segment
+    /// doubling back along the one before turns by no angle, as its
+    /// direction of turning is undefined.
+    pub fn winding_number(&self, steps: usize) -> f64 {
+        // segments are kept as the offsets between consecutive points
+        let (mut first, mut previous): (Option<Point>, Option<Point>) = (None, None);
+        let (mut at, mut total) = (self.init, 0.0);
+        let end = (0..steps).fold(self.init, |start, _| {
+            self.body.walk_steps(start, &mut |p| {
+                let segment = p - at;
+                if segment.x == 0.0 && segment.y == 0.0 {
+                    return;
+                }
+                if let Some(before) = previous {
+                    total += turning_angle(before, segment);
+                }
+                first.get_or_insert(segment);
+                (previous, at) = (Some(segment), p);
+            })
+        });
+        if let (Some(first), Some(last)) = (first, previous) {
+            if float::hypot((end - self.init).x, (end - self.init).y) <= 1e-9 {
+                total += turning_angle(last, first);
+            }
+        }
+        total / TAU
+    }
+
+    /// The center and radius of the smallest circle containing the points
+    /// visited by `steps` runs of the body, `init` included as in
+    /// `trajectory`: a circle of radius zero around `init` when no steps are
+    /// taken. The points are walked rather than collected through
+    /// `trajectory`, so they aren't limited by `EvalConfig`.
+    pub fn bounding_circle(&self, steps: usize) -> (Point, f64) {
+        let mut points = vec![self.init];
+        (0..steps).fold(self.init, |start, _| {
+            self.body.walk_steps(start, &mut |p| points.push(p))
+        });
+        let (center, radius_squared) = enclosing_circle(&mut points);
+        (center, float::sqrt(radius_squared))
+    }
+
+    /// The grid cells, `cell` wide and high, containing the points visited
+    /// by `steps` runs of the body, each listed once in the order first
+    /// visited. Cell `(i, j)` spans `[i * cell, (i + 1) * cell)` across and
+    /// likewise up.
+    pub fn to_grid(&self, steps: usize, cell: f64) -> Result<Vec<(i64, i64)>, EvalError> {
+        let mut seen = BTreeSet::new();
+        let cells = self.trajectory(steps)?.into_iter()
+            .map(|p| (float::floor(p.x / cell) as i64, float::floor(p.y / cell) as i64))
+            .filter(|cell| seen.insert(*cell))
+            .collect();
+        Ok(cells)
+    }
+
+    /// The sum of the absolute angles of every rotation and spin in one run
+    /// of the body. Both sides of a choice count, and an iteration counts its
+    /// body once per repetition.
+    pub fn total_rotation(&self) -> f64 {
+        self.body.total(&|leaf| match leaf {
+            Expression::Rotation { theta, .. } | Expression::Spin { theta } => theta.abs(),
+            _ => 0.0,
+        })
+    }
+
+    /// The sum of the lengths of every translation in one run of the body,
+    /// counted as `total_rotation` counts rotations.
+    pub fn total_translation_distance(&self) -> f64 {
+        self.body.total(&|leaf| match leaf {
+            Expression::Translation { u, v } => float::hypot(*u, *v),
+            _ => 0.0,
+        })
+    }
+}
+
+impl Expression {
+    /// The sum of `measure` over the leaves, with iteration bodies weighted
+    /// by their count.
+    fn total<F: Fn(&Expression) -> f64>(&self, measure: &F) -> f64 {
+        match self {
+            Expression::Chained(first, second) | Expression::EitherOr { left: first, right: second, .. } => {
+                first.total(measure) + second.total(measure)
+            }
+            Expression::Sequence(statements) => statements.iter().map(|statement| statement.total(measure)).sum(),
+            Expression::Iterate { count, body } => count.unwrap_or(1) as f64 * body.total(measure),
+            Expression::Reverse(body) => body.total(measure),
+            leaf => measure(leaf),
+        }
+    }
+}
+
+/// The minimum and maximum corners of `points`, if there are any.
+pub(crate) fn bounds(points: &[Point]) -> Option<(Point, Point)> {
+    let first = *points.first()?;
+    Some(points.iter().fold((first, first), |(min, max), p| {
+        (Point { x: min.x.min(p.x), y: min.y.min(p.y) }, Point { x: max.x.max(p.x), y: max.y.max(p.y) })
+    }))
+}
+
+/// The smallest circle containing `points`, which must not be empty, as
+/// its center and squared radius. This is Welzl's algorithm with its
+/// recursion unrolled into loops: each point found outside the circle of
+/// those before it lies on the boundary of their circle with it. The points
+/// are shuffled first, with a fixed seed, for the expected linear time.
+fn enclosing_circle(points: &mut [Point]) -> (Point, f64) {
+    let mut rng = Rng::new(0x5eed);
+    for i in (1..points.len()).rev() {
+        points.swap(i, (rng.next_u64() % (i as u64 + 1)) as usize);
+    }
+    let outside = |(center, radius_squared): (Point, f64), p: Point| squared_distance(center, p) > radius_squared * (1.0 + 1e-12) + 1e-18;
+    let mut circle = (points[0], 0.0);
+    for i in 1..points.len() {
+        if !outside(circle, points[i]) {
+            continue;
+        }
+        circle = (points[i], 0.0);
+        for j in 0..i {
+            if !outside(circle, points[j]) {
+                continue;
+            }
+            circle = diametral_circle(points[i], points[j]);
+            for k in 0..j {
+                if outside(circle, points[k]) {
+                    circle = circle_through(points[i], points[j], points[k]);
+                }
+            }
+        }
+    }
+    circle
+}
+
+fn squared_distance(a: Point, b: Point) -> f64 {
+    let d = b - a;
+    d.x * d.x + d.y * d.y
+}
+
+/// The circle with `a` and `b` at opposite ends of a diameter.
+fn diametral_circle(a: Point, b: Point) -> (Point, f64) {
+    let center = Point { x: (a.x + b.x) / 2.0, y: (a.y + b.y) / 2.0 };
+    (center, squared_distance(center, a))
+}
+
+/// The circle through `a`, `b` and `c`, or the smallest containing them
+/// when they are collinear.
+fn circle_through(a: Point, b: Point, c: Point) -> (Point, f64) {
+    let (ab, ac) = (b - a, c - a);
+    let cross = ab.x * ac.y - ab.y * ac.x;
+    let scale = squared_distance(a, b).max(squared_distance(a, c));
+    if cross.abs() <= 1e-12 * scale {
+        // the two points farthest apart span the circle
+        return [diametral_circle(a, b), diametral_circle(a, c), diametral_circle(b, c)]
+            .into_iter()
+            .fold((a, f64::NEG_INFINITY), |widest, circle| if circle.1 > widest.1 { circle } else { widest });
+    }
+    let (ab_squared, ac_squared) = (ab.x * ab.x + ab.y * ab.y, ac.x * ac.x + ac.y * ac.y);
+    let offset = Point {
+        x: (ac.y * ab_squared - ab.y * ac_squared) / (2.0 * cross),
+        y: (ab.x * ac_squared - ac.x * ab_squared) / (2.0 * cross),
+    };
+    (a + offset, offset.x * offset.x + offset.y * offset.y)
+}
+
+/// The signed angle from the direction of `a` to that of `b`, in `[-π, π]`,
+/// or zero if `b` points straight back along `a`.
+fn turning_angle(a: Point, b: Point) -> f64 {
+    let (cross, dot) = (a.x * b.y - a.y * b.x, a.x * b.x + a.y * b.y);
+    if dot < 0.0 && cross.abs() <= 1e-12 * float::hypot(a.x, a.y) * float::hypot(b.x, b.y) {
+        0.0
+    } else {
+        float::atan2(cross, dot)
+    }
+}
+
+#[test]
+fn test_bounding_box() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(1, -1); translation(2, 0); translation(0, 1); translation(-3, 0)").unwrap();
+    let (min, max) = walk.bounding_box(2).unwrap();
+    assert_eq!((Point { x: -1.0, y: -1.0 }, Point { x: 1.0, y: 1.0 }), (min, max));
+
+    let (_, walk) = program("init(1, -1); iter[3](translation(2, 0)); translation(-7, 5)").unwrap();
+    assert_eq!((Point { x: 0.0, y: -1.0 }, Point { x: 7.0, y: 4.0 }), walk.bounding_box(1).unwrap());
+    assert_eq!((walk.init, walk.init), walk.bounding_box(0).unwrap());
+}
+
+#[test]
+fn test_totals() {
+    use super::parser::program;
+
+    let (_, turns) = program("init(0, 0); iter[3](rotation(0,0,0.5))").unwrap();
+    assert!((turns.total_rotation() - 1.5).abs() < 1e-12);
+    assert_eq!(0.0, turns.total_translation_distance());
+
+    let (_, walk) = program("init(0, 0); translation(3, 4); iter[2]({ translation(0, -1) } or { rotation(1, 1, -0.25); reflection(0, 0, 1) })").unwrap();
+    assert_eq!(7.0, walk.total_translation_distance());
+    assert_eq!(0.5, walk.total_rotation());
+}
+
+#[test]
+fn test_to_grid() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(0.5, 0.5); translation(1, 0); translation(0, 1); translation(-1, 0); translation(0, -1); translation(0.25, -1)").unwrap();
+    assert_eq!(vec![(0, 0), (0, -1)], walk.to_grid(1, 1.0).unwrap());
+
+    let (_, walk) = program("init(0.5, 0.5); iter[2](translation(1, 0)); iter[2](translation(0, -1.5)); translation(-2.5, 0)").unwrap();
+    assert_eq!(vec![(0, 0), (1, 0), (2, 0), (2, -1), (2, -3), (0, -3)], walk.to_grid(1, 1.0).unwrap());
+    assert_eq!(vec![(0, 0), (1, 0), (1, -1), (1, -2), (0, -2)], walk.to_grid(1, 2.0).unwrap());
+}
+
+#[test]
+fn test_centroid() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(-2, 1); iter[2](translation(1, 0.5))").unwrap();
+    assert_eq!(Point { x: 0.0, y: 2.0 }, walk.centroid(2));
+    assert_eq!(walk.init, walk.centroid(0));
+
+    // the midpoint of a straight walk
+    let (_, straight) = program("init(3, 3); translation(1, -1)").unwrap();
+    assert_eq!(Point { x: 5.0, y: 1.0 }, straight.centroid(4));
+}
+
+#[test]
+fn test_winding_number() {
+    use super::parser::program;
+
+    // the corners of a unit square, counterclockwise
+    let (_, square) = program("init(0, 0); translation(1, 0); rotation(0, 0, 90deg)").unwrap();
+    assert!((square.winding_number(4) - 1.0).abs() < 1e-9, "{}", square.winding_number(4));
+    assert!((square.winding_number(8) - 2.0).abs() < 1e-9);
+    // open: the quarter turns between three sides, without the one closing the loop
+    assert!((square.winding_number(3) - 0.5).abs() < 1e-9, "{}", square.winding_number(3));
+    let (_, clockwise) = program("init(0, 0); translation(1, 0); rotation(0, 0, -90deg)").unwrap();
+    assert!((clockwise.winding_number(4) + 1.0).abs() < 1e-9);
+
+    // straight, repeated and doubling-back points don't turn
+    let (_, straight) = program("init(0, 0); translation(1, 1)").unwrap();
+    assert_eq!(0.0, straight.winding_number(5));
+    let (_, still) = program("init(2, 2); id").unwrap();
+    assert_eq!(0.0, still.winding_number(3));
+    let (_, back_and_forth) = program("init(0, 0); scale(0.5, 0, -1, 1)").unwrap();
+    assert_eq!(0.0, back_and_forth.winding_number(4));
+}
+
+#[test]
+fn test_bounding_circle() {
+    use super::parser::program;
+
+    // an acute triangle, whose circle is the circumcircle: centered at
+    // (2, 1), through all three corners
+    let (_, triangle) = program("init(0, 0); iter[1](translation(4, 0)); translation(-3, 3)").unwrap();
+    assert_eq!(3, triangle.trajectory(1).unwrap().len());
+    let (center, radius) = triangle.bounding_circle(1);
+    assert!((center.x - 2.0).abs() < 1e-12 && (center.y - 1.0).abs() < 1e-12, "{:?}", center);
+    assert!((radius - 5f64.sqrt()).abs() < 1e-12, "{}", radius);
+
+    assert_eq!((Point::new(1.0, 2.0), 0.0), program("init(1, 2); translation(3, 0)").unwrap().1.bounding_circle(0));
+    let (_, segment) = program("init(1, 2); translation(3, 4)").unwrap();
+    assert_eq!((Point::new(2.5, 4.0), 2.5), segment.bounding_circle(1));
+    // collinear points are spanned by the two farthest apart
+    assert_eq!((Point::new(4.0, 2.0), 3.0), program("init(1, 2); translation(1.5, 0)").unwrap().1.bounding_circle(4));
+
+    // an obtuse corner lies inside the circle on the longest side
+    let (_, obtuse) = program("init(0, 0); iter[1](translation(4, 0)); translation(-2, 1)").unwrap();
+    assert_eq!((Point::new(2.0, 0.0), 2.0), obtuse.bounding_circle(1));
+
+    let (_, spiral) = program("init(1, 0); rotation(0, 0, 0.7); scale(0, 0, 1.05, 1.05)").unwrap();
+    let (center, radius) = spiral.bounding_circle(40);
+    let points = spiral.trajectory(40).unwrap();
+    assert!(points.iter().all(|p| float::hypot(p.x - center.x, p.y - center.y) <= radius * (1.0 + 1e-9)));
+    // no smaller circle around the same center would do
+    assert!(points.iter().any(|p| float::hypot(p.x - center.x, p.y - center.y) >= radius * (1.0 - 1e-9)));
+}
@@ -1,2 +1,22 @@
-mod language;
-mod parser;
\ No newline at end of file
+
+pub mod affine;
+pub mod analysis;
+mod binary;
+pub mod build;
+pub mod chaos;
+pub mod definitions;
+mod dot;
+pub mod error;
+pub mod eval;
+mod float;
+pub mod frame;
+pub mod language;
+mod metrics;
+pub mod parser;
+mod rewrite;
+#[cfg(feature = "serde")]
+mod serialization;
+mod svg;
+pub mod three_d;
+pub mod timed;
+mod traverse;
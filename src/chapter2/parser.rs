@@ -1,121 +1,1245 @@
 
+use alloc::{boxed::Box, format, string::{String, ToString}, vec, vec::Vec};
+use core::cell::RefCell;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::io::Read;
+
 use nom::{
     IResult,
-    bytes::complete::tag,
+    bytes::complete::{tag, take_while},
     branch::alt,
-    character::complete::{char, multispace0},
-    Err,
+    character::complete::{char, digit1, hex_digit1, multispace0, one_of, satisfy},
+    combinator::{cut, opt, recognize},
+    error::{ErrorKind, FromExternalError},
     number::complete::double,
-    sequence::{Tuple, delimited},
+    sequence::{pair, Tuple},
 };
 
+use super::affine::Matrix3;
+use super::definitions::Definitions;
+use super::error::{ParseError, ParseErrorKind, ResolveError, Warning};
 use super::language::*;
+use super::rewrite::chain;
+
+/// Options controlling what the parser accepts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParserConfig {
+    /// Deepest nesting of `iter(...)` and `rev(...)` bodies and `{ ... }`
+    /// alternatives accepted, keeping the recursive descent within the
+    /// stack. Unoptimized builds take several kilobytes of stack per level,
+    /// so the default of 128 is what still leaves room to spare on a 2 MiB
+    /// thread stack, the default for spawned threads.
+    pub max_depth: usize,
+    /// The character between the numbers of a transformation, `,` by
+    /// default. It must not be `)`, `-`, `+`, `.`, a digit or a letter.
+    pub separator: char,
+    /// Whether numbers may also be written as `0x` hexadecimal integers or
+    /// `a/b` rationals of decimal integers. The separator must then not be
+    /// `/`.
+    pub extended_numbers: bool,
+    /// Whether a line break between two statements separates them as a `;`
+    /// would, so that statements on lines of their own need no `;`. A
+    /// statement still continues onto the next line while it is
+    /// incomplete, as within parentheses or before the `or` of a choice.
+    pub newline_as_separator: bool,
+    /// Whether a `;` left out before a statement that starts with a name or
+    /// a `{` is only warned about, as the statements are unambiguous
+    /// without it. Otherwise it is an error.
+    pub recover_missing_separator: bool,
+}
+
+impl Default for ParserConfig {
+    fn default() -> ParserConfig {
+        ParserConfig { max_depth: 128, separator: ',', extended_numbers: false, newline_as_separator: false, recover_missing_separator: false }
+    }
+}
+
+/// A comment in parsed input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trivia {
+    /// Byte offset of the comment's `#` or `/*` into the input.
+    pub offset: usize,
+    /// The comment as written, delimiters included; a line comment doesn't
+    /// include its newline.
+    pub text: String,
+}
+
+/// What the parsers share while reading one input: the options in force and
+/// the warnings collected so far.
+struct ParseState<'a> {
+    config: &'a ParserConfig,
+    /// The whole input, which warnings are located in.
+    input: &'a str,
+    warnings: RefCell<Vec<Warning>>,
+}
+
+impl<'a> ParseState<'a> {
+    fn new(config: &'a ParserConfig, input: &'a str) -> ParseState<'a> {
+        ParseState { config, input, warnings: RefCell::new(Vec::new()) }
+    }
+
+    /// Records a warning at `tail`, a suffix of the input.
+    fn warn(&self, tail: &str, message: &str) {
+        let offset = ParseError::at(self.input, tail, String::new()).offset;
+        self.warnings.borrow_mut().push(Warning { offset, message: message.to_string() });
+    }
+}
+
+const EXPECTED_STATEMENT: &str = "expected `translation`, `rotation`, `scale`, `reflection`, `affine`, `spin`, `id`, `iter`, `rev`, `{`, or a name";
+
+/// Words that cannot be used as names.
+const KEYWORDS: [&str; 14] = ["translation", "rotation", "scale", "reflection", "affine", "spin", "id", "identity", "iter", "repeat", "rev", "or", "let", "init"];
+
+/// The grammar `parse_program` and `parse_expression` implement, in ISO
+/// EBNF. `program` and `statements` are the two entry points. Whitespace
+/// and comments may appear between any two tokens.
+pub const GRAMMAR: &str = r##"program = "init" , "(" , number , separator , number , ")" , ";" , statements ;
+statements = { ";" } , item , { ";" , { ";" } , item } , { ";" } ;
+item = definition | dotted ;
+definition = "let" , name , "=" , dotted ;
+dotted = statement , { "." , statement } ;
+statement = "translation" , "(" , number , separator , number , ")"
+    | "rotation" , "(" , number , separator , number , separator , angle , ")"
+    | "scale" , "(" , number , separator , number , separator , number , separator , number , ")"
+    | "reflection" , "(" , number , separator , number , separator , angle , ")"
+    | "affine" , "(" , number , 5 * ( separator , number ) , ")"
+    | "spin" , "(" , angle , ")"
+    | "id" | "identity"
+    | ( "iter" | "repeat" ) , [ "[" , count , "]" ] , "(" , statements , ")"
+    | "rev" , "(" , statements , ")"
+    | "{" , statements , "}" , [ weight ] , "or" , "{" , statements , "}" , [ weight ]
+    | name ;
+weight = ":" , number ;
+angle = number , [ "deg" | "rad" ] ;
+separator = "," (* or ParserConfig::separator *) ;
+number = [ "+" | "-" ] , ( digits , [ "." , [ digits ] ] | "." , digits ) , [ ( "e" | "E" ) , [ "+" | "-" ] , digits ]
+    (* with ParserConfig::extended_numbers, also: *)
+    | [ "+" | "-" ] , ( "0x" | "0X" ) , hex digit , { hex digit }
+    | [ "+" | "-" ] , digits , "/" , digits ;
+count = digits ;
+digits = digit , { digit } ;
+name = ( letter | "_" ) , { letter | digit | "_" } (* ASCII, and not a keyword *) ;
+comment = "#" , { character - newline } | "/*" , { character } , "*/" ;
+"##;
+
+/// Error type threaded through the nom parsers: the remaining input where
+/// parsing stopped and a description of what was expected there.
+#[derive(Debug, PartialEq)]
+pub struct SyntaxError<'a> {
+    input: &'a str,
+    message: String,
+    /// The delimiter left open and the input starting at it, when parsing
+    /// stopped because a group was never closed.
+    unclosed: Option<(char, &'a str)>,
+    kind: ParseErrorKind,
+}
+
+impl<'a> SyntaxError<'a> {
+    pub(crate) fn new(input: &'a str, message: String) -> SyntaxError<'a> {
+        SyntaxError { input, message, unclosed: None, kind: ParseErrorKind::Syntax }
+    }
+
+    fn into_parse_error(self, original: &str) -> ParseError {
+        match self.unclosed {
+            Some((open, opened)) => {
+                let opened_at = ParseError::at(original, opened, String::new()).offset;
+                ParseError::at(original, self.input, format!("unclosed `{}` opened at offset {}", open, opened_at))
+            }
+            None => ParseError { kind: self.kind, ..ParseError::at(original, self.input, self.message) },
+        }
+    }
+}
 
-fn comma_separator(text: &str) -> IResult<&str, ()> {
-    let (text, _) = (multispace0, char(','), multispace0).parse(text)?;
+impl<'a> nom::error::ParseError<&'a str> for SyntaxError<'a> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        let message = match kind {
+            ErrorKind::Float => "expected a number".to_string(),
+            ErrorKind::Digit => "expected a count".to_string(),
+            ErrorKind::Tag => "expected a keyword".to_string(),
+            _ => kind.description().to_string(),
+        };
+        SyntaxError::new(input, message)
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(input: &'a str, c: char) -> Self {
+        SyntaxError::new(input, format!("expected `{}`", c))
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for SyntaxError<'a> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _: E) -> Self {
+        nom::error::ParseError::from_error_kind(input, kind)
+    }
+}
+
+/// Replaces the message of a recoverable error from `parser`, leaving
+/// committed failures untouched.
+fn expecting<'a, O>(message: &'static str, mut parser: impl FnMut(&'a str) -> IResult<&'a str, O, SyntaxError<'a>>)
+    -> impl FnMut(&'a str) -> IResult<&'a str, O, SyntaxError<'a>> {
+    move |text| parser(text).map_err(|error| match error {
+        nom::Err::Error(error) => nom::Err::Error(SyntaxError { message: message.to_string(), ..error }),
+        error => error,
+    })
+}
+
+/// Skips whitespace, `#` line comments and `/* ... */` block comments.
+pub(crate) fn ws(text: &str) -> IResult<&str, (), SyntaxError<'_>> {
+    let (text, _) = ws_line_break(text)?;
     Ok((text, ()))
 }
 
-fn semicolon_separator(text: &str) -> IResult<&str, ()> {
-    let (text, _) = (multispace0, char(';'), multispace0).parse(text)?;
+/// Like `ws`, also reporting whether the whitespace skipped held a line
+/// break. Line breaks inside block comments don't count, while the one
+/// ending a line comment does.
+fn ws_line_break(text: &str) -> IResult<&str, bool, SyntaxError<'_>> {
+    let (mut text, space) = multispace0(text)?;
+    let mut line_break = space.contains('\n');
+    loop {
+        match comment(text)? {
+            (rest, Some(_)) => text = rest,
+            (_, None) => return Ok((text, line_break)),
+        }
+        let (rest, space) = multispace0(text)?;
+        (text, line_break) = (rest, line_break || space.contains('\n'));
+    }
+}
+
+/// The comment `text` starts with, delimiters included, or `None` if it
+/// doesn't start with one. A line comment ends before its newline.
+fn comment(text: &str) -> IResult<&str, Option<&str>, SyntaxError<'_>> {
+    let end = if let Some(comment) = text.strip_prefix('#') {
+        1 + comment.find('\n').unwrap_or(comment.len())
+    } else if let Some(comment) = text.strip_prefix("/*") {
+        match comment.find("*/") {
+            Some(end) => 2 + end + 2,
+            None => return Err(nom::Err::Failure(SyntaxError::new(text, "unterminated comment".to_string()))),
+        }
+    } else {
+        return Ok((text, None));
+    };
+    Ok((&text[end..], Some(&text[..end])))
+}
+
+/// Parses `inner` between the `open` and `close` delimiters, committing once
+/// `open` is seen. Running out of input, or meeting some other closing
+/// delimiter, where `close` is expected reports the group as unclosed.
+fn enclosed<'a, O>(open: char, mut inner: impl FnMut(&'a str) -> IResult<&'a str, O, SyntaxError<'a>>, close: char)
+    -> impl FnMut(&'a str) -> IResult<&'a str, O, SyntaxError<'a>> {
+    move |text| {
+        let (rest, _) = char(open)(text)?;
+        let (rest, _) = ws(rest)?;
+        let (rest, value) = inner(rest).map_err(commit)?;
+        let (rest, _) = ws(rest)?;
+        match char::<_, SyntaxError<'a>>(close)(rest) {
+            Ok((rest, _)) => Ok((rest, value)),
+            Err(_) if rest.is_empty() || rest.starts_with([')', '}', ']']) => {
+                Err(nom::Err::Failure(SyntaxError { unclosed: Some((open, text)), ..SyntaxError::new(rest, String::new()) }))
+            }
+            Err(error) => Err(commit(error)),
+        }
+    }
+}
+
+/// `enclosed` for the groups that nest expressions, parsing the body one
+/// level deeper. The delimiters are handled by their own functions, so that
+/// each level of nesting takes as little of the stack as possible.
+fn nested_group<'a>(text: &'a str, (open, close): (char, char), state: &ParseState<'_>, depth: usize, scope: &Definitions)
+    -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (rest, _) = group_open(text, open)?;
+    let (rest, body) = nested_expression(rest, state, depth + 1, scope).map_err(commit)?;
+    let (rest, _) = group_close(text, rest, open, close)?;
+    Ok((rest, body))
+}
+
+fn group_open(text: &str, open: char) -> IResult<&str, (), SyntaxError<'_>> {
+    let (rest, _) = char(open)(text)?;
+    ws(rest)
+}
+
+/// The `close` of the group opened at `opened`, after the body ending at
+/// `rest`, as `enclosed` expects it.
+fn group_close<'a>(opened: &'a str, rest: &'a str, open: char, close: char) -> IResult<&'a str, (), SyntaxError<'a>> {
+    let (rest, _) = ws(rest)?;
+    match char::<_, SyntaxError<'a>>(close)(rest) {
+        Ok((rest, _)) => Ok((rest, ())),
+        Err(_) if rest.is_empty() || rest.starts_with([')', '}', ']']) => {
+            Err(nom::Err::Failure(SyntaxError { unclosed: Some((open, opened)), ..SyntaxError::new(rest, String::new()) }))
+        }
+        Err(error) => Err(commit(error)),
+    }
+}
+
+/// Turns a recoverable error into a failure, as `cut` does.
+pub(crate) fn commit(error: nom::Err<SyntaxError<'_>>) -> nom::Err<SyntaxError<'_>> {
+    match error {
+        nom::Err::Error(error) => nom::Err::Failure(error),
+        error => error,
+    }
+}
+
+/// The character between coordinates, `ParserConfig::separator`.
+fn separator<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, (), SyntaxError<'a>> {
+    move |text| {
+        let (text, _) = (ws, char(c), ws).parse(text)?;
+        Ok((text, ()))
+    }
+}
+
+pub(crate) fn semicolon_separator(text: &str) -> IResult<&str, (), SyntaxError<'_>> {
+    let (text, _) = (ws, char(';'), ws).parse(text)?;
     Ok((text, ()))
 }
 
-fn float_pair(text: &str) -> IResult<&str, (f64, f64)> {
-    let (text, (f1, _, f2)) = (double, comma_separator, double).parse(text)?;
+/// Consumes any number of `;`, skipping empty statements, and reports
+/// whether there was at least one. Each `;` after the first, or every one
+/// when `leading`, ends an empty statement and is warned about.
+fn separators<'a>(mut text: &'a str, state: &ParseState<'_>, leading: bool) -> IResult<&'a str, bool, SyntaxError<'a>> {
+    let mut separated = false;
+    loop {
+        let (at, _) = ws(text)?;
+        let rest = match semicolon_separator(at) {
+            Ok((rest, _)) => rest,
+            Err(nom::Err::Error(_)) => return Ok((text, separated)),
+            Err(error) => return Err(error),
+        };
+        if separated || leading {
+            state.warn(at, "empty statement");
+        }
+        (text, separated) = (rest, true);
+    }
+}
+
+/// A name made of ASCII letters, digits and `_`, not starting with a digit.
+fn identifier(text: &str) -> IResult<&str, &str, SyntaxError<'_>> {
+    let (rest, _) = satisfy(|c| c.is_ascii_alphabetic() || c == '_')(text)?;
+    let (rest, _) = take_while(|c: char| c.is_ascii_alphanumeric() || c == '_')(rest)?;
+    Ok((rest, &text[..text.len() - rest.len()]))
+}
+
+/// `0x` or `0X` and hexadecimal digits, optionally signed.
+fn hexadecimal(text: &str) -> IResult<&str, f64, SyntaxError<'_>> {
+    let (rest, (sign, _, digits)) = (opt(one_of("+-")), alt((tag("0x"), tag("0X"))), hex_digit1).parse(text)?;
+    let magnitude = digits.chars().filter_map(|c| c.to_digit(16)).fold(0.0, |value, digit| value * 16.0 + f64::from(digit));
+    Ok((rest, if sign == Some('-') { -magnitude } else { magnitude }))
+}
+
+/// `a/b` for decimal integers `a`, optionally signed, and `b`, rejected
+/// where it starts if `b` is zero.
+fn rational(text: &str) -> IResult<&str, f64, SyntaxError<'_>> {
+    let (rest, (numerator, _, denominator)) = (recognize(pair(opt(one_of("+-")), digit1)), char('/'), digit1).parse(text)?;
+    let (numerator, denominator): (f64, f64) = (numerator.parse().unwrap_or(f64::NAN), denominator.parse().unwrap_or(f64::NAN));
+    if denominator == 0.0 {
+        let kind = ParseErrorKind::DivisionByZero;
+        return Err(nom::Err::Failure(SyntaxError { kind, ..SyntaxError::new(text, "division by zero".to_string()) }));
+    }
+    Ok((rest, numerator / denominator))
+}
+
+/// A finite number. `double` also reads `inf`, `nan` and overflowing
+/// literals, which are rejected where they start. The literals of
+/// `ParserConfig::extended_numbers` are tried first, as `double` would read
+/// just their leading digits.
+fn number<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, f64, SyntaxError<'a>> {
+    let extended = if config.extended_numbers { opt(alt((hexadecimal, rational)))(text)? } else { (text, None) };
+    let (rest, value) = match extended {
+        (rest, Some(value)) => (rest, value),
+        (_, None) => double(text)?,
+    };
+    if !value.is_finite() {
+        let kind = ParseErrorKind::NonFinite;
+        return Err(nom::Err::Failure(SyntaxError { kind, ..SyntaxError::new(text, "expected a finite number".to_string()) }));
+    }
+    Ok((rest, value))
+}
+
+fn float_pair<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, (f64, f64), SyntaxError<'a>> {
+    let number = |text| number(text, config);
+    let (text, (f1, _, f2)) = (number, separator(config.separator), number).parse(text)?;
     Ok((text, (f1, f2)))
 }
 
-fn float_triple(text: &str) -> IResult<&str, (f64, f64, f64)> {
-    let (text, (f1, _, f2, _, f3)) = (double, comma_separator, double, comma_separator, double).parse(text)?;
-    Ok((text, (f1, f2, f3)))
+/// A number with an optional `deg` or `rad` unit, in radians.
+fn angle<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, f64, SyntaxError<'a>> {
+    let (text, (value, unit)) = (|text| number(text, config), opt(alt((tag("deg"), tag("rad"))))).parse(text)?;
+    match unit {
+        Some("deg") => Ok((text, value.to_radians())),
+        _ => Ok((text, value)),
+    }
 }
 
-fn parenthesized_float_pair(text: &str) -> IResult<&str, (f64, f64)> {
-    let (text, (_, _, float_pair, _, _)) = (char('('), multispace0, float_pair, multispace0, char(')')).parse(text)?;
-    Ok((text, float_pair))
+fn float_pair_and_angle<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, (f64, f64, f64), SyntaxError<'a>> {
+    let (number, sep) = (|text| number(text, config), config.separator);
+    let (text, (f1, _, f2, _, theta)) = (number, separator(sep), number, separator(sep), |text| angle(text, config)).parse(text)?;
+    Ok((text, (f1, f2, theta)))
 }
 
-fn parenthesized_float_triple(text: &str) -> IResult<&str, (f64, f64, f64)> {
-    let (text, (_, _, float_triple, _, _)) = (char('('), multispace0, float_triple, multispace0, char(')')).parse(text)?;
-    Ok((text, float_triple))
+fn float_quadruple<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, (f64, f64, f64, f64), SyntaxError<'a>> {
+    let (number, sep) = (|text| number(text, config), config.separator);
+    let (text, (f1, _, f2, _, f3, _, f4)) = (number, separator(sep), number, separator(sep), number, separator(sep), number).parse(text)?;
+    Ok((text, (f1, f2, f3, f4)))
 }
 
-fn translation_expression(text: &str) -> IResult<&str, Expression> {
-    let (text, (_, _, (u, v))) = (tag("translation"), multispace0, parenthesized_float_pair).parse(text)?;
+fn parenthesized_float_pair<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, (f64, f64), SyntaxError<'a>> {
+    enclosed('(', |text| float_pair(text, config), ')')(text)
+}
+
+fn parenthesized_float_pair_and_angle<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, (f64, f64, f64), SyntaxError<'a>> {
+    enclosed('(', |text| float_pair_and_angle(text, config), ')')(text)
+}
+
+fn parenthesized_float_quadruple<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, (f64, f64, f64, f64), SyntaxError<'a>> {
+    enclosed('(', |text| float_quadruple(text, config), ')')(text)
+}
+
+/// `n` numbers in parentheses, separated by `ParserConfig::separator`.
+pub(crate) fn parenthesized_float_n<'a: 'c, 'c>(n: usize, config: &'c ParserConfig) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<f64>, SyntaxError<'a>> + 'c {
+    enclosed('(', move |text| {
+        let (mut text, first) = number(text, config)?;
+        let mut numbers = vec![first];
+        for _ in 1..n {
+            let (rest, (_, value)) = (separator(config.separator), |text| number(text, config)).parse(text)?;
+            numbers.push(value);
+            text = rest;
+        }
+        Ok((text, numbers))
+    }, ')')
+}
+
+fn translation_expression<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, (_, _, (u, v))) = (tag("translation"), ws, cut(|text| parenthesized_float_pair(text, config))).parse(text)?;
     Ok((text, Expression::Translation { u, v }))
 }
 
-fn rotation_expression(text: &str) -> IResult<&str, Expression> {
-    let (text, (_, _, (u, v, theta))) = (tag("rotation"), multispace0, parenthesized_float_triple).parse(text)?;
+fn rotation_expression<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, (_, _, (u, v, theta))) = (tag("rotation"), ws, cut(|text| parenthesized_float_pair_and_angle(text, config))).parse(text)?;
     Ok((text, Expression::Rotation { u, v, theta }))
 }
 
-fn iterate_expression(text: &str) -> IResult<&str, Expression> {
-    let (text, (_, _, _, _, body, _, _)) = (tag("iter"), multispace0, char('('), multispace0, expression, multispace0, char(')')).parse(text)?;
-    Ok((text, Expression::Iterate(Box::new(body))))
+fn scale_expression<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, (_, _, (u, v, sx, sy))) = (tag("scale"), ws, cut(|text| parenthesized_float_quadruple(text, config))).parse(text)?;
+    Ok((text, Expression::Scale { u, v, sx, sy }))
+}
+
+fn reflection_expression<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, (_, _, (u, v, theta))) = (tag("reflection"), ws, cut(|text| parenthesized_float_pair_and_angle(text, config))).parse(text)?;
+    Ok((text, Expression::Reflection { u, v, theta }))
+}
+
+/// `affine(a, b, c, d, e, f)`: the top two rows of the matrix.
+fn affine_expression<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, (_, _, n)) = (tag("affine"), ws, cut(parenthesized_float_n(6, config))).parse(text)?;
+    Ok((text, Expression::Affine(Matrix3([[n[0], n[1], n[2]], [n[3], n[4], n[5]], [0.0, 0.0, 1.0]]))))
+}
+
+/// `spin(theta)`, with the angle's unit optional as for `rotation`.
+fn spin_expression<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, (_, _, theta)) = (tag("spin"), ws, cut(enclosed('(', |text| angle(text, config), ')'))).parse(text)?;
+    Ok((text, Expression::Spin { theta }))
+}
+
+/// Decimal digits, rejected where they start if the value doesn't fit.
+fn count(text: &str) -> IResult<&str, usize, SyntaxError<'_>> {
+    let (rest, digits) = digit1(text)?;
+    match digits.parse() {
+        Ok(count) => Ok((rest, count)),
+        Err(_) => {
+            let kind = ParseErrorKind::CountOverflow;
+            Err(nom::Err::Failure(SyntaxError { kind, ..SyntaxError::new(text, "count is out of range".to_string()) }))
+        }
+    }
+}
+
+/// `id`, or `identity` spelled out.
+fn identity_expression(text: &str) -> IResult<&str, Expression, SyntaxError<'_>> {
+    let (text, _) = alt((tag("identity"), tag("id")))(text)?;
+    Ok((text, Expression::Identity))
+}
+
+fn iterate_count(text: &str) -> IResult<&str, usize, SyntaxError<'_>> {
+    enclosed('[', count, ']')(text)
+}
+
+/// Parses `iter[n](...)`, also spelled `repeat[n](...)`.
+fn iterate_expression<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, count) = iterate_header(text)?;
+    let (text, body) = nested_group(text, ('(', ')'), state, depth, scope).map_err(commit)?;
+    Ok((text, Expression::Iterate { count, body: Box::new(body) }))
+}
+
+/// The keyword and optional count before the body of an iteration.
+fn iterate_header(text: &str) -> IResult<&str, Option<usize>, SyntaxError<'_>> {
+    let (text, _) = alt((tag("iter"), tag("repeat")))(text)?;
+    let (text, _) = ws(text)?;
+    let (text, count) = opt(iterate_count)(text)?;
+    let (text, _) = ws(text)?;
+    Ok((text, count))
 }
 
-fn eitheror_leaf(text: &str) -> IResult<&str, Expression> {
-    let (text, (_, _, expr, _, _)) = (char('{'), multispace0, expression, multispace0, char('}')).parse(text)?;
+/// Parses `rev(...)`, the body's statements in reverse order.
+fn reverse_expression<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (text, _) = (tag("rev"), ws).parse(text)?;
+    let (text, body) = nested_group(text, ('(', ')'), state, depth, scope).map_err(commit)?;
+    Ok((text, Expression::Reverse(Box::new(body))))
+}
+
+fn eitheror_leaf<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    nested_group(text, ('{', '}'), state, depth, scope)
+}
+
+/// `:weight` after an alternative, rejected where the number starts if it
+/// is negative.
+fn choice_weight<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, f64, SyntaxError<'a>> {
+    let (text, (_, _, _)) = (ws, char(':'), ws).parse(text)?;
+    let (rest, weight) = cut(|text| number(text, config))(text)?;
+    if weight < 0.0 {
+        let kind = ParseErrorKind::InvalidWeight;
+        return Err(nom::Err::Failure(SyntaxError { kind, ..SyntaxError::new(text, "weight must not be negative".to_string()) }));
+    }
+    Ok((rest, weight))
+}
+
+/// `{ ... } or { ... }`, each alternative optionally followed by its
+/// `:weight`, 0.5 when left out.
+fn eitheror_expression<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    // the alternatives are parsed one at a time, keeping combinators off the
+    // recursive path, where unoptimized builds give every temporary of a
+    // combinator its own stack space
+    let start = text;
+    let (text, left) = eitheror_leaf(text, state, depth, scope)?;
+    let (text, left_weight) = eitheror_middle(text, state.config)?;
+    let (text, right) = eitheror_leaf(text, state, depth, scope).map_err(commit)?;
+    let (text, weights) = eitheror_weights(start, text, left_weight, state.config)?;
+    Ok((text, Expression::EitherOr { left: Box::new(left), right: Box::new(right), weights }))
+}
+
+/// The left alternative's weight and the `or` after it.
+fn eitheror_middle<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Option<f64>, SyntaxError<'a>> {
+    let (text, (left_weight, _, _, _)) = (opt(|text| choice_weight(text, config)), ws, cut(expecting("expected `or`", tag("or"))), ws).parse(text)?;
+    Ok((text, left_weight))
+}
+
+/// The right alternative's weight, and both weights of the choice starting
+/// at `start`, which must not both be zero.
+fn eitheror_weights<'a>(start: &'a str, text: &'a str, left_weight: Option<f64>, config: &ParserConfig) -> IResult<&'a str, (f64, f64), SyntaxError<'a>> {
+    let (text, right_weight) = opt(|text| choice_weight(text, config))(text)?;
+    let weights = (left_weight.unwrap_or(0.5), right_weight.unwrap_or(0.5));
+    if weights == (0.0, 0.0) {
+        let kind = ParseErrorKind::InvalidWeight;
+        return Err(nom::Err::Failure(SyntaxError { kind, ..SyntaxError::new(start, "weights must not both be zero".to_string()) }));
+    }
+    Ok((text, weights))
+}
+
+/// A use of a name, replaced by its definition in `scope`.
+fn reference<'a>(text: &'a str, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let (rest, name) = identifier(text)?;
+    match Expression::Reference(name.to_string()).resolve(scope) {
+        Ok(expr) => Ok((rest, expr)),
+        Err(ResolveError::Undefined { name }) => {
+            // the whole word, as a name stops at the first letter that isn't ASCII
+            let word: String = text.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect();
+            let message = match nearest_keyword(&word) {
+                Some(keyword) => format!("undefined name `{}`; did you mean `{}`?", name, keyword),
+                None => format!("undefined name `{}`", name),
+            };
+            Err(nom::Err::Failure(SyntaxError { kind: ParseErrorKind::UndefinedName { name }, ..SyntaxError::new(text, message) }))
+        }
+        Err(ResolveError::Cyclic { name }) => {
+            let message = format!("`{}` is defined in terms of itself", name);
+            Err(nom::Err::Failure(SyntaxError { kind: ParseErrorKind::CyclicReference { name }, ..SyntaxError::new(text, message) }))
+        }
+    }
+}
+
+/// The keyword closest to `word` by edit distance, if it is close enough to
+/// be a likely misspelling: at most one edit per three characters of the
+/// keyword.
+fn nearest_keyword(word: &str) -> Option<&'static str> {
+    KEYWORDS.iter()
+        .map(|keyword| (levenshtein(word, keyword), *keyword))
+        .filter(|(distance, keyword)| *distance > 0 && *distance <= keyword.len() / 3)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, keyword)| keyword)
+}
+
+/// The number of single-character insertions, deletions and substitutions
+/// turning `a` into `b`, counting characters rather than bytes.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // `row[j]` is the distance from the prefix of `a` read so far to `b[..j]`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Parses `let name = statement`. The value may only use names defined
+/// before it; using the name itself, unless an earlier definition of it is
+/// in scope, is reported as a cycle.
+fn definition<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, (&'a str, Expression), SyntaxError<'a>> {
+    let (rest, name) = definition_name(text)?;
+    let (rest, value) = dotted_statements(rest, state, depth, scope).map_err(|error| self_reference(commit(error), name))?;
+    Ok((rest, (name, chain(value).unwrap_or(Expression::Identity))))
+}
+
+/// `let`, the name being defined and the `=` after it.
+fn definition_name(text: &str) -> IResult<&str, &str, SyntaxError<'_>> {
+    let (text, _) = tag("let")(text)?;
+    let (text, _) = ws(text)?;
+    let (rest, name) = expecting("expected a name", identifier)(text).map_err(commit)?;
+    if KEYWORDS.contains(&name) {
+        return Err(nom::Err::Failure(SyntaxError::new(text, format!("`{}` is a keyword", name))));
+    }
+    let (rest, _) = ws(rest)?;
+    let (rest, _) = char('=')(rest).map_err(commit)?;
+    let (rest, _) = ws(rest)?;
+    Ok((rest, name))
+}
+
+/// `error` from the value of the definition of `name`, reported as a cycle
+/// if the value uses `name` itself.
+fn self_reference<'a>(error: nom::Err<SyntaxError<'a>>, name: &str) -> nom::Err<SyntaxError<'a>> {
+    match error {
+        nom::Err::Failure(SyntaxError { kind: ParseErrorKind::UndefinedName { name: undefined }, input, .. }) if undefined == name => {
+            let message = format!("`{}` is defined in terms of itself", name);
+            nom::Err::Failure(SyntaxError { kind: ParseErrorKind::CyclicReference { name: undefined }, ..SyntaxError::new(input, message) })
+        }
+        error => error,
+    }
+}
+
+/// The character between statements chained as methods are, binding
+/// tighter than `;`.
+fn dot_separator(text: &str) -> IResult<&str, (), SyntaxError<'_>> {
+    let (text, _) = (ws, char('.'), ws).parse(text)?;
+    Ok((text, ()))
+}
+
+/// Parses statements joined by `.`, as in `translation(1, 0).rotation(0, 0, 1)`,
+/// which chain just as when joined by `;`. A `.` can't be mistaken for a
+/// decimal point, as numbers only appear inside a statement's delimiters.
+fn dotted_statements<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Vec<Expression>, SyntaxError<'a>> {
+    let (mut text, first) = statement(text, state, depth, scope)?;
+    let mut statements = vec![first];
+    while let Ok((rest, _)) = dot_separator(text) {
+        let (rest, next) = statement(rest, state, depth, scope).map_err(commit)?;
+        statements.push(next);
+        text = rest;
+    }
+    Ok((text, statements))
+}
+
+fn statement<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    // the nesting statements are dispatched directly, keeping `alt` off the
+    // recursive path
+    match identifier(text) {
+        Ok((_, "iter" | "repeat")) => return iterate_expression(text, state, depth, scope),
+        Ok((_, "rev")) => return reverse_expression(text, state, depth, scope),
+        Ok((_, name)) if !KEYWORDS.contains(&name) => return reference(text, scope),
+        _ => {}
+    }
+    if text.starts_with('{') {
+        return eitheror_expression(text, state, depth, scope);
+    }
+    leaf_statement(text, state.config)
+}
+
+/// A statement that doesn't nest others.
+fn leaf_statement<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    let leaves = (
+        move |text| translation_expression(text, config),
+        move |text| rotation_expression(text, config),
+        move |text| scale_expression(text, config),
+        move |text| reflection_expression(text, config),
+        move |text| affine_expression(text, config),
+        move |text| spin_expression(text, config),
+        identity_expression,
+    );
+    expecting(EXPECTED_STATEMENT, alt(leaves))(text)
+}
+
+/// Parses `;`-separated statements, or `.`-separated ones, into a
+/// right-nested chain. Empty
+/// statements, including leading and trailing `;`, are skipped. Statements
+/// are read in a loop so that only `iter(...)`, `rev(...)` and `{ ... }` nesting
+/// counts towards `ParserConfig::max_depth`.
+///
+/// Names defined by `let` are visible to the statements after them, nested
+/// groups included, and inlined where they are used; `scope` holds the
+/// definitions of the enclosing groups.
+fn nested_expression<'a>(text: &'a str, state: &ParseState<'_>, depth: usize, scope: &Definitions) -> IResult<&'a str, Expression, SyntaxError<'a>> {
+    if depth > state.config.max_depth {
+        return Err(too_deep(text, state.config.max_depth));
+    }
+    let mut definitions: Option<Definitions> = None;
+    let mut statements = Vec::new();
+    let (mut text, _) = separators(text, state, true)?;
+    loop {
+        let current_scope = definitions.as_ref().unwrap_or(scope);
+        let remaining_text = if let Ok((_, "let")) = identifier(text) {
+            let (remaining_text, (name, value)) = definition(text, state, depth, current_scope)?;
+            definitions.get_or_insert_with(|| scope.clone()).define(name, value);
+            remaining_text
+        } else {
+            let (remaining_text, dotted) = dotted_statements(text, state, depth, current_scope)?;
+            statements.extend(dotted);
+            remaining_text
+        };
+        let more_statements;
+        (text, more_statements) = statement_end(remaining_text, state)?;
+        if !more_statements {
+            break;
+        }
+    }
+    let Some(expr) = chain(statements) else {
+        return Err(nom::Err::Failure(SyntaxError::new(text, EXPECTED_STATEMENT.to_string())));
+    };
     Ok((text, expr))
 }
 
-fn eitheror_expression(text: &str) -> IResult<&str, Expression> {
-    let (text, (left, _, _, _, right)) = (eitheror_leaf, multispace0, tag("or"), multispace0, eitheror_leaf).parse(text)?;
-    Ok((text, Expression::EitherOr { left: Box::new(left), right: Box::new(right) }))
+/// Skips what follows a statement ending at `text`: where the next
+/// statement starts and `true`, or where the statements end and `false` if
+/// the input or the enclosing group ends next.
+fn statement_end<'a>(text: &'a str, state: &ParseState<'_>) -> IResult<&'a str, bool, SyntaxError<'a>> {
+    let (text_after_separators, separated) = separators(text, state, false)?;
+    // without a `;`, `separators` left all the whitespace after the
+    // statement to be skipped here
+    let (lookahead, line_break) = ws_line_break(text_after_separators)?;
+    let separated = separated || state.config.newline_as_separator && line_break;
+    // the statements end with the input or the enclosing group, whether
+    // or not a trailing separator was written
+    if lookahead.is_empty() || lookahead.starts_with(')') || lookahead.starts_with('}') {
+        return Ok((if separated { text_after_separators } else { text }, false));
+    }
+    if !separated {
+        // a name or a group can only start another statement, so the
+        // `;` before it was left out; anything else is a mistake
+        let starts_statement = lookahead.starts_with('{') || identifier(lookahead).is_ok_and(|(_, word)| word != "or");
+        if !(state.config.recover_missing_separator && starts_statement) {
+            return Err(nom::Err::Failure(SyntaxError::new(lookahead, "expected `;`".to_string())));
+        }
+        state.warn(lookahead, "missing `;`");
+    }
+    Ok((lookahead, true))
+}
+
+/// The failure for nesting past `max_depth` at `text`.
+pub(crate) fn too_deep(text: &str, max_depth: usize) -> nom::Err<SyntaxError<'_>> {
+    let message = format!("nesting exceeds the maximum depth of {}", max_depth);
+    nom::Err::Failure(SyntaxError { kind: ParseErrorKind::TooDeep { depth: max_depth }, ..SyntaxError::new(text, message) })
+}
+
+pub fn expression(text: &str) -> IResult<&str, Expression, SyntaxError<'_>> {
+    let config = ParserConfig::default();
+    nested_expression(text, &ParseState::new(&config, text), 0, &Definitions::new())
+}
+
+pub fn program(text: &str) -> IResult<&str, Program, SyntaxError<'_>> {
+    let (text, (_, _, _, (x, y), _, body)) = (ws, expecting("expected `init`", tag("init")), ws, cut(|text| parenthesized_float_pair(text, &ParserConfig::default())), cut(semicolon_separator), cut(expression)).parse(text)?;
+    Ok((text, Program { init: Point { x, y }, body }))
+}
+
+/// Parses a complete expression, surrounding whitespace included, reporting
+/// failures against the original input. Along with the expression come the
+/// warnings about input that parsed but is likely a mistake, such as stray
+/// `;`s.
+pub fn parse_expression(input: &str) -> Result<(Expression, Vec<Warning>), ParseError> {
+    parse_expression_with(input, &ParserConfig::default())
+}
+
+pub fn parse_expression_with(input: &str, config: &ParserConfig) -> Result<(Expression, Vec<Warning>), ParseError> {
+    let state = ParseState::new(config, input);
+    let expression = |text| nested_expression(text, &state, 0, &Definitions::new());
+    let (text, (_, expr, _)) = (ws, expression, ws).parse(input).map_err(|error| report(input, error))?;
+    expect_end(input, text)?;
+    Ok((expr, state.warnings.into_inner()))
+}
+
+/// Parses a complete program, `init(x, y);` and its body, surrounding
+/// whitespace included.
+pub fn parse_program(input: &str) -> Result<Program, ParseError> {
+    let (text, (parsed_program, _)) = (program, ws).parse(input).map_err(|error| report(input, error))?;
+    expect_end(input, text)?;
+    Ok(parsed_program)
+}
+
+/// Reads all of `reader` and parses it as by `parse_program`. The input
+/// must be UTF-8; invalid bytes fail with `ParseErrorKind::InvalidUtf8`
+/// where they start, and read errors with `ParseErrorKind::Io` after the
+/// bytes read.
+#[cfg(feature = "std")]
+pub fn parse_program_reader<R: Read>(mut reader: R) -> Result<Program, ParseError> {
+    let mut bytes = Vec::new();
+    if let Err(error) = reader.read_to_end(&mut bytes) {
+        return Err(ParseError { kind: ParseErrorKind::Io(error.kind()), ..at_byte(&bytes, bytes.len(), error.to_string()) });
+    }
+    match core::str::from_utf8(&bytes) {
+        Ok(input) => parse_program(input),
+        Err(error) => {
+            let offset = error.valid_up_to();
+            let unexpected = format!("byte 0x{:02x}", bytes[offset]);
+            Err(ParseError { kind: ParseErrorKind::InvalidUtf8, unexpected, ..at_byte(&bytes, offset, "invalid UTF-8".to_string()) })
+        }
+    }
+}
+
+impl FromStr for Expression {
+    type Err = ParseError;
+
+    /// Parses as by `parse_expression`, dropping the warnings.
+    fn from_str(input: &str) -> Result<Expression, ParseError> {
+        parse_expression(input).map(|(expr, _)| expr)
+    }
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+
+    /// Parses as by `parse_program`.
+    fn from_str(input: &str) -> Result<Program, ParseError> {
+        parse_program(input)
+    }
+}
+
+/// Parses a program as `parse_program` does, also returning its comments
+/// in input order, so that a formatter can put them back.
+pub fn parse_with_trivia(input: &str) -> Result<(Program, Vec<Trivia>), ParseError> {
+    let parsed_program = parse_program(input)?;
+    // comments can only be where `ws` skipped them, and outside of them
+    // `#` and `/*` only start comments
+    let mut trivia = Vec::new();
+    let mut rest = input;
+    while let Some(start) = rest.find(['#', '/']) {
+        rest = match comment(&rest[start..]).map_err(|error| report(input, error))? {
+            (after, Some(text)) => {
+                trivia.push(Trivia { offset: input.len() - rest.len() + start, text: text.to_string() });
+                after
+            }
+            (_, None) => &rest[start + 1..],
+        };
+    }
+    Ok((parsed_program, trivia))
+}
+
+/// Parses programs written one after another, separated by lines holding
+/// just `---`. Chunks with nothing but whitespace are skipped. A failure is
+/// located in the whole input, with `ParseError::chunk` the index of the
+/// failing program among the non-empty chunks.
+pub fn parse_many(input: &str) -> Result<Vec<Program>, ParseError> {
+    let mut chunks = Vec::new();
+    let (mut start, mut offset) = (0, 0);
+    for line in input.split_inclusive('\n') {
+        if line.trim() == "---" {
+            chunks.push(&input[start..offset]);
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    chunks.push(&input[start..]);
+
+    let chunks = chunks.into_iter().filter(|chunk| !chunk.trim().is_empty());
+    chunks.enumerate().map(|(index, chunk)| {
+        parse_program(chunk).map_err(|error| {
+            let tail = &chunk[error.offset..];
+            ParseError { unexpected: error.unexpected, kind: error.kind, chunk: Some(index), ..ParseError::at(input, tail, error.message) }
+        })
+    }).collect()
+}
+
+/// Parses a program as `parse_program` does, but doesn't stop at the first
+/// malformed top-level statement: its error is recorded and parsing resumes
+/// after the next top-level `;`. Returns the program made of the statements
+/// that parsed, if there is an `init` and at least one of them, together
+/// with every error in input order. A malformed `init` is skipped the same
+/// way, leaving no program.
+pub fn parse_program_lenient(input: &str) -> (Option<Program>, Vec<ParseError>) {
+    let mut header = (ws, expecting("expected `init`", tag("init")), ws, cut(|text| parenthesized_float_pair(text, &ParserConfig::default())), cut(semicolon_separator));
+    let mut errors = Vec::new();
+    let (init, rest) = match header.parse(input) {
+        Ok((rest, (_, _, _, (x, y), _))) => (Some(Point { x, y }), Some(rest)),
+        Err(error) => {
+            errors.push(report(input, error));
+            (None, split_statement(input).1)
+        }
+    };
+    let mut body = Vec::new();
+    let iter = StatementIter { input, rest, pending: Vec::new(), config: ParserConfig::default(), definitions: Definitions::new() };
+    for statement in iter {
+        match statement {
+            Ok(expr) => body.push(expr),
+            Err(error) => errors.push(error),
+        }
+    }
+    let parsed_program = init.zip(chain(body)).map(|(init, body)| Program { init, body });
+    (parsed_program, errors)
+}
+
+/// An error at `offset` into `bytes`, located by the valid UTF-8 before it.
+#[cfg(feature = "std")]
+fn at_byte(bytes: &[u8], offset: usize, message: String) -> ParseError {
+    let before = match core::str::from_utf8(&bytes[..offset]) {
+        Ok(before) => before,
+        Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()]).unwrap_or_default(),
+    };
+    ParseError { offset, ..ParseError::at(before, "", message) }
+}
+
+/// Fails unless `text`, the rest of `input` after a parse, is empty.
+fn expect_end(input: &str, text: &str) -> Result<(), ParseError> {
+    if let Some(close) = text.chars().next().filter(|c| [')', '}', ']'].contains(c)) {
+        return Err(ParseError::at(input, text, format!("unmatched `{}`", close)));
+    }
+    if !text.is_empty() {
+        return Err(ParseError::at(input, text, "expected end of input".to_string()));
+    }
+    Ok(())
+}
+
+/// Parses a program in the JSON form its `Serialize` impl writes, as an
+/// alternative to the text syntax. Failures, including unknown `type` tags,
+/// are located where the JSON parser stopped.
+#[cfg(feature = "json")]
+pub fn parse_json(input: &str) -> Result<Program, ParseError> {
+    let program: Program = serde_json::from_str(input).map_err(|error| {
+        let line_start: usize = input.split_inclusive('\n').take(error.line().saturating_sub(1)).map(str::len).sum();
+        let mut offset = (line_start + error.column().saturating_sub(1)).min(input.len());
+        while !input.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        // the position is reported separately
+        let message = error.to_string();
+        let message = message.rsplit_once(" at line ").map_or(message.as_str(), |(message, _)| message).to_string();
+        ParseError::at(input, &input[offset..], message)
+    })?;
+    let mut finite = program.init.x.is_finite() && program.init.y.is_finite();
+    program.body.visit(&mut |node| finite &= match node {
+        Expression::Translation { u, v } => u.is_finite() && v.is_finite(),
+        Expression::Rotation { u, v, theta } | Expression::Reflection { u, v, theta } => [u, v, theta].iter().all(|n| n.is_finite()),
+        Expression::Scale { u, v, sx, sy } => [u, v, sx, sy].iter().all(|n| n.is_finite()),
+        Expression::Affine(m) => m.0.iter().flatten().all(|n| n.is_finite()),
+        Expression::EitherOr { weights: (w1, w2), .. } => w1.is_finite() && w2.is_finite(),
+        _ => true,
+    });
+    if !finite {
+        return Err(ParseError { kind: ParseErrorKind::NonFinite, ..ParseError::at(input, input, "expected finite numbers".to_string()) });
+    }
+    Ok(program)
+}
+
+pub(crate) fn report(input: &str, error: nom::Err<SyntaxError<'_>>) -> ParseError {
+    match error {
+        nom::Err::Error(error) | nom::Err::Failure(error) => error.into_parse_error(input),
+        nom::Err::Incomplete(_) => ParseError::at(input, "", "unexpected end of input".to_string()),
+    }
+}
+
+/// Iterates over the top-level statements of an expression, parsing each
+/// one only when it is reached. Definitions aren't yielded but are inlined
+/// into the statements after them, as by `parse_expression`.
+pub fn statements(input: &str) -> StatementIter<'_> {
+    StatementIter { input, rest: Some(input), pending: Vec::new(), config: ParserConfig::default(), definitions: Definitions::new() }
 }
 
-fn expression(text: &str) -> IResult<&str, Expression> {
-    let (remaining_text, expr) = alt((translation_expression, rotation_expression, iterate_expression, eitheror_expression))(text)?;
-    match semicolon_separator(remaining_text) {
-        Ok((text_after_semicolon, _)) => {
-            let (trailing_text, additional_expression) = expression(text_after_semicolon)?;
-            // check trailing_text
-            Ok((trailing_text, Expression::Chained(Box::new(expr), Box::new(additional_expression))))
+pub struct StatementIter<'a> {
+    input: &'a str,
+    /// The input after the last `;` split at, or `None` once all of it has
+    /// been handed out.
+    rest: Option<&'a str>,
+    /// The `.`-separated statements of the last segment still to be handed
+    /// out, last first.
+    pending: Vec<Expression>,
+    config: ParserConfig,
+    definitions: Definitions,
+}
+
+impl<'a> StatementIter<'a> {
+    /// Parses the text between two top-level `;`, which holds statements
+    /// joined by `.`, a definition or nothing at all.
+    fn parse_segment(&mut self, segment: &'a str) -> Result<Vec<Expression>, ParseError> {
+        let (text, _) = ws(segment).map_err(|error| report(self.input, error))?;
+        if text.is_empty() {
+            return Ok(Vec::new());
+        }
+        let state = ParseState::new(&self.config, self.input);
+        let (text, statements) = if let Ok((_, "let")) = identifier(text) {
+            let (text, (name, value)) = definition(text, &state, 0, &self.definitions).map_err(|error| report(self.input, error))?;
+            self.definitions.define(name, value);
+            (text, Vec::new())
+        } else {
+            dotted_statements(text, &state, 0, &self.definitions).map_err(|error| report(self.input, error))?
+        };
+        let (text, _) = ws(text).map_err(|error| report(self.input, error))?;
+        if let Some(close) = text.chars().next().filter(|c| [')', '}', ']'].contains(c)) {
+            return Err(ParseError::at(self.input, text, format!("unmatched `{}`", close)));
+        }
+        if !text.is_empty() {
+            return Err(ParseError::at(self.input, text, "expected `;`".to_string()));
         }
-        Err(nom::Err::Error(inner_error)) => {
-            if inner_error.input.is_empty() || inner_error.input.starts_with(")") || inner_error.input.starts_with("}") {
-                Ok((remaining_text, expr))
-            } else {
-                Err(nom::Err::Failure(inner_error))
+        Ok(statements)
+    }
+}
+
+impl<'a> Iterator for StatementIter<'a> {
+    type Item = Result<Expression, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(expr) = self.pending.pop() {
+                return Some(Ok(expr));
+            }
+            let (segment, rest) = split_statement(self.rest?);
+            self.rest = rest;
+            match self.parse_segment(segment) {
+                Ok(statements) => self.pending = statements.into_iter().rev().collect(),
+                Err(error) => return Some(Err(error)),
             }
-        },
-        Err(error) => Err(error),
+        }
+    }
+}
+
+/// Splits `text` at its first `;` outside of any group or comment, returning
+/// the text before it and, if there was one, the text after it.
+fn split_statement(text: &str) -> (&str, Option<&str>) {
+    let bytes = text.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => i = text[i..].find('\n').map_or(bytes.len(), |end| i + end),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = text[i + 2..].find("*/").map_or(bytes.len(), |end| i + 2 + end + 1),
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            b';' if depth == 0 => return (&text[..i], Some(&text[i + 1..])),
+            _ => {}
+        }
+        i += 1;
     }
+    (text, None)
 }
 
 #[test]
 fn test_basic_expressions() {
     let raw_translation_expression = "translation ( 0.7, 18.65 )";
     let expected_expression = Expression::Translation { u: 0.7, v: 18.65 };
-    let (_, parsed_expression) = translation_expression(raw_translation_expression).unwrap();
+    let (_, parsed_expression) = translation_expression(raw_translation_expression, &ParserConfig::default()).unwrap();
     assert_eq!(expected_expression, parsed_expression);
 
     let raw_rotation_expression = "rotation( 1.15, 0.8, 0.553)";
     let expected_expression = Expression::Rotation { u: 1.15, v: 0.8, theta: 0.553 };
-    let (_, parsed_expression) = rotation_expression(raw_rotation_expression).unwrap();
+    let (_, parsed_expression) = rotation_expression(raw_rotation_expression, &ParserConfig::default()).unwrap();
     assert_eq!(expected_expression, parsed_expression);
 }
 
+#[test]
+fn test_signed_and_scientific_floats() {
+    let forms = [("-3.5", -3.5), ("+0.1", 0.1), ("-2", -2.0), ("3E4", 3e4), ("1e-2", 1e-2), ("+2.5e+3", 2.5e3), ("-.5", -0.5), ("7.", 7.0)];
+    for (first_text, first) in forms {
+        for (second_text, second) in forms {
+            let raw_expression = format!("translation({}, {})", first_text, second_text);
+            assert_eq!(Expression::Translation { u: first, v: second }, parse_expression(&raw_expression).unwrap().0);
+
+            let raw_expression = format!("rotation({}, {}, {})", second_text, first_text, second_text);
+            assert_eq!(Expression::Rotation { u: second, v: first, theta: second }, parse_expression(&raw_expression).unwrap().0);
+
+            let raw_expression = format!("scale({}, {}, {}, {})", first_text, second_text, first_text, second_text);
+            assert_eq!(Expression::Scale { u: first, v: second, sx: first, sy: second }, parse_expression(&raw_expression).unwrap().0);
+        }
+    }
+
+    assert_eq!(Expression::Rotation { u: 0.1, v: -2.0, theta: 3e4 }, parse_expression("rotation(+0.1, -2, 3E4)").unwrap().0);
+    assert!(parse_expression("translation(- 1, 2)").is_err());
+    assert!(parse_expression("translation(1e, 2)").is_err());
+}
+
+#[test]
+fn test_separator() {
+    let raw_expression = "iter[3](translation(1, 2); rotation(0, 0, 90deg)); { scale(0, 0, 2, 0.5) } or { reflection(1, 1, 0.5) }";
+    let pipe = ParserConfig { separator: '|', ..ParserConfig::default() };
+    let piped_expression = raw_expression.replace(',', " |");
+    assert_eq!(parse_expression(raw_expression).unwrap().0, parse_expression_with(&piped_expression, &pipe).unwrap().0);
+
+    assert!(parse_expression_with(raw_expression, &pipe).is_err());
+    let error = parse_expression("translation(1 | 2)").unwrap_err();
+    assert_eq!("expected `,`", error.message);
+    assert_eq!(14, error.offset);
+}
+
+#[test]
+fn test_non_finite_numbers() {
+    let error = parse_expression("translation(inf, 0)").unwrap_err();
+    assert_eq!(ParseErrorKind::NonFinite, error.kind);
+    assert_eq!(12, error.offset);
+    assert_eq!("expected a finite number", error.message);
+
+    let error = parse_expression("rotation(0, nan, 1)").unwrap_err();
+    assert_eq!(ParseErrorKind::NonFinite, error.kind);
+    assert_eq!(12, error.offset);
+
+    for raw_expression in ["scale(0, 0, 1, Infinity)", "rotation(0, 0, NaN deg)", "rotation(0, 0, infdeg)", "translation(1e400, 0)"] {
+        assert_eq!(ParseErrorKind::NonFinite, parse_expression(raw_expression).unwrap_err().kind, "{}", raw_expression);
+    }
+}
+
+#[test]
+fn test_extended_numbers() {
+    let config = ParserConfig { extended_numbers: true, ..ParserConfig::default() };
+    assert_eq!(Expression::Translation { u: 1.0 / 3.0, v: 2.0 / 5.0 }, parse_expression_with("translation(1/3, 2/5)", &config).unwrap().0);
+    assert_eq!(Expression::Rotation { u: 255.0, v: -16.0, theta: (-1.0f64 / 2.0).to_radians() }, parse_expression_with("rotation(0xff, -0x10, -1/2deg)", &config).unwrap().0);
+    assert_eq!(Expression::Translation { u: 0.5, v: 2.0 }, parse_expression_with("translation(0.5, 2)", &config).unwrap().0);
+
+    let error = parse_expression_with("translation(1, 2/0)", &config).unwrap_err();
+    assert_eq!(ParseErrorKind::DivisionByZero, error.kind);
+    assert_eq!(15, error.offset);
+    assert_eq!("division by zero", error.message);
+
+    // off by default
+    assert!(parse_expression("translation(1/3, 2/5)").is_err());
+    assert!(parse_expression("translation(0x10, 0)").is_err());
+}
+
+#[test]
+fn test_angle_units() {
+    let parsed_expression = parse_expression("rotation(0,0,180deg)").unwrap().0;
+    let Expression::Rotation { theta, .. } = parsed_expression else { panic!("expected a rotation") };
+    assert!((theta - std::f64::consts::PI).abs() < 1e-12);
+
+    assert_eq!(Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 }, parse_expression("rotation(0,0,1rad)").unwrap().0);
+    assert_eq!(Expression::Rotation { u: 1.0, v: 2.0, theta: -0.5 }, parse_expression("rotation(1, 2, -0.5)").unwrap().0);
+    assert_eq!(Expression::Reflection { u: 0.0, v: 0.0, theta: 90f64.to_radians() }, parse_expression("reflection(0, 0, 90deg)").unwrap().0);
+
+    assert!(parse_expression("translation(1deg, 0)").is_err());
+    assert!(parse_expression("rotation(0, 0, 1 deg)").is_err());
+    assert!(parse_expression("rotation(0, 0, 1grad)").is_err());
+}
+
+#[test]
+fn test_reflection_expressions() {
+    let raw_reflection_expression = "reflection(1, 2, 0.785)";
+    let expected_expression = Expression::Reflection { u: 1.0, v: 2.0, theta: 0.785 };
+    let (_, parsed_expression) = reflection_expression(raw_reflection_expression, &ParserConfig::default()).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+
+    let raw_expression = "{ reflection(0, 0, 0) } or { rotation(0, 0, 3.0) }";
+    let expected_expression = Expression::EitherOr {
+        left: Box::new(Expression::Reflection { u: 0.0, v: 0.0, theta: 0.0 }),
+        right: Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 3.0 }),
+        weights: (0.5, 0.5),
+    };
+    let (_, parsed_expression) = expression(raw_expression).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+}
+
+#[test]
+fn test_scale_expressions() {
+    let raw_scale_expression = "scale( 0.5, -1, 2, 0.25 )";
+    let expected_expression = Expression::Scale { u: 0.5, v: -1.0, sx: 2.0, sy: 0.25 };
+    let (_, parsed_expression) = scale_expression(raw_scale_expression, &ParserConfig::default()).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+
+    let raw_expression = "iter[2](scale(0, 0, 2, 2)); translation(1, 0)";
+    let expected_expression =
+      Expression::Chained(
+        Box::new(Expression::Iterate { count: Some(2), body: Box::new(
+            Expression::Scale { u: 0.0, v: 0.0, sx: 2.0, sy: 2.0 }
+        )}),
+        Box::new(Expression::Translation { u: 1.0, v: 0.0 }),
+      );
+    let (_, parsed_expression) = expression(raw_expression).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+
+    assert!(parse_expression("scale(0, 0, 2)").is_err());
+}
+
+
+#[test]
+fn test_identity_expressions() {
+    let expected_expression = Expression::Chained(Box::new(Expression::Identity), Box::new(Expression::Translation { u: 1.0, v: 0.0 }));
+    assert_eq!(expected_expression, parse_expression("id; translation(1,0)").unwrap().0);
+    assert_eq!(expected_expression, parse_expression("identity ; translation(1,0)").unwrap().0);
+    assert_eq!(Expression::Iterate { count: Some(2), body: Box::new(Expression::Identity) }, parse_expression("iter[2](id)").unwrap().0);
+
+    assert_eq!("undefined name `idx`", parse_expression("idx").unwrap_err().message);
+    assert!(parse_expression("let id = translation(1, 0); id").is_err());
+}
 
 #[test]
 fn test_iterate_expressions() {
     let raw_expression = "iter( rotation(0.1, 0.2, 0.3) )";
-    let expected_expression = Expression::Iterate( Box::new(
+    let expected_expression = Expression::Iterate { count: None, body: Box::new(
                                                 Expression::Rotation { u: 0.1, v: 0.2, theta: 0.3 })
-                                          );
+                                          };
     let (_, parsed_expression) = expression(raw_expression).unwrap();
     assert_eq!(expected_expression, parsed_expression);
 
     let raw_expression = "iter( iter( translation(0.1, 0.2) ) )";
-    let expected_expression = Expression::Iterate( Box::new(
-                                              Expression::Iterate(Box::new(
+    let expected_expression = Expression::Iterate { count: None, body: Box::new(
+                                              Expression::Iterate { count: None, body: Box::new(
                                                   Expression::Translation { u: 0.1, v: 0.2 })
-                                              ))
-                                          );
+                                              })
+                                          };
+    let (_, parsed_expression) = expression(raw_expression).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+}
+
+#[test]
+fn test_reverse_expressions() {
+    let raw_expression = "rev( translation(1, 0); rotation(0, 0, 0.5) )";
+    let expected_expression = Expression::Reverse(Box::new(Expression::Chained(
+        Box::new(Expression::Translation { u: 1.0, v: 0.0 }),
+        Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 0.5 }),
+    )));
     let (_, parsed_expression) = expression(raw_expression).unwrap();
     assert_eq!(expected_expression, parsed_expression);
+    assert_eq!("rev(translation(1, 0); rotation(0, 0, 0.5))", parsed_expression.to_string());
+
+    let error = parse_expression("rev translation(1, 0)").unwrap_err();
+    assert_eq!("expected `(`", error.message);
+    assert_eq!("`rev` is a keyword", parse_expression("let rev = id; rev").unwrap_err().message);
+}
+
+#[test]
+fn test_iterate_count_expressions() {
+    let raw_expression = "iter[3]( translation(1, 0) )";
+    let expected_expression = Expression::Iterate { count: Some(3), body: Box::new(
+                                                Expression::Translation { u: 1.0, v: 0.0 })
+                                          };
+    let (_, parsed_expression) = expression(raw_expression).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+
+    let raw_expression = "iter [ 12 ] (rotation(0.1, 0.2, 0.3))";
+    let expected_expression = Expression::Iterate { count: Some(12), body: Box::new(
+                                                Expression::Rotation { u: 0.1, v: 0.2, theta: 0.3 })
+                                          };
+    let (_, parsed_expression) = expression(raw_expression).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+
+    assert_eq!(parse_expression("iter[4](translation(1,0))"), parse_expression("repeat[4](translation(1,0))"));
+    assert_eq!(parse_expression("iter ( rotation(0, 0, 1); iter(translation(1, 0)) )"), parse_expression("repeat ( rotation(0, 0, 1); repeat(translation(1, 0)) )"));
+    assert!(parse_expression("let repeat = translation(1, 0); repeat").is_err());
+
+    assert!(expression("iter[](translation(1, 0))").is_err());
+    assert!(expression("iter[-2](translation(1, 0))").is_err());
+
+    let error = parse_expression("iter[100000000000000000000](translation(1, 0))").unwrap_err();
+    assert_eq!(ParseErrorKind::CountOverflow, error.kind);
+    assert_eq!(5, error.offset);
+    assert_eq!("count is out of range", error.message);
+    assert_eq!(Some(usize::MAX), match parse_expression(&format!("iter[{}](translation(1, 0))", usize::MAX)).unwrap().0 {
+        Expression::Iterate { count, .. } => count,
+        _ => None,
+    });
 }
 
 #[test]
@@ -123,12 +1247,12 @@ fn test_complex_expression() {
     let raw_expression = "iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )";
     let expected_expression =
       Expression::Chained(Box::new(
-        Expression::Iterate(Box::new(
+        Expression::Iterate { count: None, body: Box::new(
             Expression::Chained(
                 Box::new(Expression::Translation { u: 12.0, v: 0.4 }),
                 Box::new(Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 })
             )
-        ))),
+        )}),
         Box::new(Expression::Translation { u: 8.0, v: 15.0 }),
       );
     let (_, parsed_expression) = expression(raw_expression).unwrap();
@@ -146,14 +1270,545 @@ translation( 8.0, 15.0 )
 ";
     let expected_expression =
       Expression::Chained(Box::new(
-        Expression::Iterate(Box::new(
+        Expression::Iterate { count: None, body: Box::new(
             Expression::Chained(
                 Box::new(Expression::Translation { u: 12.0, v: 0.4 }),
                 Box::new(Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 })
             )
-        ))),
+        )}),
         Box::new(Expression::Translation { u: 8.0, v: 15.0 }),
       );
     let (_, parsed_expression) = expression(raw_expression).unwrap();
     assert_eq!(expected_expression, parsed_expression);
 }
+
+#[test]
+fn test_error_line_and_column() {
+    let raw_expression =
+r"iter(
+    translation(12.0, 0.4) rotation(0.2, 0.3, 0.5)
+);
+translation( 8.0, 15.0 )
+";
+    let error = parse_expression(raw_expression).unwrap_err();
+    assert_eq!("expected `;`", error.message);
+    assert_eq!((2, 28), (error.line, error.column));
+
+    let error = parse_expression("translation(1, 2);\n# ünïcödé\nrotation(0, 0, ö)").unwrap_err();
+    assert_eq!((3, 16), (error.line, error.column));
+    let error = parse_expression("translation(1, 2); /* éé */ rotation(0, 0, ö)").unwrap_err();
+    assert_eq!((1, 44, 45), (error.line, error.column, error.offset));
+}
+
+#[test]
+fn test_program() {
+    let raw_program = "init (0.0, 0.0); translation(1, 2)";
+    let expected_program = Program {
+        init: Point { x: 0.0, y: 0.0 },
+        body: Expression::Translation { u: 1.0, v: 2.0 },
+    };
+    let (_, parsed_program) = program(raw_program).unwrap();
+    assert_eq!(expected_program, parsed_program);
+
+    let raw_program =
+r"init( -1.5, 3 );
+iter[4](
+    translation(12.0, 0.4);
+    rotation(0.2, 0.3, 0.5)
+);
+translation( 8.0, 15.0 )
+";
+    let expected_program = Program {
+        init: Point { x: -1.5, y: 3.0 },
+        body: Expression::Chained(Box::new(
+            Expression::Iterate { count: Some(4), body: Box::new(
+                Expression::Chained(
+                    Box::new(Expression::Translation { u: 12.0, v: 0.4 }),
+                    Box::new(Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 })
+                )
+            )}),
+            Box::new(Expression::Translation { u: 8.0, v: 15.0 }),
+        ),
+    };
+    let (_, parsed_program) = program(raw_program).unwrap();
+    assert_eq!(expected_program, parsed_program);
+
+    assert!(program("translation(1, 2)").is_err());
+}
+
+#[test]
+fn test_parse_errors() {
+    let error = parse_expression("translation(1, )").unwrap_err();
+    assert_eq!(15, error.offset);
+    assert_eq!(")", error.unexpected);
+    assert_eq!("expected a number", error.message);
+
+    let error = parse_expression("rotat(1,2,3)").unwrap_err();
+    assert_eq!(0, error.offset);
+    assert_eq!("rotat", error.unexpected);
+    assert_eq!("undefined name `rotat`", error.message);
+
+    let error = parse_expression("or(1,2,3)").unwrap_err();
+    assert_eq!(EXPECTED_STATEMENT, error.message);
+
+    let error = parse_expression("translation(1, 2); rotat(1,2,3)").unwrap_err();
+    assert_eq!(19, error.offset);
+    assert_eq!("rotat", error.unexpected);
+
+    let error = parse_expression("iter(translation(1, 2) rotation(0, 0, 1))").unwrap_err();
+    assert_eq!(23, error.offset);
+    assert_eq!("expected `;`", error.message);
+
+    let error = parse_expression("translation(1, 2))").unwrap_err();
+    assert_eq!(17, error.offset);
+    assert_eq!("unmatched `)`", error.message);
+
+    assert!(parse_expression("  translation(1, 2) \n").is_ok());
+}
+
+
+#[test]
+fn test_comments() {
+    let raw_expression =
+r"# a square-ish walk
+iter[4]( /* one side */ translation(1, 0) ; # then turn
+    rotation(0, 0, 1.5) /* about the origin */ );
+translation( 8.0, /* y */ 15.0 ) # trailing comment";
+    let expected_expression =
+      Expression::Chained(Box::new(
+        Expression::Iterate { count: Some(4), body: Box::new(
+            Expression::Chained(
+                Box::new(Expression::Translation { u: 1.0, v: 0.0 }),
+                Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 1.5 })
+            )
+        )}),
+        Box::new(Expression::Translation { u: 8.0, v: 15.0 }),
+      );
+    assert_eq!(expected_expression, parse_expression(raw_expression).unwrap().0);
+    assert_eq!(Expression::Translation { u: 1.0, v: 2.0 }, parse_expression("translation(1, 2) /* done */\n# bye\n").unwrap().0);
+
+    let error = parse_expression("translation(1, 2); /* never closed").unwrap_err();
+    assert_eq!(19, error.offset);
+    assert_eq!("unterminated comment", error.message);
+}
+
+#[test]
+fn test_unclosed_delimiters() {
+    let error = parse_expression("iter( translation(1,2)").unwrap_err();
+    assert_eq!(22, error.offset);
+    assert_eq!("unclosed `(` opened at offset 4", error.message);
+
+    let error = parse_expression("iter( iter( translation(1,2) )").unwrap_err();
+    assert_eq!("unclosed `(` opened at offset 4", error.message);
+
+    let error = parse_expression("translation(1, 2").unwrap_err();
+    assert_eq!("unclosed `(` opened at offset 11", error.message);
+
+    let error = parse_expression("{ translation(1,2) or { rotation(0,0,1) }").unwrap_err();
+    assert_eq!("expected `;`", error.message);
+
+    let error = parse_expression("{ translation(1,2); rotation(0,0,1) ").unwrap_err();
+    assert_eq!("unclosed `{` opened at offset 0", error.message);
+
+    let error = parse_expression("{ iter(translation(1,2) } or { rotation(0,0,1) }").unwrap_err();
+    assert_eq!(24, error.offset);
+    assert_eq!("unclosed `(` opened at offset 6", error.message);
+
+    let error = parse_expression("translation(1,2) }").unwrap_err();
+    assert_eq!(17, error.offset);
+    assert_eq!("unmatched `}`", error.message);
+}
+
+#[test]
+fn test_nesting_limit() {
+    let depth = 10_000;
+    let raw_expression = format!("{}translation(1, 0){}", "iter(".repeat(depth), ")".repeat(depth));
+    let error = parse_expression(&raw_expression).unwrap_err();
+    assert_eq!(ParseErrorKind::TooDeep { depth: 128 }, error.kind);
+    assert_eq!(129 * "iter(".len(), error.offset);
+
+    let raw_expression = format!("{}translation(1, 0){}", "{ ".repeat(4), " } or { rotation(0, 0, 1) }".repeat(4));
+    let config = ParserConfig { max_depth: 3, ..ParserConfig::default() };
+    assert_eq!(ParseErrorKind::TooDeep { depth: 3 }, parse_expression_with(&raw_expression, &config).unwrap_err().kind);
+    let config = ParserConfig { max_depth: 4, ..ParserConfig::default() };
+    assert!(parse_expression_with(&raw_expression, &config).is_ok());
+
+    // the default depth fits the stack of a test thread for every kind of
+    // nesting, even unoptimized
+    let raw_expression = format!("{}translation(1, 0){}", "iter(".repeat(128), ")".repeat(128));
+    assert_eq!(129, parse_expression(&raw_expression).unwrap().0.depth());
+    let raw_expression = format!("{}translation(1, 0){}", "rev(".repeat(128), ")".repeat(128));
+    assert!(parse_expression(&raw_expression).is_ok());
+    let raw_expression = format!("{}translation(1, 0){}", "{ ".repeat(128), " } or { id }".repeat(128));
+    assert!(parse_expression(&raw_expression).is_ok());
+    let raw_expression = format!("{}translation(1, 0){}", "iter(let a = ".repeat(128), "; a)".repeat(128));
+    assert!(parse_expression(&raw_expression).is_ok());
+
+    // long chains are not nesting
+    let raw_expression = vec!["translation(1, 0)"; 10_000].join("; ");
+    assert!(parse_expression(&raw_expression).is_ok());
+}
+
+#[test]
+fn test_empty_statements() {
+    let chain = Expression::Chained(
+        Box::new(Expression::Translation { u: 1.0, v: 2.0 }),
+        Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 }),
+    );
+    assert_eq!(Expression::Translation { u: 1.0, v: 2.0 }, parse_expression("translation(1,2);").unwrap().0);
+    assert_eq!(chain, parse_expression("translation(1,2);;rotation(0,0,1)").unwrap().0);
+    assert_eq!(chain, parse_expression("; translation(1,2) ; ; rotation(0,0,1) ;\n").unwrap().0);
+
+    let iterate = |body| Expression::Iterate { count: Some(2), body: Box::new(body) };
+    assert_eq!(iterate(chain.clone()), parse_expression("iter[2](translation(1,2);;rotation(0,0,1);)").unwrap().0);
+    assert_eq!(iterate(Expression::Translation { u: 1.0, v: 2.0 }), parse_expression("iter[2]( translation(1,2); )").unwrap().0);
+    let raw_expression = "{ translation(1,2); } or { ;rotation(0,0,1) }; ";
+    let expected_expression = Expression::EitherOr {
+        left: Box::new(Expression::Translation { u: 1.0, v: 2.0 }),
+        right: Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 }),
+        weights: (0.5, 0.5),
+    };
+    assert_eq!(expected_expression, parse_expression(raw_expression).unwrap().0);
+
+    let config = ParserConfig::default();
+    let (_, warnings) = parse_expression_with("translation(1,2); rotation(0,0,1);", &config).unwrap();
+    assert!(warnings.is_empty());
+    let (parsed_expression, warnings) = parse_expression_with("; translation(1,2) ;; iter[2]( rotation(0,0,1); ; );", &config).unwrap();
+    assert_eq!(parse_expression("translation(1,2); iter[2](rotation(0,0,1))").unwrap().0, parsed_expression);
+    let expected_warnings = vec![
+        Warning { offset: 0, message: "empty statement".to_string() },
+        Warning { offset: 20, message: "empty statement".to_string() },
+        Warning { offset: 48, message: "empty statement".to_string() },
+    ];
+    assert_eq!(expected_warnings, warnings);
+
+    // a `;` left out before a statement is only warned about if asked for
+    let raw_expression = "translation(1,2) iter[2](rotation(0,0,1) { id } or { spin(1) })";
+    assert_eq!("expected `;`", parse_expression_with(raw_expression, &config).unwrap_err().message);
+    let config = ParserConfig { recover_missing_separator: true, ..ParserConfig::default() };
+    let (parsed_expression, warnings) = parse_expression_with(raw_expression, &config).unwrap();
+    assert_eq!(parse_expression("translation(1,2); iter[2](rotation(0,0,1); { id } or { spin(1) })").unwrap().0, parsed_expression);
+    let expected_warnings = vec![
+        Warning { offset: 17, message: "missing `;`".to_string() },
+        Warning { offset: 41, message: "missing `;`".to_string() },
+    ];
+    assert_eq!(expected_warnings, warnings);
+    assert_eq!("expected `;`", parse_expression_with("translation(1,2) 3", &config).unwrap_err().message);
+
+    let error = parse_expression(";;").unwrap_err();
+    assert_eq!(EXPECTED_STATEMENT, error.message);
+    let error = parse_expression("iter( ; )").unwrap_err();
+    assert_eq!(EXPECTED_STATEMENT, error.message);
+    assert_eq!(8, error.offset);
+}
+
+#[test]
+fn test_definitions() {
+    let rotation = || Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 0.1 });
+    let expected_expression = Expression::Chained(Box::new(Expression::Iterate { count: Some(3), body: rotation() }), rotation());
+    assert_eq!(expected_expression, parse_expression("let a = rotation(0,0,0.1); iter[3](a); a").unwrap().0);
+
+    let raw_expression = "let step = translation(1, 0); let square = iter[4](step; rotation(0, 0, 90deg)); { square } or { step }";
+    let expected_expression = parse_expression(
+        "{ iter[4](translation(1, 0); rotation(0, 0, 90deg)) } or { translation(1, 0) }"
+    ).unwrap().0;
+    assert_eq!(expected_expression, parse_expression(raw_expression).unwrap().0);
+    let raw_expression = "let iterations = translation(1, 0); let a = rotation(0, 0, 1); let a = iter(a); iterations; a";
+    let expected_expression = parse_expression("translation(1, 0); iter(rotation(0, 0, 1))").unwrap().0;
+    assert_eq!(expected_expression, parse_expression(raw_expression).unwrap().0);
+
+    let error = parse_expression("translation(1, 0); b").unwrap_err();
+    assert_eq!(19, error.offset);
+    assert_eq!("undefined name `b`", error.message);
+    assert_eq!(ParseErrorKind::UndefinedName { name: "b".to_string() }, error.kind);
+
+    // definitions end with their group and can't refer to themselves
+    let error = parse_expression("{ let a = translation(1, 0); a } or { a }").unwrap_err();
+    assert_eq!(38, error.offset);
+    let error = parse_expression("let a = iter(a); a").unwrap_err();
+    assert_eq!(13, error.offset);
+    assert_eq!("`a` is defined in terms of itself", error.message);
+    assert_eq!(ParseErrorKind::CyclicReference { name: "a".to_string() }, error.kind);
+    let error = parse_expression("let a = iter(b); b").unwrap_err();
+    assert_eq!(ParseErrorKind::UndefinedName { name: "b".to_string() }, error.kind);
+
+    let error = parse_expression("let iter = translation(1, 0); iter").unwrap_err();
+    assert_eq!("`iter` is a keyword", error.message);
+    let error = parse_expression("let a translation(1, 0); a").unwrap_err();
+    assert_eq!("expected `=`", error.message);
+    let error = parse_expression("let a = translation(1, 0);").unwrap_err();
+    assert_eq!(EXPECTED_STATEMENT, error.message);
+    assert_eq!(26, error.offset);
+}
+
+#[test]
+fn test_statement_iter() {
+    let raw_expression =
+r"iter[4]( translation(1, 0); rotation(0, 0, 90deg) );  # one; two
+let up = translation(0, 1);
+{ up; up } or { /* ; */ scale(0, 0, 2, 2) };
+;
+up";
+    let parsed: Vec<Expression> = statements(raw_expression).collect::<Result<_, _>>().unwrap();
+    assert_eq!(3, parsed.len());
+    let expected_expression = parse_expression(raw_expression).unwrap().0;
+    let chained = parsed.into_iter().rev().reduce(|rest, statement| Expression::Chained(Box::new(statement), Box::new(rest))).unwrap();
+    assert_eq!(expected_expression, chained);
+
+    assert_eq!(0, statements(" ; # nothing\n").count());
+
+    let mut parsed = statements("translation(1, 0); rotat(0, 0, 1); translation(1, 2) translation(3, 4); translation(0, 1)");
+    assert_eq!(Some(Ok(Expression::Translation { u: 1.0, v: 0.0 })), parsed.next());
+    assert_eq!(19, parsed.next().unwrap().unwrap_err().offset);
+    let error = parsed.next().unwrap().unwrap_err();
+    assert_eq!(53, error.offset);
+    assert_eq!("expected `;`", error.message);
+    assert_eq!(Some(Ok(Expression::Translation { u: 0.0, v: 1.0 })), parsed.next());
+    assert_eq!(None, parsed.next());
+}
+
+#[test]
+fn test_weighted_choice() {
+    let translation = || Box::new(Expression::Translation { u: 1.0, v: 0.0 });
+    let rotation = || Box::new(Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 });
+    let choice = |weights| Expression::EitherOr { left: translation(), right: rotation(), weights };
+
+    assert_eq!(choice((0.5, 0.5)), parse_expression("{ translation(1, 0) } or { rotation(0, 0, 1) }").unwrap().0);
+    assert_eq!(choice((0.7, 0.3)), parse_expression("{ translation(1, 0) }:0.7 or { rotation(0, 0, 1) }:0.3").unwrap().0);
+    assert_eq!(choice((2.0, 0.5)), parse_expression("{ translation(1, 0) } : 2 or { rotation(0, 0, 1) }").unwrap().0);
+    assert_eq!(choice((0.5, 0.0)), parse_expression("{ translation(1, 0) } or { rotation(0, 0, 1) }:0").unwrap().0);
+
+    let error = parse_expression("{ translation(1, 0) }:-1 or { rotation(0, 0, 1) }").unwrap_err();
+    assert_eq!((ParseErrorKind::InvalidWeight, 22), (error.kind, error.offset));
+    let error = parse_expression("{ translation(1, 0) }:0 or { rotation(0, 0, 1) }:0").unwrap_err();
+    assert_eq!((ParseErrorKind::InvalidWeight, 0), (error.kind, error.offset));
+    let error = parse_expression("{ translation(1, 0) }: or { rotation(0, 0, 1) }").unwrap_err();
+    assert_eq!(("expected a number", 23), (error.message.as_str(), error.offset));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_parse_json() {
+    let (_, parsed_program) = program("init(-1, 0.5); iter[2]({ translation(1, 0) } or { rev(rotation(0, 0, 1); id) }); affine(1, 2, 3, 4, 5, 6)").unwrap();
+    let json = serde_json::to_string(&parsed_program).unwrap();
+    assert_eq!(parsed_program, parse_json(&json).unwrap());
+
+    let json = "{\"init\": {\"x\": 0, \"y\": 0},\n \"body\": {\"type\": \"Shear\", \"k\": 2}}";
+    let error = parse_json(json).unwrap_err();
+    assert!(error.message.starts_with("unknown variant `Shear`, expected one of"), "{}", error.message);
+    assert_eq!(2, error.line);
+
+    assert!(parse_json("{\"init\": {\"x\": 1e400, \"y\": 0}, \"body\": {\"type\": \"Identity\"}}").is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_parse_program_reader() {
+    use std::io::Cursor;
+
+    let raw_program =
+r"init(1, -1);
+iter(
+    translation(12.0, 0.4);
+    rotation(0.2, 0.3, 0.5)
+);
+translation( 8.0, 15.0 )
+";
+    let parsed_program = parse_program_reader(Cursor::new(raw_program.as_bytes())).unwrap();
+    assert_eq!(parse_program(raw_program).unwrap(), parsed_program);
+    assert_eq!(Point { x: 1.0, y: -1.0 }, parsed_program.init);
+
+    let mut bytes = b"init(0, 0);\ntranslation(1, ".to_vec();
+    bytes.extend([0xff, b'2', b')']);
+    let error = parse_program_reader(Cursor::new(bytes)).unwrap_err();
+    assert_eq!(ParseErrorKind::InvalidUtf8, error.kind);
+    assert_eq!((27, 2, 16), (error.offset, error.line, error.column));
+    assert_eq!("byte 0xff", error.unexpected);
+
+    let error = parse_program_reader(Cursor::new("init(0, 0); translation(1, 2) )")).unwrap_err();
+    assert_eq!("unmatched `)`", error.message);
+}
+
+#[test]
+fn test_parse_program_lenient() {
+    let raw_program = "init(1, 2); translation(1, 0); rotation(0, 0, x); \n scale(0, 0, 2, 2)";
+    let (parsed_program, errors) = parse_program_lenient(raw_program);
+    assert_eq!(parse_program("init(1, 2); translation(1, 0); scale(0, 0, 2, 2)").ok(), parsed_program);
+    assert_eq!(1, errors.len());
+    assert_eq!((46, "expected a number"), (errors[0].offset, errors[0].message.as_str()));
+
+    let raw_program = "init(0, 0); let a = translation(1, 0); b; iter[2](a) ); a";
+    let (parsed_program, errors) = parse_program_lenient(raw_program);
+    assert_eq!(parse_program("init(0, 0); translation(1, 0)").ok(), parsed_program);
+    let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+    assert_eq!(vec!["undefined name `b`", "unmatched `)`"], messages);
+
+    let (parsed_program, errors) = parse_program_lenient("init(0); translation(1, 0)");
+    assert_eq!(None, parsed_program);
+    assert_eq!(1, errors.len());
+    let (parsed_program, errors) = parse_program_lenient("init(0, 0); translation(1, 0); iter(rotation(0, 0, 1)); id");
+    assert_eq!(parse_program("init(0, 0); translation(1, 0); iter(rotation(0, 0, 1)); id").ok(), parsed_program);
+    assert!(errors.is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_from_str() -> Result<(), Box<dyn std::error::Error>> {
+    let expr: Expression = "translation(1,2); iter[2](rotation(0, 0, 90deg))".parse()?;
+    assert_eq!(parse_expression("translation(1, 2); iter[2](rotation(0, 0, 90deg))")?.0, expr);
+    let parsed_program: Program = "init(1, -1);\ntranslation(1,2)".parse()?;
+    assert_eq!(Program { init: Point { x: 1.0, y: -1.0 }, body: Expression::Translation { u: 1.0, v: 2.0 } }, parsed_program);
+
+    let error = "translation(1, x)".parse::<Expression>().unwrap_err();
+    assert_eq!("expected a number at 1:16", error.to_string());
+    assert!("translation(1, 2)".parse::<Program>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_many() {
+    let raw_programs = "init(0, 0); translation(1, 0)\n---\n\n  \n---\ninit(1, 1);\n  rotation(0, 0, 1)\n---  \ninit(2, 2); iter[2](scale(0, 0, 2, 2))\n";
+    let programs = parse_many(raw_programs).unwrap();
+    let expected_programs = vec![
+        parse_program("init(0, 0); translation(1, 0)").unwrap(),
+        parse_program("init(1, 1); rotation(0, 0, 1)").unwrap(),
+        parse_program("init(2, 2); iter[2](scale(0, 0, 2, 2))").unwrap(),
+    ];
+    assert_eq!(expected_programs, programs);
+    assert_eq!(Ok(vec![]), parse_many("\n---\n"));
+
+    let raw_programs = "init(0, 0); id\n---\n---\ninit(1, 1); id\n---\ninit(2, 2);\ntranslation(1, x)\n---\ninit(3, 3); id";
+    let error = parse_many(raw_programs).unwrap_err();
+    assert_eq!(Some(2), error.chunk);
+    assert_eq!("expected a number", error.message);
+    assert_eq!((7, 16), (error.line, error.column));
+    assert_eq!(raw_programs.find('x').unwrap(), error.offset);
+
+    let error = parse_many("init(0, 0); id\n---\ninit(1, 1); translation(1, 2").unwrap_err();
+    assert_eq!((Some(1), "end of input"), (error.chunk, error.unexpected.as_str()));
+}
+
+#[test]
+fn test_parse_with_trivia() {
+    let raw_program = "# a square\ninit(0, 0);\niter[4](translation(1, 0); /* then turn */ rotation(0, 0, 90deg))";
+    let (parsed_program, trivia) = parse_with_trivia(raw_program).unwrap();
+    assert_eq!(parse_program(raw_program).unwrap(), parsed_program);
+    let expected_trivia = vec![
+        Trivia { offset: 0, text: "# a square".to_string() },
+        Trivia { offset: 50, text: "/* then turn */".to_string() },
+    ];
+    assert_eq!(expected_trivia, trivia);
+    assert_eq!("/*", &raw_program[50..52]);
+
+    let (_, trivia) = parse_with_trivia("init(0, 0); translation(1, 2) # done").unwrap();
+    assert_eq!(vec![Trivia { offset: 30, text: "# done".to_string() }], trivia);
+    assert!(parse_with_trivia("init(0, 0); translation(1, 2) /* open").is_err());
+}
+
+#[test]
+fn test_method_chaining() {
+    let dotted = parse_expression("translation(1, 0).rotation(0, 0, 1)").unwrap().0;
+    assert_eq!(parse_expression("translation(1, 0); rotation(0, 0, 1)").unwrap().0, dotted);
+
+    let raw_expression = "translation(1.5, .5) . iter[2](scale(0, 0, 0.5, 0.5).id).{ spin(1) } or { rev(id; id) }; translation(2, 2).a";
+    let error = parse_expression(raw_expression).unwrap_err();
+    assert_eq!(ParseErrorKind::UndefinedName { name: "a".to_string() }, error.kind);
+    let dotted = parse_expression(&format!("let a = reflection(0, 0, 1).id; {}", raw_expression)).unwrap().0;
+    let expected_expression = parse_expression(
+        "translation(1.5, 0.5); iter[2](scale(0, 0, 0.5, 0.5); id); { spin(1) } or { rev(id; id) }; translation(2, 2); reflection(0, 0, 1); id"
+    ).unwrap().0;
+    assert_eq!(expected_expression, dotted);
+
+    let parts: Vec<Expression> = statements("translation(1, 0).id; rotation(0, 0, 1)").collect::<Result<_, _>>().unwrap();
+    let expected_parts = vec![Expression::Translation { u: 1.0, v: 0.0 }, Expression::Identity, Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 }];
+    assert_eq!(expected_parts, parts);
+
+    let error = parse_expression("translation(1, 0).").unwrap_err();
+    assert_eq!((EXPECTED_STATEMENT, 18), (error.message.as_str(), error.offset));
+    let error = parse_expression("translation(1, 0).5").unwrap_err();
+    assert_eq!(18, error.offset);
+}
+
+#[test]
+fn test_keyword_suggestions() {
+    let error = parse_expression("translatoin(1,2)").unwrap_err();
+    assert_eq!("undefined name `translatoin`; did you mean `translation`?", error.message);
+    assert_eq!(ParseErrorKind::UndefinedName { name: "translatoin".to_string() }, error.kind);
+    let error = parse_expression("translation(1, 0); rotatión(0, 0, 1)").unwrap_err();
+    assert_eq!((19, "undefined name `rotati`; did you mean `rotation`?"), (error.offset, error.message.as_str()));
+    assert!(parse_expression("iter[2](reflect(0, 0, 1))").unwrap_err().message.ends_with("did you mean `reflection`?"));
+
+    // names far from every keyword, or defined, get no suggestion
+    assert_eq!("undefined name `step`", parse_expression("step").unwrap_err().message);
+    assert!(parse_expression("let rotatin = id; rotatin").is_ok());
+
+    assert_eq!(2, levenshtein("translatoin", "translation"));
+    assert_eq!(1, levenshtein("ñ", "n"));
+    assert_eq!(3, levenshtein("", "rev"));
+}
+
+#[test]
+fn test_grammar() {
+    // the quoted words of the grammar
+    let mut words: Vec<&str> = GRAMMAR.split('"').skip(1).step_by(2).filter(|word| word.chars().all(|c| c.is_ascii_alphabetic()) && word.len() > 1).collect();
+    words.sort_unstable();
+    words.dedup();
+    let examples = [
+        ("translation", "translation(1, 2)"),
+        ("rotation", "rotation(0, 0, 90deg)"),
+        ("scale", "scale(0, 0, 2, 0.5)"),
+        ("reflection", "reflection(1, 1, 0.25rad)"),
+        ("affine", "affine(1, 0, 2, 0, 1, 3)"),
+        ("spin", "spin(1)"),
+        ("id", "id"),
+        ("identity", "identity"),
+        ("iter", "iter[3](translation(1, 0))"),
+        ("repeat", "repeat(translation(1, 0))"),
+        ("rev", "rev(translation(1, 0); spin(1))"),
+        ("or", "{ id }:0.25 or { spin(1) }"),
+        ("let", "let step = translation(1, 0); step.step"),
+        ("deg", "spin(1deg)"),
+        ("rad", "spin(1rad)"),
+        ("init", "init(0, 0); id"),
+    ];
+    let mut documented: Vec<&str> = examples.iter().map(|(word, _)| *word).collect();
+    documented.sort_unstable();
+    assert_eq!(documented, words);
+    for (word, example) in examples {
+        let parsed = if word == "init" { parse_program(example).map(|_| ()) } else { parse_expression(example).map(|_| ()) };
+        assert!(parsed.is_ok(), "`{}` in {:?}: {:?}", word, example, parsed);
+    }
+    for keyword in KEYWORDS {
+        assert!(words.contains(&keyword), "`{}` is missing from the grammar", keyword);
+    }
+}
+
+#[test]
+fn test_newline_as_separator() {
+    let config = ParserConfig { newline_as_separator: true, ..ParserConfig::default() };
+    let raw_expression = "
+        let step = translation(1, 0)
+        iter[3](
+            step
+            rotation(0, 0,
+                90deg)   # a comment before the line break
+        )
+        /* a block comment */ { scale(0, 0, 2, 2) }
+            or { id }; translation(0, 1)
+        .rotation(1, 1, 0.5)
+    ";
+    let expected_expression = parse_expression("iter[3](translation(1, 0); rotation(0, 0, 90deg)); { scale(0, 0, 2, 2) } or { id }; translation(0, 1); rotation(1, 1, 0.5)").unwrap().0;
+    assert_eq!(expected_expression, parse_expression_with(raw_expression, &config).unwrap().0);
+
+    // without the flag, line breaks are just whitespace
+    let error = parse_expression("translation(1, 0)\nrotation(0, 0, 1)").unwrap_err();
+    assert_eq!(("expected `;`", 2), (error.message.as_str(), error.line));
+    let error = parse_expression_with("translation(1, 0) rotation(0, 0, 1)", &config).unwrap_err();
+    assert_eq!("expected `;`", error.message);
+
+    // only line breaks outside comments separate
+    let error = parse_expression_with("translation(1, 0) /* a\nb */ translation(2, 0)", &config).unwrap_err();
+    assert_eq!(("expected `;`", 2), (error.message.as_str(), error.line));
+    let expected_expression = parse_expression("translation(1, 0); translation(2, 0)").unwrap().0;
+    assert_eq!(expected_expression, parse_expression_with("translation(1, 0) /* a */\n/* b */ translation(2, 0)", &config).unwrap().0);
+}
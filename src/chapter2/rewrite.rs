@@ -0,0 +1,569 @@
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::f64::consts::{PI, TAU};
+
+use super::affine::Matrix3;
+use super::float;
+use super::language::*;
+
+impl Expression {
+    /// The expression undoing this one, if there is one. Choices, degenerate
+    /// scalings and unresolved references cannot be undone.
+    pub fn inverse(&self) -> Option<Expression> {
+        match self {
+            Expression::Translation { u, v } => Some(Expression::Translation { u: -u, v: -v }),
+            Expression::Rotation { u, v, theta } => Some(Expression::Rotation { u: *u, v: *v, theta: -theta }),
+            Expression::Scale { u, v, sx, sy } if *sx != 0.0 && *sy != 0.0 => {
+                Some(Expression::Scale { u: *u, v: *v, sx: 1.0 / sx, sy: 1.0 / sy })
+            }
+            Expression::Scale { .. } => None,
+            Expression::Reflection { .. } | Expression::Identity => Some(self.clone()),
+            Expression::Spin { theta } => Some(Expression::Spin { theta: -theta }),
+            Expression::Affine(m) => Some(Expression::Affine(m.inverse()?)),
+            Expression::Chained(first, second) => {
+                Some(Expression::Chained(Box::new(second.inverse()?), Box::new(first.inverse()?)))
+            }
+            Expression::Sequence(statements) => Some(Expression::Sequence(statements.iter().rev().map(Expression::inverse).collect::<Option<_>>()?)),
+            Expression::EitherOr { .. } | Expression::Reference(_) => None,
+            Expression::Iterate { count, body } => Some(Expression::Iterate { count: *count, body: Box::new(body.inverse()?) }),
+            // the inverse runs the inverted statements first to last, which
+            // is the body's inverse reversed
+            Expression::Reverse(body) => Some(Expression::Reverse(Box::new(body.inverse()?))),
+        }
+    }
+
+    /// An equivalent expression with adjacent translations folded together
+    /// and no-op steps (identities, zero translations and rotations,
+    /// zero-count iterations) removed. Steps are never reordered, since translations
+    /// and rotations don't commute. An expression that does nothing at all
+    /// simplifies to `id`.
+    pub fn simplify(&self) -> Expression {
+        chain(self.simplified_statements()).unwrap_or(Expression::Identity)
+    }
+
+    /// The simplified statements of a chain, in order; empty when the whole
+    /// expression is a no-op.
+    fn simplified_statements(&self) -> Vec<Expression> {
+        match self {
+            Expression::Chained(first, second) => {
+                let mut statements = first.simplified_statements();
+                append_simplified(&mut statements, second.simplified_statements());
+                statements
+            }
+            Expression::Sequence(parts) => {
+                let mut statements = Vec::new();
+                for part in parts {
+                    append_simplified(&mut statements, part.simplified_statements());
+                }
+                statements
+            }
+            Expression::Reverse(body) => {
+                let mut statements = Vec::new();
+                for part in body.chain_statements().into_iter().rev() {
+                    append_simplified(&mut statements, part.simplified_statements());
+                }
+                statements
+            }
+            Expression::Translation { u, v } if *u == 0.0 && *v == 0.0 => vec![],
+            Expression::Rotation { theta, .. } if *theta == 0.0 => vec![],
+            Expression::Identity => vec![],
+            Expression::Spin { theta } if *theta == 0.0 => vec![],
+            Expression::Iterate { count: Some(0), .. } => vec![],
+            Expression::Iterate { count, body } => match chain(body.simplified_statements()) {
+                Some(body) => vec![Expression::Iterate { count: *count, body: Box::new(body) }],
+                None => vec![],
+            },
+            Expression::EitherOr { left, right, weights } => vec![Expression::EitherOr {
+                left: Box::new(left.simplify()),
+                right: Box::new(right.simplify()),
+                weights: *weights,
+            }],
+            _ => vec![self.clone()],
+        }
+    }
+
+    /// The expression with every chain and iteration free of choices and
+    /// references replaced by the single `Affine` map it amounts to, so it
+    /// evaluates in one step. Folding stops at `EitherOr` boundaries, inside
+    /// which chains are folded separately. Lone leaves are kept as they are.
+    pub fn fold_constants(&self) -> Expression {
+        if let Expression::Chained(..) | Expression::Sequence(_) | Expression::Iterate { .. } | Expression::Reverse(_) = self {
+            if let Some(m) = self.constant_matrix() {
+                return Expression::Affine(m);
+            }
+        }
+        match self {
+            Expression::Chained(first, second) => Expression::Chained(Box::new(first.fold_constants()), Box::new(second.fold_constants())),
+            Expression::Sequence(statements) => Expression::Sequence(statements.iter().map(Expression::fold_constants).collect()),
+            Expression::EitherOr { left, right, weights } => Expression::EitherOr {
+                left: Box::new(left.fold_constants()),
+                right: Box::new(right.fold_constants()),
+                weights: *weights,
+            },
+            Expression::Iterate { count, body } => Expression::Iterate { count: *count, body: Box::new(body.fold_constants()) },
+            Expression::Reverse(body) => Expression::Reverse(Box::new(body.fold_constants())),
+            _ => self.clone(),
+        }
+    }
+
+    /// Like `to_affine`, but also folding iterations, which apply their body
+    /// `count` times (once without a count) as `evaluate` does.
+    pub(crate) fn constant_matrix(&self) -> Option<Matrix3> {
+        match self {
+            Expression::Chained(first, second) => Some(second.constant_matrix()? * first.constant_matrix()?),
+            Expression::Sequence(statements) => {
+                statements.iter().try_fold(Matrix3::identity(), |m, statement| Some(statement.constant_matrix()? * m))
+            }
+            Expression::Iterate { count, body } => Some(body.constant_matrix()?.pow(count.unwrap_or(1))),
+            Expression::Reverse(body) => body.chain_statements().into_iter().rev()
+                .try_fold(Matrix3::identity(), |m, statement| Some(statement.constant_matrix()? * m)),
+            _ => self.to_affine(),
+        }
+    }
+
+    /// The expression with every chain, however nested, replaced by a
+    /// `Sequence` of the statements it runs, in order. Sequences are
+    /// flattened the same way, so no sequence directly contains another.
+    pub fn flatten_chains(&self) -> Expression {
+        match self {
+            Expression::Chained(..) | Expression::Sequence(_) => {
+                let mut statements = Vec::new();
+                self.push_flattened(&mut statements);
+                Expression::Sequence(statements)
+            }
+            Expression::EitherOr { left, right, weights } => Expression::EitherOr {
+                left: Box::new(left.flatten_chains()),
+                right: Box::new(right.flatten_chains()),
+                weights: *weights,
+            },
+            Expression::Iterate { count, body } => Expression::Iterate { count: *count, body: Box::new(body.flatten_chains()) },
+            Expression::Reverse(body) => Expression::Reverse(Box::new(body.flatten_chains())),
+            _ => self.clone(),
+        }
+    }
+
+    fn push_flattened(&self, statements: &mut Vec<Expression>) {
+        match self {
+            Expression::Chained(first, second) => {
+                first.push_flattened(statements);
+                second.push_flattened(statements);
+            }
+            Expression::Sequence(parts) => {
+                for part in parts {
+                    part.push_flattened(statements);
+                }
+            }
+            _ => statements.push(self.flatten_chains()),
+        }
+    }
+
+    /// The expression with every `Sequence` replaced by right-nested
+    /// `Chained`s, undoing `flatten_chains`. A sequence of one statement
+    /// becomes that statement, and an empty one `Identity`.
+    pub fn nest(&self) -> Expression {
+        match self {
+            Expression::Sequence(statements) => chain(statements.iter().map(Expression::nest).collect()).unwrap_or(Expression::Identity),
+            Expression::Chained(first, second) => Expression::Chained(Box::new(first.nest()), Box::new(second.nest())),
+            Expression::EitherOr { left, right, weights } => Expression::EitherOr {
+                left: Box::new(left.nest()),
+                right: Box::new(right.nest()),
+                weights: *weights,
+            },
+            Expression::Iterate { count, body } => Expression::Iterate { count: *count, body: Box::new(body.nest()) },
+            Expression::Reverse(body) => Expression::Reverse(Box::new(body.nest())),
+            _ => self.clone(),
+        }
+    }
+
+    /// The expression with every coordinate, angle, factor and matrix entry
+    /// rounded to `decimals` places, as by `Point::round_to`.
+    pub fn round_coords(&self, decimals: u32) -> Expression {
+        let round = |n: &f64| round_to(*n, decimals);
+        self.map_leaves(&|leaf| match leaf {
+            Expression::Translation { u, v } => Expression::Translation { u: round(u), v: round(v) },
+            Expression::Rotation { u, v, theta } => Expression::Rotation { u: round(u), v: round(v), theta: round(theta) },
+            Expression::Scale { u, v, sx, sy } => Expression::Scale { u: round(u), v: round(v), sx: round(sx), sy: round(sy) },
+            Expression::Reflection { u, v, theta } => Expression::Reflection { u: round(u), v: round(v), theta: round(theta) },
+            Expression::Spin { theta } => Expression::Spin { theta: round(theta) },
+            Expression::Affine(m) => Expression::Affine(Matrix3(m.0.map(|row| row.map(|n| round(&n))))),
+            leaf => leaf.clone(),
+        })
+    }
+
+    /// The expression with the offset of every translation and the factors
+    /// of every scaling multiplied by `factor`. Rotations, reflections and
+    /// the centers of scalings are left as they are.
+    pub fn scale_all(&self, factor: f64) -> Expression {
+        self.map_leaves(&|leaf| match leaf {
+            Expression::Translation { u, v } => Expression::Translation { u: u * factor, v: v * factor },
+            Expression::Scale { u, v, sx, sy } => Expression::Scale { u: *u, v: *v, sx: sx * factor, sy: sy * factor },
+            leaf => leaf.clone(),
+        })
+    }
+
+    /// The expression `t` of the way from `a` to `b`, which must have the
+    /// same shape: the same variants, counts and names throughout, and
+    /// sequences of the same length. Numbers are interpolated linearly, with
+    /// rotation and spin angles turning along the shorter arc, and
+    /// reflection angles likewise taking the line's nearer direction.
+    /// `None` if the shapes differ.
+    pub fn lerp(a: &Expression, b: &Expression, t: f64) -> Option<Expression> {
+        let mix = |x: &f64, y: &f64| x + (y - x) * t;
+        let arc = |x: &f64, y: &f64, period: f64| {
+            let turn = float::rem_euclid(y - x, period);
+            x + if turn > period / 2.0 { turn - period } else { turn } * t
+        };
+        let lerp_boxed = |a: &Expression, b: &Expression| Expression::lerp(a, b, t).map(Box::new);
+        let expr = match (a, b) {
+            (Expression::Translation { u, v }, Expression::Translation { u: u2, v: v2 }) => {
+                Expression::Translation { u: mix(u, u2), v: mix(v, v2) }
+            }
+            (Expression::Rotation { u, v, theta }, Expression::Rotation { u: u2, v: v2, theta: theta2 }) => {
+                Expression::Rotation { u: mix(u, u2), v: mix(v, v2), theta: arc(theta, theta2, TAU) }
+            }
+            (Expression::Scale { u, v, sx, sy }, Expression::Scale { u: u2, v: v2, sx: sx2, sy: sy2 }) => {
+                Expression::Scale { u: mix(u, u2), v: mix(v, v2), sx: mix(sx, sx2), sy: mix(sy, sy2) }
+            }
+            (Expression::Reflection { u, v, theta }, Expression::Reflection { u: u2, v: v2, theta: theta2 }) => {
+                Expression::Reflection { u: mix(u, u2), v: mix(v, v2), theta: arc(theta, theta2, PI) }
+            }
+            (Expression::Chained(first, second), Expression::Chained(first2, second2)) => {
+                Expression::Chained(lerp_boxed(first, first2)?, lerp_boxed(second, second2)?)
+            }
+            (Expression::Sequence(statements), Expression::Sequence(statements2)) if statements.len() == statements2.len() => {
+                Expression::Sequence(statements.iter().zip(statements2).map(|(a, b)| Expression::lerp(a, b, t)).collect::<Option<_>>()?)
+            }
+            (Expression::EitherOr { left, right, weights }, Expression::EitherOr { left: left2, right: right2, weights: weights2 }) => {
+                let weights = (mix(&weights.0, &weights2.0), mix(&weights.1, &weights2.1));
+                Expression::EitherOr { left: lerp_boxed(left, left2)?, right: lerp_boxed(right, right2)?, weights }
+            }
+            (Expression::Iterate { count, body }, Expression::Iterate { count: count2, body: body2 }) if count == count2 => {
+                Expression::Iterate { count: *count, body: lerp_boxed(body, body2)? }
+            }
+            (Expression::Reverse(body), Expression::Reverse(body2)) => Expression::Reverse(lerp_boxed(body, body2)?),
+            (Expression::Identity, Expression::Identity) => Expression::Identity,
+            (Expression::Spin { theta }, Expression::Spin { theta: theta2 }) => Expression::Spin { theta: arc(theta, theta2, TAU) },
+            (Expression::Affine(m), Expression::Affine(m2)) => {
+                let mut entries = m.0;
+                for (row, row2) in entries.iter_mut().zip(m2.0) {
+                    for (n, n2) in row.iter_mut().zip(row2) {
+                        *n = mix(n, &n2);
+                    }
+                }
+                Expression::Affine(Matrix3(entries))
+            }
+            (Expression::Reference(name), Expression::Reference(name2)) if name == name2 => a.clone(),
+            _ => return None,
+        };
+        Some(expr)
+    }
+
+    /// The expression with every rotation angle wrapped into `[0, 2π)`.
+    pub fn normalize_angles(&self) -> Expression {
+        self.map_leaves(&|leaf| match leaf {
+            Expression::Rotation { u, v, theta } => {
+                // `rem_euclid` rounds tiny negative angles up to exactly 2π
+                let theta = float::rem_euclid(*theta, TAU);
+                Expression::Rotation { u: *u, v: *v, theta: if theta < TAU { theta } else { 0.0 } }
+            }
+            leaf => leaf.clone(),
+        })
+    }
+
+    /// The expression with every rotation angle rounded to the nearest
+    /// multiple of `base`, which must be positive.
+    pub fn quantize_angles(&self, base: f64) -> Expression {
+        self.map_leaves(&|leaf| match leaf {
+            // adding zero turns `-0.0` into `0.0`
+            Expression::Rotation { u, v, theta } => Expression::Rotation { u: *u, v: *v, theta: float::round(theta / base) * base + 0.0 },
+            leaf => leaf.clone(),
+        })
+    }
+}
+
+impl Program {
+    /// The program with every rotation angle of the body snapped to the
+    /// nearest multiple of `base`, as by `Expression::quantize_angles`, then
+    /// wrapped into `[0, 2π)` by `normalize_angles`. When `base` divides a
+    /// full turn the wrapped angles remain multiples of it.
+    pub fn quantize_angles(&self, base: f64) -> Program {
+        Program { init: self.init, body: self.body.quantize_angles(base).normalize_angles() }
+    }
+
+    /// The program drawing the mirror image of this one across the vertical
+    /// line through `init`: every point's offset from `init` has its x
+    /// component negated.
+    pub fn flip_x(&self) -> Program {
+        Program { init: self.init, body: mirror(&self.body, self.init, true) }
+    }
+
+    /// Like `flip_x`, but across the horizontal line through `init`,
+    /// negating y components.
+    pub fn flip_y(&self) -> Program {
+        Program { init: self.init, body: mirror(&self.body, self.init, false) }
+    }
+}
+
+/// `expr` conjugated by the reflection across the line through `center`
+/// that is vertical if `negate_x`, and horizontal otherwise. Mirroring
+/// reverses every turn, so rotation, reflection and spin angles are negated.
+fn mirror(expr: &Expression, center: Point, negate_x: bool) -> Expression {
+    let point = |u: f64, v: f64| if negate_x { (2.0 * center.x - u, v) } else { (u, 2.0 * center.y - v) };
+    let mirror_matrix = if negate_x { Matrix3::scale(center.x, center.y, -1.0, 1.0) } else { Matrix3::scale(center.x, center.y, 1.0, -1.0) };
+    expr.map_leaves(&|leaf| match leaf {
+        Expression::Translation { u, v } => {
+            let (u, v) = if negate_x { (-u, *v) } else { (*u, -v) };
+            Expression::Translation { u, v }
+        }
+        Expression::Rotation { u, v, theta } => {
+            let (u, v) = point(*u, *v);
+            Expression::Rotation { u, v, theta: -theta }
+        }
+        Expression::Scale { u, v, sx, sy } => {
+            let (u, v) = point(*u, *v);
+            Expression::Scale { u, v, sx: *sx, sy: *sy }
+        }
+        Expression::Reflection { u, v, theta } => {
+            let (u, v) = point(*u, *v);
+            Expression::Reflection { u, v, theta: -theta }
+        }
+        Expression::Spin { theta } => Expression::Spin { theta: -theta },
+        Expression::Affine(m) => Expression::Affine(mirror_matrix * *m * mirror_matrix),
+        leaf => leaf.clone(),
+    })
+}
+
+/// Appends simplified `statements` to `simplified`, folding a translation
+/// into one that ends `simplified`.
+fn append_simplified(simplified: &mut Vec<Expression>, statements: Vec<Expression>) {
+    for statement in statements {
+        match (simplified.last_mut(), statement) {
+            (Some(Expression::Translation { u, v }), Expression::Translation { u: du, v: dv }) => {
+                *u += du;
+                *v += dv;
+                if *u == 0.0 && *v == 0.0 {
+                    simplified.pop();
+                }
+            }
+            (_, statement) => simplified.push(statement),
+        }
+    }
+}
+
+/// Right-nests `statements` into a chain, or `None` if there are none.
+pub(crate) fn chain(statements: Vec<Expression>) -> Option<Expression> {
+    statements.into_iter().rev().reduce(|rest, statement| Expression::Chained(Box::new(statement), Box::new(rest)))
+}
+
+#[test]
+fn test_inverse() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter[3](translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); scale(1, 1, 2, 0.5); reflection(0, 1, 0.3); translation(8, 15)").unwrap().0;
+    let inverse = expr.inverse().unwrap();
+    let p = Point { x: -2.5, y: 7.0 };
+    let there_and_back = Expression::Chained(Box::new(expr.clone()), Box::new(inverse.clone())).evaluate(p);
+    assert!((there_and_back.x - p.x).abs() < 1e-9);
+    assert!((there_and_back.y - p.y).abs() < 1e-9);
+    let back_and_there = Expression::Chained(Box::new(inverse), Box::new(expr)).evaluate(p);
+    assert!((back_and_there.x - p.x).abs() < 1e-9);
+    assert!((back_and_there.y - p.y).abs() < 1e-9);
+
+    assert_eq!(Some(Expression::Translation { u: -1.0, v: 2.0 }), parse_expression("translation(1, -2)").unwrap().0.inverse());
+    assert_eq!(None, parse_expression("{ translation(1, 0) } or { translation(0, 1) }").unwrap().0.inverse());
+    assert_eq!(None, parse_expression("translation(1, 0); scale(0, 0, 0, 1)").unwrap().0.inverse());
+
+    let expr = parse_expression("rev(translation(1, 0); rotation(0, 0, 1); scale(2, 0, 3, 0.5))").unwrap().0;
+    let there_and_back = Expression::Chained(Box::new(expr.clone()), Box::new(expr.inverse().unwrap())).evaluate(p);
+    assert!((there_and_back.x - p.x).abs() < 1e-9);
+    assert!((there_and_back.y - p.y).abs() < 1e-9);
+}
+
+#[test]
+fn test_simplify() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("translation(1, 0); translation(2, 0.5); rotation(0, 0, 1); translation(1, 1); rotation(3, 3, 0); translation(-1, 1)").unwrap().0;
+    let expected_expression = parse_expression("translation(3, 0.5); rotation(0, 0, 1); translation(0, 2)").unwrap().0;
+    let simplified = expr.simplify();
+    assert_eq!(expected_expression, simplified);
+    let p = Point { x: 0.5, y: -2.0 };
+    assert!((expr.evaluate(p).x - simplified.evaluate(p).x).abs() < 1e-12);
+    assert!((expr.evaluate(p).y - simplified.evaluate(p).y).abs() < 1e-12);
+
+    let expr = parse_expression("iter[0](rotation(0, 0, 1)); iter[3](translation(1, 0); translation(0, 1)); { iter(rotation(0, 0, 0)) } or { translation(1, 1); translation(-1, -1) }").unwrap().0;
+    let expected_expression = parse_expression("iter[3](translation(1, 1)); { id } or { id }").unwrap().0;
+    assert_eq!(expected_expression, expr.simplify());
+
+    assert_eq!(Expression::Identity, parse_expression("rotation(1, 1, 0); iter[0](translation(1, 0))").unwrap().0.simplify());
+
+    assert_eq!(Expression::Translation { u: 1.0, v: 0.0 }, parse_expression("id; translation(1,0)").unwrap().0.simplify());
+    let expr = parse_expression("translation(1, 0); identity; translation(2, 0); iter[3](id); { id } or { rotation(0, 0, 1); id }").unwrap().0;
+    let expected_expression = parse_expression("translation(3, 0); { id } or { rotation(0, 0, 1) }").unwrap().0;
+    assert_eq!(expected_expression, expr.simplify());
+}
+
+#[test]
+fn test_normalize_angles() {
+    use super::parser::parse_expression;
+
+    let Expression::Rotation { theta, .. } = parse_expression("rotation(0, 0, 7.0)").unwrap().0.normalize_angles() else { panic!("expected a rotation") };
+    assert!((theta - (7.0 - TAU)).abs() < 1e-12);
+
+    let expr = parse_expression("iter[2](rotation(1, 2, 0.5); { rotation(0, 0, 0) } or { reflection(0, 0, 9) })").unwrap().0;
+    assert_eq!(expr, expr.normalize_angles());
+
+    let expr = parse_expression("rotation(0, 0, -90deg); translation(1, 0); rotation(0, 0, -1e-17)").unwrap().0;
+    let expected_expression = parse_expression("rotation(0, 0, 270deg); translation(1, 0); rotation(0, 0, 0)").unwrap().0;
+    assert!(expected_expression.approx_eq(&expr.normalize_angles(), 1e-12));
+}
+
+#[test]
+fn test_fold_constants() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("translation(1, 2); iter[3](rotation(0.5, -1, 0.3); scale(1, 1, 2, 0.5)); reflection(0, 0, 1)").unwrap().0;
+    let folded = expr.fold_constants();
+    assert!(matches!(folded, Expression::Affine(_)));
+    let mut affines = 0;
+    folded.visit(&mut |node| affines += matches!(node, Expression::Affine(_)) as usize);
+    assert_eq!(1, affines);
+    let p = Point { x: -2.5, y: 7.0 };
+    assert!((expr.evaluate(p).x - folded.evaluate(p).x).abs() < 1e-9);
+    assert!((expr.evaluate(p).y - folded.evaluate(p).y).abs() < 1e-9);
+
+    let expr = parse_expression("translation(1, 0); { translation(0, 1); rotation(0, 0, 1) } or { scale(0, 0, 2, 2) }; translation(1, 0); translation(2, 0)").unwrap().0;
+    let Expression::Chained(first, rest) = expr.fold_constants() else { panic!("expected a chain") };
+    assert_eq!(Expression::Translation { u: 1.0, v: 0.0 }, *first);
+    let Expression::Chained(choice, last) = *rest else { panic!("expected a chain") };
+    assert_eq!(Expression::Affine(Matrix3::translation(3.0, 0.0)), *last);
+    let Expression::EitherOr { left, right, .. } = *choice else { panic!("expected a choice") };
+    assert!(matches!(*left, Expression::Affine(_)));
+    assert_eq!(Expression::Scale { u: 0.0, v: 0.0, sx: 2.0, sy: 2.0 }, *right);
+}
+
+#[test]
+fn test_flatten_chains() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    let expected_expression = Expression::Sequence(vec![
+        Expression::Iterate { count: None, body: Box::new(Expression::Sequence(vec![
+            Expression::Translation { u: 12.0, v: 0.4 },
+            Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 },
+        ])) },
+        Expression::Translation { u: 8.0, v: 15.0 },
+    ]);
+    let flattened = expr.flatten_chains();
+    assert_eq!(expected_expression, flattened);
+    assert_eq!(expr, flattened.nest());
+    assert_eq!(expr.to_string(), flattened.to_string());
+    let p = Point { x: 1.0, y: -1.0 };
+    assert_eq!(expr.evaluate(p), flattened.evaluate(p));
+
+    // left-nested chains flatten in the same order
+    let translation = |u| Box::new(Expression::Translation { u, v: 0.0 });
+    let left_nested = Expression::Chained(Box::new(Expression::Chained(translation(1.0), translation(2.0))), translation(3.0));
+    let expected_expression = Expression::Sequence(vec![*translation(1.0), *translation(2.0), *translation(3.0)]);
+    assert_eq!(expected_expression, left_nested.flatten_chains());
+    assert_eq!(parse_expression("translation(1, 0); translation(2, 0); translation(3, 0)").unwrap().0, left_nested.flatten_chains().nest());
+
+    assert_eq!(Expression::Identity, Expression::Sequence(vec![]).nest());
+    assert_eq!(*translation(1.0), Expression::Sequence(vec![*translation(1.0)]).nest());
+}
+
+#[test]
+fn test_round_coords() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("translation(0.123456, 1.0)").unwrap().0.round_coords(3);
+    assert_eq!(Expression::Translation { u: 0.123, v: 1.0 }, expr);
+
+    let expr = parse_expression("iter[2](rotation(-0.0004, 1.23456, 0.5); { spin(1.9999) } or { affine(1.00001, 0, 2, 0, 1, -0.00001) })").unwrap().0;
+    let expected_expression = parse_expression("iter[2](rotation(0, 1.235, 0.5); { spin(2) } or { affine(1, 0, 2, 0, 1, 0) })").unwrap().0;
+    let rounded = expr.round_coords(3);
+    assert_eq!(expected_expression, rounded);
+    assert_eq!("iter[2](rotation(0, 1.235, 0.5); { spin(2) } or { affine(1, 0, 2, 0, 1, 0) })", rounded.to_string());
+}
+
+#[test]
+fn test_scale_all() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    let expected_expression = parse_expression("iter(translation(24, 0.8); rotation(0.2, 0.3, 0.5)); translation(16, 30)").unwrap().0;
+    assert_eq!(expected_expression, expr.scale_all(2.0));
+
+    let expr = parse_expression("{ scale(1, 1, 0.5, -2) } or { reflection(0, 1, 0.3) }").unwrap().0;
+    let expected_expression = parse_expression("{ scale(1, 1, 1.5, -6) } or { reflection(0, 1, 0.3) }").unwrap().0;
+    assert_eq!(expected_expression, expr.scale_all(3.0));
+}
+
+#[test]
+fn test_lerp() {
+    use super::parser::parse_expression;
+
+    let a = Expression::Translation { u: 0.0, v: 4.0 };
+    let b = Expression::Translation { u: 2.0, v: -4.0 };
+    assert_eq!(Some(Expression::Translation { u: 1.0, v: 0.0 }), Expression::lerp(&a, &b, 0.5));
+    assert_eq!(Some(a.clone()), Expression::lerp(&a, &b, 0.0));
+    assert_eq!(Some(b.clone()), Expression::lerp(&a, &b, 1.0));
+
+    // 350° to 10° passes through 0°, not 180°
+    let a = parse_expression("rotation(0, 0, 350deg)").unwrap().0;
+    let b = parse_expression("rotation(2, 2, 10deg)").unwrap().0;
+    let Some(Expression::Rotation { u, v, theta }) = Expression::lerp(&a, &b, 0.5) else { panic!("expected a rotation") };
+    assert_eq!((1.0, 1.0), (u, v));
+    assert!((theta - TAU).abs() < 1e-12, "{}", theta);
+    let Some(Expression::Rotation { theta, .. }) = Expression::lerp(&b, &a, 0.25) else { panic!("expected a rotation") };
+    assert!((theta - 5f64.to_radians()).abs() < 1e-12, "{}", theta);
+
+    let a = parse_expression("iter[3](translation(1, 0); { spin(0) } or { scale(0, 0, 1, 1) })").unwrap().0;
+    let b = parse_expression("iter[3](translation(3, 0); { spin(1) } or { scale(0, 0, 2, 3) })").unwrap().0;
+    let expected_expression = parse_expression("iter[3](translation(2, 0); { spin(0.5) } or { scale(0, 0, 1.5, 2) })").unwrap().0;
+    assert_eq!(Some(expected_expression), Expression::lerp(&a, &b, 0.5));
+
+    let c = parse_expression("iter[4](translation(3, 0); { spin(1) } or { scale(0, 0, 2, 3) })").unwrap().0;
+    assert_eq!(None, Expression::lerp(&a, &c, 0.5));
+    assert_eq!(None, Expression::lerp(&Expression::Identity, &Expression::Translation { u: 0.0, v: 0.0 }, 0.5));
+}
+
+#[test]
+fn test_quantize_angles() {
+    use super::parser::{parse_expression, program};
+
+    let Expression::Rotation { theta, .. } = parse_expression("rotation(0, 0, 0.4)").unwrap().0.quantize_angles(PI / 3.0) else { panic!("expected a rotation") };
+    assert_eq!(0.0, theta);
+    let Expression::Rotation { theta, .. } = parse_expression("rotation(0, 0, 0.6)").unwrap().0.quantize_angles(PI / 3.0) else { panic!("expected a rotation") };
+    assert!((theta - PI / 3.0).abs() < 1e-12);
+
+    let (_, tiling) = program("init(0, 0); iter[6](translation(1, 0); rotation(1, 0, 1.1)); rotation(0, 0, -0.9); rotation(0, 0, -0.5); spin(0.4)").unwrap();
+    let (_, expected_program) = program("init(0, 0); iter[6](translation(1, 0); rotation(1, 0, 60deg)); rotation(0, 0, 300deg); rotation(0, 0, 0); spin(0.4)").unwrap();
+    let quantized = tiling.quantize_angles(PI / 3.0);
+    assert!(expected_program.body.approx_eq(&quantized.body, 1e-12));
+    assert!(quantized.body.to_string().contains("rotation(0, 0, 0)"));
+}
+
+#[test]
+fn test_flip() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(1, 2); translation(3, 0)").unwrap();
+    let (_, expected_program) = program("init(1, 2); translation(-3, 0)").unwrap();
+    assert_eq!(expected_program, walk.flip_x());
+    assert_eq!(Point::new(-2.0, 2.0), walk.flip_x().evaluate());
+    assert_eq!(walk, walk.flip_y());
+
+    let (_, figure) = program("init(1, 2); iter[3](translation(2, 1); rotation(4, 0, 0.5)); { scale(0, 1, 2, 0.5) } or { reflection(3, 3, 0.25) }; spin(1); affine(1, 2, 3, 0, 1, 5)").unwrap();
+    assert_eq!(figure, figure.flip_x().flip_x());
+    assert_eq!(figure, figure.flip_y().flip_y());
+
+    // every point of the trajectory is mirrored through the init point
+    let points = |program: &Program| program.trajectory(20).unwrap();
+    for (p, q) in points(&figure).iter().zip(points(&figure.flip_x())) {
+        assert!((p.x + q.x - 2.0).abs() < 1e-9 && (p.y - q.y).abs() < 1e-9, "{:?} {:?}", p, q);
+    }
+    for (p, q) in points(&figure).iter().zip(points(&figure.flip_y())) {
+        assert!((p.x - q.x).abs() < 1e-9 && (p.y + q.y - 4.0).abs() < 1e-9, "{:?} {:?}", p, q);
+    }
+}
@@ -0,0 +1,129 @@
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::affine::Matrix3;
+use super::language::*;
+
+/// Serialized form of `Expression`: internally tagged by variant name, with
+/// named fields for the chain so every variant is a JSON object.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum ExpressionRepr {
+    Translation { u: f64, v: f64 },
+    Rotation { u: f64, v: f64, theta: f64 },
+    Scale { u: f64, v: f64, sx: f64, sy: f64 },
+    Reflection { u: f64, v: f64, theta: f64 },
+    Chained { first: Box<Expression>, second: Box<Expression> },
+    Sequence { statements: Vec<Expression> },
+    EitherOr {
+        left: Box<Expression>,
+        right: Box<Expression>,
+        #[serde(default = "even_weights")]
+        weights: (f64, f64),
+    },
+    Iterate { count: Option<usize>, body: Box<Expression> },
+    Reverse { body: Box<Expression> },
+    Identity,
+    Spin { theta: f64 },
+    Affine { matrix: Matrix3 },
+    Reference { name: String },
+}
+
+/// `ExpressionRepr` borrowing from the expression being serialized, so that
+/// serializing never copies the tree.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ExpressionRef<'a> {
+    Translation { u: f64, v: f64 },
+    Rotation { u: f64, v: f64, theta: f64 },
+    Scale { u: f64, v: f64, sx: f64, sy: f64 },
+    Reflection { u: f64, v: f64, theta: f64 },
+    Chained { first: &'a Expression, second: &'a Expression },
+    Sequence { statements: &'a [Expression] },
+    EitherOr { left: &'a Expression, right: &'a Expression, weights: (f64, f64) },
+    Iterate { count: Option<usize>, body: &'a Expression },
+    Reverse { body: &'a Expression },
+    Identity,
+    Spin { theta: f64 },
+    Affine { matrix: &'a Matrix3 },
+    Reference { name: &'a str },
+}
+
+impl<'a> From<&'a Expression> for ExpressionRef<'a> {
+    fn from(expr: &'a Expression) -> ExpressionRef<'a> {
+        match expr {
+            Expression::Translation { u, v } => ExpressionRef::Translation { u: *u, v: *v },
+            Expression::Rotation { u, v, theta } => ExpressionRef::Rotation { u: *u, v: *v, theta: *theta },
+            Expression::Scale { u, v, sx, sy } => ExpressionRef::Scale { u: *u, v: *v, sx: *sx, sy: *sy },
+            Expression::Reflection { u, v, theta } => ExpressionRef::Reflection { u: *u, v: *v, theta: *theta },
+            Expression::Chained(first, second) => ExpressionRef::Chained { first, second },
+            Expression::Sequence(statements) => ExpressionRef::Sequence { statements },
+            Expression::EitherOr { left, right, weights } => ExpressionRef::EitherOr { left, right, weights: *weights },
+            Expression::Iterate { count, body } => ExpressionRef::Iterate { count: *count, body },
+            Expression::Reverse(body) => ExpressionRef::Reverse { body },
+            Expression::Identity => ExpressionRef::Identity,
+            Expression::Spin { theta } => ExpressionRef::Spin { theta: *theta },
+            Expression::Affine(matrix) => ExpressionRef::Affine { matrix },
+            Expression::Reference(name) => ExpressionRef::Reference { name },
+        }
+    }
+}
+
+impl From<ExpressionRepr> for Expression {
+    fn from(repr: ExpressionRepr) -> Expression {
+        match repr {
+            ExpressionRepr::Translation { u, v } => Expression::Translation { u, v },
+            ExpressionRepr::Rotation { u, v, theta } => Expression::Rotation { u, v, theta },
+            ExpressionRepr::Scale { u, v, sx, sy } => Expression::Scale { u, v, sx, sy },
+            ExpressionRepr::Reflection { u, v, theta } => Expression::Reflection { u, v, theta },
+            ExpressionRepr::Chained { first, second } => Expression::Chained(first, second),
+            ExpressionRepr::Sequence { statements } => Expression::Sequence(statements),
+            ExpressionRepr::EitherOr { left, right, weights } => Expression::EitherOr { left, right, weights },
+            ExpressionRepr::Iterate { count, body } => Expression::Iterate { count, body },
+            ExpressionRepr::Reverse { body } => Expression::Reverse(body),
+            ExpressionRepr::Identity => Expression::Identity,
+            ExpressionRepr::Spin { theta } => Expression::Spin { theta },
+            ExpressionRepr::Affine { matrix } => Expression::Affine(matrix),
+            ExpressionRepr::Reference { name } => Expression::Reference(name),
+        }
+    }
+}
+
+/// The weights of a choice serialized before it could be weighted.
+fn even_weights() -> (f64, f64) {
+    (0.5, 0.5)
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ExpressionRef::from(self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expression, D::Error> {
+        ExpressionRepr::deserialize(deserializer).map(Expression::from)
+    }
+}
+
+#[test]
+fn test_json_round_trip() {
+    use super::parser::{parse_expression, program};
+
+    let expr = parse_expression("translation(1, 2)").unwrap().0;
+    assert_eq!(r#"{"type":"Translation","u":1.0,"v":2.0}"#, serde_json::to_string(&expr).unwrap());
+    let expr = parse_expression("rev(id; { spin(1) }:1 or { id }:3)").unwrap().0;
+    let json = r#"{"type":"Reverse","body":{"type":"Chained","first":{"type":"Identity"},"second":{"type":"EitherOr","left":{"type":"Spin","theta":1.0},"right":{"type":"Identity"},"weights":[1.0,3.0]}}}"#;
+    assert_eq!(json, serde_json::to_string(&expr).unwrap());
+    assert_eq!(expr, serde_json::from_str::<Expression>(json).unwrap());
+
+    let expr = parse_expression("iter[3]({ translation(12.0, 0.4) } or { rotation(0.2, 0.3, 0.5) }); iter(scale(0, 0, 2, 2)); reflection(1, 1, 0.5)").unwrap().0;
+    let json = serde_json::to_string(&expr).unwrap();
+    assert_eq!(expr, serde_json::from_str::<Expression>(&json).unwrap());
+
+    let (_, parsed_program) = program("init(-1, 0.5); iter[2](translation(1, 0); rotation(0, 0, 1))").unwrap();
+    let json = serde_json::to_string(&parsed_program).unwrap();
+    assert_eq!(parsed_program, serde_json::from_str::<Program>(&json).unwrap());
+}
@@ -0,0 +1,75 @@
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+use super::chaos::RngKind;
+use super::error::EvalError;
+use super::language::*;
+use super::metrics::bounds;
+
+impl Program {
+    /// Renders the points generated by `steps` runs of the body as an SVG
+    /// document, with the y axis pointing up. Choice-free programs are drawn
+    /// as a polyline along their trajectory; programs with choices are drawn
+    /// as a scatter of the chaos-game points.
+    pub fn to_svg(&self, steps: usize) -> Result<String, EvalError> {
+        let has_choice = self.body.contains_choice();
+        let points = if has_choice { self.chaos_game(steps, 0, RngKind::default()) } else { self.trajectory(steps)? };
+        let (min, max) = bounds(&points).unwrap_or((self.init, self.init));
+        let margin = 0.05 * (max.x - min.x).max(max.y - min.y);
+        let margin = if margin > 0.0 { margin } else { 1.0 };
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">"#,
+            min.x - margin,
+            flip(max.y) - margin,
+            max.x - min.x + 2.0 * margin,
+            max.y - min.y + 2.0 * margin,
+        ).unwrap();
+        if has_choice {
+            let radius = margin / 10.0;
+            for p in &points {
+                writeln!(svg, r#"  <circle cx="{}" cy="{}" r="{}"/>"#, p.x, flip(p.y), radius).unwrap();
+            }
+        } else {
+            let coordinates: Vec<String> = points.iter().map(|p| format!("{},{}", p.x, flip(p.y))).collect();
+            writeln!(svg, r#"  <polyline fill="none" stroke="black" points="{}"/>"#, coordinates.join(" ")).unwrap();
+        }
+        svg.push_str("</svg>\n");
+        Ok(svg)
+    }
+}
+
+/// Maps a y coordinate into SVG's downward-pointing axis, without turning
+/// zero into `-0`.
+fn flip(y: f64) -> f64 {
+    0.0 - y
+}
+
+#[test]
+fn test_svg_polyline() {
+    use super::parser::program;
+
+    let (_, walk) = program("init(0, 0); translation(1, 0); translation(0, 2)").unwrap();
+    let svg = walk.to_svg(4).unwrap();
+    assert!(svg.starts_with("<svg "));
+    assert!(svg.contains(r#"viewBox="-0.4 -8.4 4.8 8.8""#));
+    let polyline = svg.lines().find(|line| line.contains("<polyline")).unwrap();
+    assert_eq!(r#"  <polyline fill="none" stroke="black" points="0,0 1,-2 2,-4 3,-6 4,-8"/>"#, polyline);
+    assert!(svg.trim_end().ends_with("</svg>"));
+}
+
+#[test]
+fn test_svg_scatter() {
+    use super::parser::program;
+
+    let (_, sierpinski) = program(
+        "init(0, 0); { scale(0, 0, 0.5, 0.5) } or { { scale(1, 0, 0.5, 0.5) } or { scale(0.5, 1, 0.5, 0.5) } }",
+    ).unwrap();
+    let svg = sierpinski.to_svg(200).unwrap();
+    assert!(svg.contains("viewBox=\""));
+    assert_eq!(200, svg.matches("<circle").count());
+    assert!(!svg.contains("<polyline"));
+}
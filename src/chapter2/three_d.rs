@@ -0,0 +1,135 @@
+
+use alloc::{boxed::Box, string::ToString};
+
+use nom::{
+    IResult,
+    bytes::complete::tag,
+    branch::alt,
+    combinator::cut,
+    multi::separated_list1,
+    sequence::Tuple,
+};
+
+use super::error::ParseError;
+use super::float;
+use super::parser::{parenthesized_float_n, report, semicolon_separator, ws, ParserConfig, SyntaxError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Point3 {
+    pub(crate) x: f64,
+    pub(crate) y: f64,
+    pub(crate) z: f64,
+}
+
+impl Point3 {
+    pub fn new(x: f64, y: f64, z: f64) -> Point3 {
+        Point3 { x, y, z }
+    }
+
+    pub fn x(&self) -> f64 {
+        self.x
+    }
+
+    pub fn y(&self) -> f64 {
+        self.y
+    }
+
+    pub fn z(&self) -> f64 {
+        self.z
+    }
+}
+
+/// The three-dimensional counterpart of `Expression`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression3 {
+    Translation3{u: f64, v: f64, w: f64},
+    /// Rotation by `theta` radians around the line through `center` along
+    /// `axis`, counterclockwise when looking against the axis. The axis needn't
+    /// be normalized but must not be zero.
+    Rotation3{axis: Point3, theta: f64, center: Point3},
+    Chained3(Box<Expression3>, Box<Expression3>),
+}
+
+impl Expression3 {
+    pub fn evaluate(&self, p: Point3) -> Point3 {
+        match self {
+            Expression3::Translation3 { u, v, w } => Point3 { x: p.x + u, y: p.y + v, z: p.z + w },
+            Expression3::Rotation3 { axis, theta, center } => {
+                // Rodrigues' formula on the offset from the center
+                let length = float::sqrt(axis.x * axis.x + axis.y * axis.y + axis.z * axis.z);
+                let (kx, ky, kz) = (axis.x / length, axis.y / length, axis.z / length);
+                let (dx, dy, dz) = (p.x - center.x, p.y - center.y, p.z - center.z);
+                let (sin, cos) = float::sin_cos(*theta);
+                let dot = kx * dx + ky * dy + kz * dz;
+                let (cx, cy, cz) = (ky * dz - kz * dy, kz * dx - kx * dz, kx * dy - ky * dx);
+                Point3 {
+                    x: center.x + dx * cos + cx * sin + kx * dot * (1.0 - cos),
+                    y: center.y + dy * cos + cy * sin + ky * dot * (1.0 - cos),
+                    z: center.z + dz * cos + cz * sin + kz * dot * (1.0 - cos),
+                }
+            }
+            Expression3::Chained3(first, second) => second.evaluate(first.evaluate(p)),
+        }
+    }
+}
+
+fn translation3_expression(text: &str) -> IResult<&str, Expression3, SyntaxError<'_>> {
+    let (text, (_, _, numbers)) = (tag("translation3"), ws, cut(parenthesized_float_n(3, &ParserConfig::default()))).parse(text)?;
+    Ok((text, Expression3::Translation3 { u: numbers[0], v: numbers[1], w: numbers[2] }))
+}
+
+/// `rotation3(ax, ay, az, theta, cx, cy, cz)`: the axis, the angle and the center.
+fn rotation3_expression(text: &str) -> IResult<&str, Expression3, SyntaxError<'_>> {
+    let (rest, (_, _, n)) = (tag("rotation3"), ws, cut(parenthesized_float_n(7, &ParserConfig::default()))).parse(text)?;
+    if n[0] == 0.0 && n[1] == 0.0 && n[2] == 0.0 {
+        return Err(nom::Err::Failure(SyntaxError::new(text, "rotation axis must not be zero".to_string())));
+    }
+    Ok((rest, Expression3::Rotation3 { axis: Point3::new(n[0], n[1], n[2]), theta: n[3], center: Point3::new(n[4], n[5], n[6]) }))
+}
+
+/// Parses `;`-separated 3D statements into a right-nested chain.
+pub fn expression3(text: &str) -> IResult<&str, Expression3, SyntaxError<'_>> {
+    let (text, mut statements) = separated_list1(semicolon_separator, alt((translation3_expression, rotation3_expression)))(text)?;
+    let mut expr = statements.pop().expect("separated_list1 parses at least one statement");
+    while let Some(previous) = statements.pop() {
+        expr = Expression3::Chained3(Box::new(previous), Box::new(expr));
+    }
+    Ok((text, expr))
+}
+
+/// Parses a complete 3D expression, surrounding whitespace included.
+pub fn parse_expression3(input: &str) -> Result<Expression3, ParseError> {
+    let (text, (_, expr, _)) = (ws, expression3, ws).parse(input).map_err(|error| report(input, error))?;
+    if !text.is_empty() {
+        return Err(ParseError::at(input, text, "expected end of input".to_string()));
+    }
+    Ok(expr)
+}
+
+#[test]
+fn test_translation3() {
+    assert_eq!(Expression3::Translation3 { u: 1.0, v: 2.0, w: 3.0 }, parse_expression3("translation3(1,2,3)").unwrap());
+    let p = parse_expression3(" translation3 ( 1, 2, 3 ); translation3(-1, 0, 0.5) ").unwrap().evaluate(Point3::new(0.0, 0.0, 1.0));
+    assert_eq!(Point3::new(0.0, 2.0, 4.5), p);
+
+    assert!(parse_expression3("translation3(1, 2)").is_err());
+    assert!(parse_expression3("translation(1, 2)").is_err());
+}
+
+#[test]
+fn test_rotation3_about_z() {
+    let raw_expression = "rotation3(0, 0, 1, 1.5707963267948966, 1, 0, 0)";
+    let expected_expression = Expression3::Rotation3 {
+        axis: Point3::new(0.0, 0.0, 1.0),
+        theta: std::f64::consts::FRAC_PI_2,
+        center: Point3::new(1.0, 0.0, 0.0),
+    };
+    let parsed_expression = parse_expression3(raw_expression).unwrap();
+    assert_eq!(expected_expression, parsed_expression);
+
+    let p = parsed_expression.evaluate(Point3::new(2.0, 0.0, 5.0));
+    assert!((p.x() - 1.0).abs() < 1e-12 && (p.y() - 1.0).abs() < 1e-12 && (p.z() - 5.0).abs() < 1e-12);
+
+    let error = parse_expression3("rotation3(0, 0, 0, 1, 0, 0, 0)").unwrap_err();
+    assert_eq!("rotation axis must not be zero", error.message);
+}
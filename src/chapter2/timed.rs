@@ -0,0 +1,226 @@
+
+use alloc::{boxed::Box, string::ToString, vec, vec::Vec};
+
+use nom::{
+    IResult,
+    bytes::complete::tag,
+    branch::alt,
+    character::complete::{char, one_of, satisfy},
+    combinator::{cut, not},
+    multi::separated_list1,
+    number::complete::double,
+    sequence::Tuple,
+};
+
+use super::error::ParseError;
+use super::language::*;
+use super::parser::{commit, report, semicolon_separator, too_deep, ws, ParserConfig, SyntaxError};
+
+/// A coordinate depending on the time parameter `t`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CoordExpr {
+    /// The parameter itself.
+    T,
+    Number(f64),
+    Add(Box<CoordExpr>, Box<CoordExpr>),
+    Sub(Box<CoordExpr>, Box<CoordExpr>),
+    Mul(Box<CoordExpr>, Box<CoordExpr>),
+    Neg(Box<CoordExpr>),
+}
+
+impl CoordExpr {
+    pub fn evaluate(&self, t: f64) -> f64 {
+        match self {
+            CoordExpr::T => t,
+            CoordExpr::Number(value) => *value,
+            CoordExpr::Add(a, b) => a.evaluate(t) + b.evaluate(t),
+            CoordExpr::Sub(a, b) => a.evaluate(t) - b.evaluate(t),
+            CoordExpr::Mul(a, b) => a.evaluate(t) * b.evaluate(t),
+            CoordExpr::Neg(a) => -a.evaluate(t),
+        }
+    }
+}
+
+/// An expression whose coordinates may depend on `t`, as for animating a
+/// figure; `at` gives the `Expression` for one value of `t`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimedExpression {
+    Translation{u: CoordExpr, v: CoordExpr},
+    Rotation{u: CoordExpr, v: CoordExpr, theta: CoordExpr},
+    Scale{u: CoordExpr, v: CoordExpr, sx: CoordExpr, sy: CoordExpr},
+    Reflection{u: CoordExpr, v: CoordExpr, theta: CoordExpr},
+    Chained(Box<TimedExpression>, Box<TimedExpression>),
+}
+
+impl TimedExpression {
+    /// The expression with every coordinate evaluated at `t`.
+    pub fn at(&self, t: f64) -> Expression {
+        match self {
+            TimedExpression::Translation { u, v } => Expression::Translation { u: u.evaluate(t), v: v.evaluate(t) },
+            TimedExpression::Rotation { u, v, theta } => Expression::Rotation { u: u.evaluate(t), v: v.evaluate(t), theta: theta.evaluate(t) },
+            TimedExpression::Scale { u, v, sx, sy } => {
+                Expression::Scale { u: u.evaluate(t), v: v.evaluate(t), sx: sx.evaluate(t), sy: sy.evaluate(t) }
+            }
+            TimedExpression::Reflection { u, v, theta } => Expression::Reflection { u: u.evaluate(t), v: v.evaluate(t), theta: theta.evaluate(t) },
+            TimedExpression::Chained(first, second) => Expression::Chained(Box::new(first.at(t)), Box::new(second.at(t))),
+        }
+    }
+
+    pub fn evaluate(&self, p: Point, t: f64) -> Point {
+        self.at(t).evaluate(p)
+    }
+}
+
+/// `t`, a finite number, a negated factor or a parenthesized sum. Each
+/// `-` and `(` around a factor nests it a level deeper, up to
+/// `ParserConfig::max_depth`.
+fn coord_factor<'a>(text: &'a str, config: &ParserConfig, depth: usize) -> IResult<&'a str, CoordExpr, SyntaxError<'a>> {
+    if depth > config.max_depth {
+        return Err(too_deep(text, config.max_depth));
+    }
+    let (text, _) = ws(text)?;
+    if let Ok((rest, _)) = (char::<_, SyntaxError<'_>>('t'), not(satisfy(|c| c.is_alphanumeric() || c == '_'))).parse(text) {
+        return Ok((rest, CoordExpr::T));
+    }
+    if let Some(rest) = text.strip_prefix('-') {
+        let (rest, factor) = cut(|text| coord_factor(text, config, depth + 1))(rest)?;
+        return Ok((rest, CoordExpr::Neg(Box::new(factor))));
+    }
+    if let Some(rest) = text.strip_prefix('(') {
+        let (rest, (sum, _, _)) = (|text| coord_sum(text, config, depth + 1), ws, char(')')).parse(rest).map_err(commit)?;
+        return Ok((rest, sum));
+    }
+    let (rest, value) = double(text).map_err(|_: nom::Err<SyntaxError<'_>>| nom::Err::Error(SyntaxError::new(text, "expected a number or `t`".to_string())))?;
+    if !value.is_finite() {
+        return Err(nom::Err::Failure(SyntaxError::new(text, "expected a finite number".to_string())));
+    }
+    Ok((rest, CoordExpr::Number(value)))
+}
+
+/// Factors joined by `*`.
+fn coord_product<'a>(text: &'a str, config: &ParserConfig, depth: usize) -> IResult<&'a str, CoordExpr, SyntaxError<'a>> {
+    let (mut text, mut product) = coord_factor(text, config, depth)?;
+    while let Ok((rest, _)) = (ws, char::<_, SyntaxError<'_>>('*')).parse(text) {
+        let (rest, factor) = cut(|text| coord_factor(text, config, depth))(rest)?;
+        (text, product) = (rest, CoordExpr::Mul(Box::new(product), Box::new(factor)));
+    }
+    Ok((text, product))
+}
+
+/// Products joined by `+` and `-`, associating to the left.
+fn coord_sum<'a>(text: &'a str, config: &ParserConfig, depth: usize) -> IResult<&'a str, CoordExpr, SyntaxError<'a>> {
+    let (mut text, mut sum) = coord_product(text, config, depth)?;
+    while let Ok((rest, (_, operator))) = (ws, one_of::<_, _, SyntaxError<'_>>("+-")).parse(text) {
+        let (rest, term) = cut(|text| coord_product(text, config, depth))(rest)?;
+        let (a, b) = (Box::new(sum), Box::new(term));
+        (text, sum) = (rest, if operator == '+' { CoordExpr::Add(a, b) } else { CoordExpr::Sub(a, b) });
+    }
+    Ok((text, sum))
+}
+
+/// `n` coordinates in parentheses, separated by `,`.
+fn parenthesized_coords<'a>(n: usize, config: &ParserConfig) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<CoordExpr>, SyntaxError<'a>> + '_ {
+    let coord = move |text| coord_sum(text, config, 0);
+    move |text| {
+        let (mut text, (_, _, first)) = (char('('), ws, coord).parse(text)?;
+        let mut coords = vec![first];
+        for _ in 1..n {
+            let (rest, (_, _, coord)) = (ws, char(','), coord).parse(text)?;
+            coords.push(coord);
+            text = rest;
+        }
+        let (text, _) = (ws, char(')')).parse(text)?;
+        Ok((text, coords))
+    }
+}
+
+fn timed_statement<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, TimedExpression, SyntaxError<'a>> {
+    let (text, (keyword, _)) = (alt((tag("translation"), tag("rotation"), tag("scale"), tag("reflection"))), ws).parse(text)?;
+    let arity = match keyword {
+        "translation" => 2,
+        "scale" => 4,
+        _ => 3,
+    };
+    let (text, c) = cut(parenthesized_coords(arity, config))(text)?;
+    let mut c = c.into_iter();
+    let mut next = || c.next().expect("parenthesized_coords parses `arity` coordinates");
+    let expr = match keyword {
+        "translation" => TimedExpression::Translation { u: next(), v: next() },
+        "rotation" => TimedExpression::Rotation { u: next(), v: next(), theta: next() },
+        "scale" => TimedExpression::Scale { u: next(), v: next(), sx: next(), sy: next() },
+        _ => TimedExpression::Reflection { u: next(), v: next(), theta: next() },
+    };
+    Ok((text, expr))
+}
+
+/// Parses `;`-separated statements whose coordinates are sums and products
+/// of numbers and `t`, as in `translation(2 * t, 0); rotation(0, 0, t - 1)`,
+/// into a right-nested chain.
+pub fn timed_expression(text: &str) -> IResult<&str, TimedExpression, SyntaxError<'_>> {
+    timed_expression_with(text, &ParserConfig::default())
+}
+
+/// Like `timed_expression`, with `config.max_depth` limiting how deeply
+/// coordinates nest; the other options don't apply.
+fn timed_expression_with<'a>(text: &'a str, config: &ParserConfig) -> IResult<&'a str, TimedExpression, SyntaxError<'a>> {
+    let (text, mut statements) = separated_list1(semicolon_separator, |text| timed_statement(text, config))(text)?;
+    let mut expr = statements.pop().expect("separated_list1 parses at least one statement");
+    while let Some(previous) = statements.pop() {
+        expr = TimedExpression::Chained(Box::new(previous), Box::new(expr));
+    }
+    Ok((text, expr))
+}
+
+/// Parses a complete timed expression, surrounding whitespace included.
+pub fn parse_timed_expression(input: &str) -> Result<TimedExpression, ParseError> {
+    parse_timed_expression_with(input, &ParserConfig::default())
+}
+
+pub fn parse_timed_expression_with(input: &str, config: &ParserConfig) -> Result<TimedExpression, ParseError> {
+    let (text, (_, expr, _)) = (ws, |text| timed_expression_with(text, config), ws).parse(input).map_err(|error| report(input, error))?;
+    if !text.is_empty() {
+        return Err(ParseError::at(input, text, "expected end of input".to_string()));
+    }
+    Ok(expr)
+}
+
+#[test]
+fn test_translation_over_time() {
+    let expr = parse_timed_expression("translation(t, 0)").unwrap();
+    assert_eq!(TimedExpression::Translation { u: CoordExpr::T, v: CoordExpr::Number(0.0) }, expr);
+    assert_eq!(Expression::Translation { u: 0.0, v: 0.0 }, expr.at(0.0));
+    assert_eq!(Point::new(1.0, 1.0), expr.evaluate(Point::new(1.0, 1.0), 0.0));
+    assert_eq!(Point::new(4.0, 1.0), expr.evaluate(Point::new(1.0, 1.0), 3.0));
+}
+
+#[test]
+fn test_coordinate_arithmetic() {
+    let expr = parse_timed_expression("translation(2 * t - 1, -(t + 0.5) * 2); scale(0, 0, 1 - t - t, t*t)").unwrap();
+    let expected_expression = Expression::Chained(
+        Box::new(Expression::Translation { u: 5.0, v: -7.0 }),
+        Box::new(Expression::Scale { u: 0.0, v: 0.0, sx: -5.0, sy: 9.0 }),
+    );
+    assert_eq!(expected_expression, expr.at(3.0));
+
+    assert_eq!("expected a number or `t`", parse_timed_expression("translation(tt, 0)").unwrap_err().message);
+    assert!(parse_timed_expression("rotation(0, 0)").is_err());
+    assert!(parse_timed_expression("translation(t +, 0)").is_err());
+}
+
+#[test]
+fn test_coordinate_depth_limit() {
+    use super::error::ParseErrorKind;
+
+    let depth = ParserConfig::default().max_depth;
+    let parens = |n: usize| format!("translation({}t{}, 0)", "(".repeat(n), ")".repeat(n));
+    let negations = |n: usize| format!("translation(t, {}1)", "-".repeat(n));
+    for input in [parens(200_000), negations(500_000), parens(depth + 1), negations(depth + 1)] {
+        assert_eq!(ParseErrorKind::TooDeep { depth }, parse_timed_expression(&input).unwrap_err().kind);
+    }
+    assert_eq!(Expression::Translation { u: 2.0, v: 0.0 }, parse_timed_expression(&parens(depth)).unwrap().at(2.0));
+    assert_eq!(Expression::Translation { u: 2.0, v: 1.0 }, parse_timed_expression(&negations(depth)).unwrap().at(2.0));
+
+    let config = ParserConfig { max_depth: 2, ..ParserConfig::default() };
+    assert!(parse_timed_expression_with("translation((-t), 0)", &config).is_ok());
+    assert_eq!(ParseErrorKind::TooDeep { depth: 2 }, parse_timed_expression_with("translation((((t))), 0)", &config).unwrap_err().kind);
+}
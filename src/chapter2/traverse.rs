@@ -0,0 +1,179 @@
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use super::language::*;
+
+impl Expression {
+    /// Rebuilds the tree with every leaf (a transformation or a reference)
+    /// replaced by `f` applied to it. Chains, choices and iterations keep
+    /// their shape and counts.
+    pub fn map_leaves<F: Fn(&Expression) -> Expression>(&self, f: &F) -> Expression {
+        match self {
+            Expression::Chained(first, second) => Expression::Chained(Box::new(first.map_leaves(f)), Box::new(second.map_leaves(f))),
+            Expression::Sequence(statements) => Expression::Sequence(statements.iter().map(|statement| statement.map_leaves(f)).collect()),
+            Expression::EitherOr { left, right, weights } => Expression::EitherOr {
+                left: Box::new(left.map_leaves(f)),
+                right: Box::new(right.map_leaves(f)),
+                weights: *weights,
+            },
+            Expression::Iterate { count, body } => Expression::Iterate { count: *count, body: Box::new(body.map_leaves(f)) },
+            Expression::Reverse(body) => Expression::Reverse(Box::new(body.map_leaves(f))),
+            _ => f(self),
+        }
+    }
+
+    /// Rebuilds the tree with every subtree equal to `needle` replaced by
+    /// `replacement`. Subtrees are compared before their children, so a
+    /// match is replaced whole and `replacement` itself is not searched.
+    pub fn replace(&self, needle: &Expression, replacement: &Expression) -> Expression {
+        if self == needle {
+            return replacement.clone();
+        }
+        let replace = |expr: &Expression| Box::new(expr.replace(needle, replacement));
+        match self {
+            Expression::Chained(first, second) => Expression::Chained(replace(first), replace(second)),
+            Expression::Sequence(statements) => Expression::Sequence(statements.iter().map(|statement| statement.replace(needle, replacement)).collect()),
+            Expression::EitherOr { left, right, weights } => Expression::EitherOr { left: replace(left), right: replace(right), weights: *weights },
+            Expression::Iterate { count, body } => Expression::Iterate { count: *count, body: replace(body) },
+            Expression::Reverse(body) => Expression::Reverse(replace(body)),
+            leaf => leaf.clone(),
+        }
+    }
+
+    /// Calls `f` on every node of the tree in preorder: a node before its
+    /// children, first child before second.
+    pub fn visit<F: FnMut(&Expression)>(&self, f: &mut F) {
+        f(self);
+        match self {
+            Expression::Chained(first, second) => {
+                first.visit(f);
+                second.visit(f);
+            }
+            Expression::Sequence(statements) => {
+                for statement in statements {
+                    statement.visit(f);
+                }
+            }
+            Expression::EitherOr { left, right, .. } => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Expression::Iterate { body, .. } | Expression::Reverse(body) => body.visit(f),
+            _ => {}
+        }
+    }
+
+    /// The statements of a chain in order, with nested chains and sequences
+    /// opened up; any other expression is a single statement.
+    pub(crate) fn chain_statements(&self) -> Vec<&Expression> {
+        match self {
+            Expression::Chained(first, second) => {
+                let mut statements = first.chain_statements();
+                statements.extend(second.chain_statements());
+                statements
+            }
+            Expression::Sequence(statements) => statements.iter().flat_map(Expression::chain_statements).collect(),
+            _ => vec![self],
+        }
+    }
+
+    /// The leaves of the tree in evaluation order, each iteration body once
+    /// and both sides of a choice, left first.
+    pub fn leaves(&self) -> impl Iterator<Item = &Expression> {
+        Leaves { stack: vec![self] }
+    }
+}
+
+/// Depth-first walk yielding leaves; the stack holds the subtrees still to
+/// visit, the next one on top.
+struct Leaves<'a> {
+    stack: Vec<&'a Expression>,
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = &'a Expression;
+
+    fn next(&mut self) -> Option<&'a Expression> {
+        while let Some(expr) = self.stack.pop() {
+            match expr {
+                Expression::Chained(first, second) | Expression::EitherOr { left: first, right: second, .. } => {
+                    self.stack.push(second);
+                    self.stack.push(first);
+                }
+                Expression::Sequence(statements) => self.stack.extend(statements.iter().rev()),
+                Expression::Iterate { body, .. } => self.stack.push(body),
+                Expression::Reverse(body) => self.stack.extend(body.chain_statements()),
+                leaf => return Some(leaf),
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_map_leaves() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("translation(1, -2); iter[3]({ translation(0.5, 0) } or { rotation(0, 0, 1); translation(0, 3) })").unwrap().0;
+    let doubled = expr.map_leaves(&|leaf| match leaf {
+        Expression::Translation { u, v } => Expression::Translation { u: 2.0 * u, v: 2.0 * v },
+        leaf => leaf.clone(),
+    });
+    let expected_expression = parse_expression("translation(2, -4); iter[3]({ translation(1, 0) } or { rotation(0, 0, 1); translation(0, 6) })").unwrap().0;
+    assert_eq!(expected_expression, doubled);
+}
+
+#[test]
+fn test_replace() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("rotation(0, 0, 1); iter[3](translation(1, 0); rotation(0, 0, 1)); { rotation(0, 0, 1) } or { rotation(0, 0, 2) }").unwrap().0;
+    let rotation = Expression::Rotation { u: 0.0, v: 0.0, theta: 1.0 };
+    assert_eq!(3, expr.occurrences(&rotation));
+    let replaced = expr.replace(&rotation, &Expression::Identity);
+    assert_eq!(0, replaced.occurrences(&rotation));
+    let expected_expression = parse_expression("id; iter[3](translation(1, 0); id); { id } or { rotation(0, 0, 2) }").unwrap().0;
+    assert_eq!(expected_expression, replaced);
+
+    // a whole subtree is replaced, and the replacement is left as it is
+    let body = parse_expression("translation(1, 0); rotation(0, 0, 1)").unwrap().0;
+    let wrapped = Expression::Iterate { count: Some(2), body: Box::new(body.clone()) };
+    let expected_expression = parse_expression("rotation(0, 0, 1); iter[3](iter[2](translation(1, 0); rotation(0, 0, 1))); { rotation(0, 0, 1) } or { rotation(0, 0, 2) }").unwrap().0;
+    assert_eq!(expected_expression, expr.replace(&body, &wrapped));
+}
+
+#[test]
+fn test_visit() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("translation(1, 0); iter[2]({ rotation(0, 0, 1) } or { scale(0, 0, 2, 2) })").unwrap().0;
+    let mut kinds = Vec::new();
+    expr.visit(&mut |node| kinds.push(match node {
+        Expression::Chained(..) => "chain",
+        Expression::Iterate { .. } => "iter",
+        Expression::EitherOr { .. } => "or",
+        Expression::Translation { .. } => "translation",
+        Expression::Rotation { .. } => "rotation",
+        _ => "other",
+    }));
+    assert_eq!(vec!["chain", "translation", "iter", "or", "rotation", "other"], kinds);
+}
+
+#[test]
+fn test_leaves() {
+    use super::parser::parse_expression;
+
+    let expr = parse_expression("iter(translation(12.0, 0.4); rotation(0.2, 0.3, 0.5)); translation( 8, 15 )").unwrap().0;
+    let expected_leaves = vec![
+        Expression::Translation { u: 12.0, v: 0.4 },
+        Expression::Rotation { u: 0.2, v: 0.3, theta: 0.5 },
+        Expression::Translation { u: 8.0, v: 15.0 },
+    ];
+    assert_eq!(expected_leaves, expr.leaves().cloned().collect::<Vec<_>>());
+
+    let expr = parse_expression("{ translation(1, 0); iter[3](rotation(0, 0, 1)) } or { scale(0, 0, 2, 2) }; translation(0, 1)").unwrap().0;
+    let expected_leaves = parse_expression("translation(1, 0); rotation(0, 0, 1); scale(0, 0, 2, 2); translation(0, 1)").unwrap().0;
+    let leaves: Vec<&Expression> = expr.leaves().collect();
+    assert_eq!(expected_leaves.leaves().collect::<Vec<_>>(), leaves);
+    assert_eq!(4, leaves.len());
+}
@@ -1,2 +1,6 @@
-mod chapter2;
+// tests keep `std` even when the library doesn't use it
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+extern crate alloc;
+
+pub mod chapter2;